@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN display_name TEXT,
+    ADD COLUMN bio TEXT,
+    ADD COLUMN avatar_url TEXT,
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
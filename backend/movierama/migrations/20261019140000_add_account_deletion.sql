@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN deletion_scheduled_at TIMESTAMPTZ,
+    ADD COLUMN deletion_mode TEXT CHECK (deletion_mode IN ('ANONYMISE', 'DELETE')),
+    ADD COLUMN anonymised_at TIMESTAMPTZ;
+
+CREATE INDEX idx_users_deletion_scheduled_at ON users(deletion_scheduled_at)
+    WHERE deletion_scheduled_at IS NOT NULL;
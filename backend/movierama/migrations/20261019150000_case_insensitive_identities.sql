@@ -0,0 +1,46 @@
+-- Logins containing an '@' are looked up by email from now on, so usernames
+-- may no longer contain one. Existing ones get it replaced by '_', and their
+-- id appended when that name is taken. Those users can still log in with
+-- their email.
+UPDATE users u
+SET username = replace(u.username, '@', '_') || CASE
+    WHEN EXISTS (
+        SELECT 1 FROM users o
+        WHERE o.id <> u.id
+        AND LOWER(replace(o.username, '@', '_')) = LOWER(replace(u.username, '@', '_'))
+    ) THEN '_' || u.id
+    ELSE ''
+END
+WHERE u.username LIKE '%@%';
+
+-- Refuse to migrate while accounts differ only in the case of their username
+-- or email; they have to be merged or renamed by hand first.
+DO $$
+DECLARE
+    collisions TEXT;
+BEGIN
+    SELECT string_agg(name, ', ') INTO collisions
+    FROM (
+        SELECT 'username ' || LOWER(username) AS name
+        FROM users
+        GROUP BY LOWER(username)
+        HAVING COUNT(*) > 1
+        UNION ALL
+        SELECT 'email ' || LOWER(email)
+        FROM users
+        GROUP BY LOWER(email)
+        HAVING COUNT(*) > 1
+    ) c;
+
+    IF collisions IS NOT NULL THEN
+        RAISE EXCEPTION 'Case-insensitive identity collisions: %', collisions;
+    END IF;
+END
+$$;
+
+ALTER TABLE users
+    DROP CONSTRAINT users_username_key,
+    DROP CONSTRAINT users_email_key;
+
+CREATE UNIQUE INDEX users_username_lower_key ON users (LOWER(username));
+CREATE UNIQUE INDEX users_email_lower_key ON users (LOWER(email));
@@ -0,0 +1,16 @@
+-- Promote users by hand, e.g. UPDATE users SET role = 'ADMIN' WHERE username = '...'
+ALTER TABLE users
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'USER' CHECK (role IN ('USER', 'MODERATOR', 'ADMIN'));
+
+CREATE TABLE invites (
+    id SERIAL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    created_by INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    max_uses INTEGER NOT NULL CHECK (max_uses > 0),
+    uses INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_invites_created_by ON invites(created_by);
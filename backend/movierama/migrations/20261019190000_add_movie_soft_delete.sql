@@ -0,0 +1,4 @@
+ALTER TABLE movies ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Lets the trash listing and the purge find deleted movies without a full scan
+CREATE INDEX idx_movies_deleted_at ON movies(deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,17 @@
+-- Every version of a movie's title and description, numbered from 1 per movie.
+-- The first revision is the movie as it was created.
+CREATE TABLE movie_revisions (
+    id SERIAL PRIMARY KEY,
+    movie_id INTEGER NOT NULL REFERENCES movies(id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT,
+    edited_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    edited_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (movie_id, revision)
+);
+
+-- Existing movies start their history at their current state
+INSERT INTO movie_revisions (movie_id, revision, title, description, edited_by, edited_at)
+SELECT id, 1, title, description, user_id, date_added
+FROM movies;
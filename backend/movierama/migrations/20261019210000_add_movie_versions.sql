@@ -0,0 +1,2 @@
+-- Incremented on every change, sent to clients as the movie's ETag
+ALTER TABLE movies ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
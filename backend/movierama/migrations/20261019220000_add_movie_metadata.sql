@@ -0,0 +1,15 @@
+ALTER TABLE movies
+    ADD COLUMN release_date DATE,
+    ADD COLUMN runtime_minutes INTEGER CHECK (runtime_minutes > 0),
+    ADD COLUMN directors TEXT[] NOT NULL DEFAULT '{}',
+    -- CAST is a keyword
+    ADD COLUMN cast_members TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN original_language TEXT,
+    ADD COLUMN imdb_id TEXT,
+    ADD COLUMN tmdb_id INTEGER;
+
+-- Trashed movies keep their ids, so restoring one never creates a duplicate
+ALTER TABLE movies ADD CONSTRAINT movies_imdb_id_key UNIQUE (imdb_id);
+ALTER TABLE movies ADD CONSTRAINT movies_tmdb_id_key UNIQUE (tmdb_id);
+
+CREATE INDEX idx_movies_release_date ON movies(release_date);
@@ -0,0 +1,2 @@
+-- Filled in by hand or from the metadata provider
+ALTER TABLE movies ADD COLUMN poster_url TEXT;
@@ -0,0 +1,6 @@
+-- Set when the poster was uploaded rather than linked: the resized copies
+-- as an <img srcset>, and the stored blobs so they can be removed when the
+-- poster is replaced
+ALTER TABLE movies
+    ADD COLUMN poster_srcset TEXT,
+    ADD COLUMN poster_blobs TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,18 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Lowercase, punctuation folded into single spaces and a leading article
+-- dropped, so that "The Matrix" and "matrix" compare equal
+CREATE FUNCTION normalize_title(title TEXT) RETURNS TEXT AS $$
+    SELECT regexp_replace(
+        trim(regexp_replace(lower(title), '[^[:alnum:]]+', ' ', 'g')),
+        '^(the|a|an) ',
+        ''
+    )
+$$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+ALTER TABLE movies
+    ADD COLUMN normalized_title TEXT GENERATED ALWAYS AS (normalize_title(title)) STORED;
+
+CREATE INDEX movies_normalized_title_trgm_idx
+    ON movies USING GIN (normalized_title gin_trgm_ops)
+    WHERE deleted_at IS NULL;
@@ -0,0 +1,16 @@
+CREATE TABLE comments (
+    id SERIAL PRIMARY KEY,
+    movie_id INTEGER NOT NULL REFERENCES movies(id) ON DELETE CASCADE,
+    -- NULL for comments on the movie itself
+    parent_id INTEGER REFERENCES comments(id) ON DELETE CASCADE,
+    -- Kept when the author's account is deleted, so threads stay whole
+    user_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    edited_at TIMESTAMPTZ,
+    deleted_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_comments_movie_id_parent_id ON comments(movie_id, parent_id);
+CREATE INDEX idx_comments_parent_id ON comments(parent_id);
+CREATE INDEX idx_comments_user_id ON comments(user_id);
@@ -0,0 +1,37 @@
+CREATE TABLE reviews (
+    id SERIAL PRIMARY KEY,
+    movie_id INTEGER NOT NULL REFERENCES movies(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Counted in half stars, so 7 is 3.5 stars
+    rating_half_stars SMALLINT NOT NULL CHECK (rating_half_stars BETWEEN 1 AND 10),
+    body TEXT,
+    spoiler BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (movie_id, user_id)
+);
+
+CREATE INDEX idx_reviews_user_id ON reviews(user_id);
+
+-- Bayesian average in stars: the movie's ratings plus `prior_weight`
+-- imaginary ratings of `prior_mean`, so that a single five star review does
+-- not put a movie on top. NULL for movies without reviews.
+CREATE FUNCTION movie_rating(movie INTEGER, prior_weight FLOAT8, prior_mean FLOAT8)
+RETURNS FLOAT8 AS $$
+    SELECT (prior_weight * prior_mean + SUM(rating_half_stars)::FLOAT8 / 2)
+        / (prior_weight + COUNT(*))
+    FROM reviews
+    WHERE movie_id = movie
+    HAVING COUNT(*) > 0
+$$ LANGUAGE SQL STABLE;
+
+-- Number of reviews per rating, from half a star up to five stars
+CREATE FUNCTION movie_rating_histogram(movie INTEGER) RETURNS BIGINT[] AS $$
+    SELECT ARRAY(
+        SELECT COUNT(r.id)
+        FROM generate_series(1, 10) AS s(half_stars)
+        LEFT JOIN reviews r ON r.movie_id = movie AND r.rating_half_stars = s.half_stars
+        GROUP BY s.half_stars
+        ORDER BY s.half_stars
+    )
+$$ LANGUAGE SQL STABLE;
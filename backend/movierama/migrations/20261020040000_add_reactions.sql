@@ -0,0 +1,29 @@
+-- The kinds of vote users can cast on a movie. Retired reactions keep their
+-- votes but can no longer be picked.
+CREATE TABLE reactions (
+    code TEXT PRIMARY KEY CHECK (code ~ '^[A-Z][A-Z0-9_-]{0,31}$'),
+    label TEXT NOT NULL,
+    emoji TEXT,
+    position INTEGER NOT NULL DEFAULT 0,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO reactions (code, label, emoji, position) VALUES
+    ('LIKE', 'Like', '👍', 0),
+    ('HATE', 'Hate', '👎', 1);
+
+ALTER TABLE votes DROP CONSTRAINT votes_type_check;
+ALTER TABLE votes ADD CONSTRAINT votes_type_fkey
+    FOREIGN KEY (type) REFERENCES reactions(code);
+
+-- Number of votes per reaction, leaving out reactions nobody picked
+CREATE FUNCTION movie_reactions(movie INTEGER) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_object_agg(type, count), '{}')
+    FROM (
+        SELECT type, COUNT(*) AS count
+        FROM votes
+        WHERE movie_id = movie
+        GROUP BY type
+    ) t
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,73 @@
+-- Ranking scores, kept up to date by a trigger on votes instead of being
+-- recomputed over all votes on every listing. Only LIKE and HATE count
+-- towards them.
+ALTER TABLE movies
+    ADD COLUMN likes INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN hates INTEGER NOT NULL DEFAULT 0;
+
+-- Reddit's hot ranking. The age is baked into the score, each 12.5 hours
+-- being worth ten times the net votes, so it never needs refreshing: newer
+-- movies simply start higher.
+CREATE FUNCTION hot_rank(likes INTEGER, hates INTEGER, added TIMESTAMPTZ)
+RETURNS FLOAT8 AS $$
+    SELECT sign(likes - hates)::FLOAT8 * log(greatest(abs(likes - hates), 1)::FLOAT8)
+        + (EXTRACT(EPOCH FROM added) - 1134028003) / 45000
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- Highest for movies with many votes split evenly between likes and hates
+CREATE FUNCTION controversy_rank(likes INTEGER, hates INTEGER) RETURNS FLOAT8 AS $$
+    SELECT CASE
+        WHEN likes = 0 OR hates = 0 THEN 0
+        ELSE power((likes + hates)::FLOAT8, least(likes, hates)::FLOAT8 / greatest(likes, hates))
+    END
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- Lower bound of the Wilson score interval at 95% confidence: the share of
+-- likes we can be fairly sure of, given how few votes there may be
+CREATE FUNCTION wilson_lower_bound(likes INTEGER, hates INTEGER) RETURNS FLOAT8 AS $$
+    SELECT CASE
+        WHEN likes + hates = 0 THEN 0
+        ELSE (
+            p + 1.9208 / n - 1.96 * sqrt((p * (1 - p) + 0.9604 / n) / n)
+        ) / (1 + 3.8416 / n)
+    END
+    FROM (SELECT likes::FLOAT8 / nullif(likes + hates, 0) AS p, (likes + hates)::FLOAT8 AS n) t
+$$ LANGUAGE SQL IMMUTABLE;
+
+ALTER TABLE movies
+    ADD COLUMN hot_score FLOAT8 GENERATED ALWAYS AS (hot_rank(likes, hates, date_added)) STORED,
+    ADD COLUMN controversy_score FLOAT8 GENERATED ALWAYS AS (controversy_rank(likes, hates)) STORED,
+    ADD COLUMN best_score FLOAT8 GENERATED ALWAYS AS (wilson_lower_bound(likes, hates)) STORED;
+
+CREATE INDEX idx_movies_hot_score ON movies(hot_score DESC NULLS LAST) WHERE deleted_at IS NULL;
+CREATE INDEX idx_movies_controversy_score ON movies(controversy_score DESC NULLS LAST) WHERE deleted_at IS NULL;
+CREATE INDEX idx_movies_best_score ON movies(best_score DESC NULLS LAST) WHERE deleted_at IS NULL;
+
+CREATE FUNCTION refresh_movie_votes(movie INTEGER) RETURNS VOID AS $$
+    UPDATE movies
+    SET
+        likes = (SELECT COUNT(*) FROM votes WHERE movie_id = movie AND type = 'LIKE'),
+        hates = (SELECT COUNT(*) FROM votes WHERE movie_id = movie AND type = 'HATE')
+    WHERE id = movie
+$$ LANGUAGE SQL;
+
+CREATE FUNCTION refresh_movie_votes_on_vote() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        PERFORM refresh_movie_votes(OLD.movie_id);
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        PERFORM refresh_movie_votes(NEW.movie_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER votes_refresh_movie_scores
+    AFTER INSERT OR UPDATE OR DELETE ON votes
+    FOR EACH ROW EXECUTE FUNCTION refresh_movie_votes_on_vote();
+
+UPDATE movies m
+SET
+    likes = (SELECT COUNT(*) FROM votes v WHERE v.movie_id = m.id AND v.type = 'LIKE'),
+    hates = (SELECT COUNT(*) FROM votes v WHERE v.movie_id = m.id AND v.type = 'HATE');
@@ -0,0 +1,8 @@
+-- Votes cast before this migration get its time as their own
+ALTER TABLE votes
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- Lets trending listings read the votes of a time window straight from the
+-- index
+CREATE INDEX idx_votes_updated_at ON votes(updated_at) INCLUDE (movie_id, type);
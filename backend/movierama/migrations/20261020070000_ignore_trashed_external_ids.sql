@@ -0,0 +1,8 @@
+-- Trashed movies no longer block adding the same movie again. Restoring one
+-- fails instead if its ids have been taken in the meantime.
+ALTER TABLE movies
+    DROP CONSTRAINT movies_imdb_id_key,
+    DROP CONSTRAINT movies_tmdb_id_key;
+
+CREATE UNIQUE INDEX movies_imdb_id_key ON movies(imdb_id) WHERE deleted_at IS NULL;
+CREATE UNIQUE INDEX movies_tmdb_id_key ON movies(tmdb_id) WHERE deleted_at IS NULL;
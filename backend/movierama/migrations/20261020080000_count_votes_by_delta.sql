@@ -0,0 +1,35 @@
+-- Recounting all votes of a movie on every change loses votes cast at the
+-- same time: each transaction counts without seeing the others' votes. Each
+-- change now adds to or takes from the counts instead, and the row lock on
+-- the movie lines concurrent changes up.
+CREATE OR REPLACE FUNCTION refresh_movie_votes_on_vote() RETURNS TRIGGER AS $$
+BEGIN
+    -- Touching a vote without changing it leaves the counts alone
+    IF TG_OP = 'UPDATE' AND OLD.movie_id = NEW.movie_id AND OLD.type = NEW.type THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP <> 'INSERT' AND OLD.type IN ('LIKE', 'HATE') THEN
+        UPDATE movies
+        SET
+            likes = likes - (OLD.type = 'LIKE')::INTEGER,
+            hates = hates - (OLD.type = 'HATE')::INTEGER
+        WHERE id = OLD.movie_id;
+    END IF;
+    IF TG_OP <> 'DELETE' AND NEW.type IN ('LIKE', 'HATE') THEN
+        UPDATE movies
+        SET
+            likes = likes + (NEW.type = 'LIKE')::INTEGER,
+            hates = hates + (NEW.type = 'HATE')::INTEGER
+        WHERE id = NEW.movie_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP FUNCTION refresh_movie_votes(INTEGER);
+
+-- Puts right the counts that went wrong before
+UPDATE movies m
+SET
+    likes = (SELECT COUNT(*) FROM votes v WHERE v.movie_id = m.id AND v.type = 'LIKE'),
+    hates = (SELECT COUNT(*) FROM votes v WHERE v.movie_id = m.id AND v.type = 'HATE');
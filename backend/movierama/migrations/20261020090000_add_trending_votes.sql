@@ -0,0 +1,16 @@
+-- What a vote adds to the net votes of a movie. Only likes and hates count,
+-- other reactions are neither for nor against it.
+CREATE FUNCTION vote_value(type TEXT) RETURNS INTEGER AS $$
+    SELECT CASE type WHEN 'LIKE' THEN 1 WHEN 'HATE' THEN -1 ELSE 0 END
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- The movies with more likes than hates among the votes cast or changed
+-- since the given time, trashed movies included
+CREATE FUNCTION trending_votes(since TIMESTAMPTZ)
+RETURNS TABLE (movie_id INTEGER, net_votes BIGINT) AS $$
+    SELECT v.movie_id, SUM(vote_value(v.type))
+    FROM votes v
+    WHERE v.updated_at >= since
+    GROUP BY v.movie_id
+    HAVING SUM(vote_value(v.type)) > 0
+$$ LANGUAGE SQL STABLE;
@@ -34,7 +34,7 @@ echo "👤 Creating users..."
 for i in $(seq 1 5); do
   USERNAME="user$i"
   EMAIL="user$i@mail.com"
-  PASSWORD='$argon2id$v=19$m=19456,t=2,p=1$lv/Keg0ipUKFfrBFbQXn+w$QGaGR+AdQ9e8jF9V9n3+9FOIf2xvmNc0Kr8sIXMpZf0'  # dummy bcrypt hash
+  PASSWORD='$argon2id$v=19$m=19456,t=2,p=1$lv/Keg0ipUKFfrBFbQXn+w$QGaGR+AdQ9e8jF9V9n3+9FOIf2xvmNc0Kr8sIXMpZf0'  # argon2id with the default parameters, rehashed on first login
 
   psql -h "$DB_HOST" -p "$DB_PORT" -U "$DB_USER" -d "$DB_NAME" -c "
     INSERT INTO users (username, email, password)
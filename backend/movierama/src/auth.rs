@@ -1,38 +1,108 @@
-use crate::exceptions::MovieramaError;
-use axum::{extract::FromRequestParts, http::request::Parts};
+use crate::{
+    exceptions::MovieramaError,
+    models::Scope,
+    services::{access_token_service, session_service},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{header, request::Parts},
+};
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
 use chrono::Utc;
+use ipnet::IpNet;
 use jsonwebtoken::{DecodingKey, Validation, decode};
 use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
     pub user_id: i32,
     pub exp: usize,
+    /// Session the JWT belongs to, absent for personal access tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i32>,
+    /// Set when authenticated with a personal access token, JWTs are unrestricted
+    #[serde(skip)]
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl Claims {
+    pub fn require_scope(&self, scope: Scope) -> Result<(), MovieramaError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(MovieramaError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects personal access tokens, for account management routes
+    pub fn require_session(&self) -> Result<(), MovieramaError> {
+        match self.scopes {
+            Some(_) => Err(MovieramaError::Forbidden),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Claims of a short-lived token issued after a correct password when the
+/// account still has to present a second factor.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MfaClaims {
+    pub sub: String,
+    pub user_id: i32,
+    pub aud: String,
+    pub exp: usize,
 }
 
+/// Audience of MFA pending tokens. Tokens carrying an audience are rejected
+/// by the `Claims` extractor, so they cannot be used as access tokens.
+pub const MFA_AUDIENCE: &str = "mfa";
+
 const JWT_SECRET: &str = "JWT_SECRET";
 
+/// Comma separated addresses or CIDR ranges of the reverse proxies in front
+/// of the API. Only their X-Forwarded-For entries are believed.
+const TRUSTED_PROXIES: &str = "TRUSTED_PROXIES";
+
+pub fn jwt_secret() -> Result<String, MovieramaError> {
+    std::env::var(JWT_SECRET).map_err(|e| MovieramaError::UnexpectedError(e.to_string()))
+}
+
 /// Axum extractor for protected routes
 impl<S> FromRequestParts<S> for Claims
 where
+    PgPool: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = MovieramaError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Already authenticated by the rate limiter
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(claims.clone());
+        }
+
         // Get the bearer token from the Authorization header
         let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, _state)
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
                 .await
                 .map_err(|_| MovieramaError::Unauthorized)?;
 
-        let jwt_secret = std::env::var(JWT_SECRET)
-            .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+        if access_token_service::is_access_token(bearer.token()) {
+            let pool = PgPool::from_ref(state);
+            return access_token_service::authenticate(&pool, bearer.token())
+                .await?
+                .ok_or(MovieramaError::Unauthorized);
+        }
+
+        let jwt_secret = jwt_secret()?;
 
         // Decode the JWT token
         let token_data = decode::<Claims>(
@@ -48,6 +118,132 @@ where
             return Err(MovieramaError::Unauthorized);
         }
 
+        // Check the session has not been signed out
+        let sid = token_data.claims.sid.ok_or(MovieramaError::Unauthorized)?;
+        let pool = PgPool::from_ref(state);
+        if !session_service::is_active(&pool, sid, token_data.claims.user_id).await? {
+            return Err(MovieramaError::Unauthorized);
+        }
+
         Ok(token_data.claims)
     }
 }
+
+/// Where a request comes from, recorded on the sessions it starts and in
+/// the audit log
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Set by the request id layer for every request
+    pub request_id: Option<String>,
+}
+
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header_value = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        Ok(ClientInfo {
+            user_agent: header_value(header::USER_AGENT),
+            ip_address: client_ip(parts),
+            request_id: header_value(header::HeaderName::from_static("x-request-id")),
+        })
+    }
+}
+
+/// Entries that are neither an address nor a range are left out, which only
+/// makes the check stricter
+fn trusted_proxies() -> Vec<IpNet> {
+    std::env::var(TRUSTED_PROXIES)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter_map(|p| {
+            p.parse::<IpNet>()
+                .ok()
+                .or_else(|| p.parse::<IpAddr>().ok().map(IpNet::from))
+        })
+        .collect()
+}
+
+/// The peer address of the request. Behind a trusted proxy it is the
+/// proxy's, so the right-most X-Forwarded-For address that is not another
+/// trusted proxy is taken instead; clients can put anything to the left.
+pub fn client_ip(parts: &Parts) -> Option<String> {
+    let ConnectInfo(peer) = parts.extensions.get::<ConnectInfo<SocketAddr>>()?;
+
+    let forwarded_for: Vec<&str> = parts
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+
+    let ip = resolve_client_ip(peer.ip(), &forwarded_for.join(","), &trusted_proxies());
+    Some(ip.to_string())
+}
+
+fn resolve_client_ip(peer: IpAddr, forwarded_for: &str, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    let mut client = peer;
+    if !is_trusted(&client) {
+        return client;
+    }
+
+    for hop in forwarded_for.rsplit(',') {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Without a trusted proxy in between the header is ignored
+        assert_eq!(
+            resolve_client_ip(ip("203.0.113.7"), "198.51.100.1", &trusted),
+            ip("203.0.113.7")
+        );
+
+        // Spoofed entries to the left of the proxies' own are skipped
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.2"), "1.2.3.4, 203.0.113.7, 10.0.0.1", &trusted),
+            ip("203.0.113.7")
+        );
+
+        // Nothing usable beyond the proxies leaves the last one seen
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.2"), "", &trusted),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.2"), "garbage, 10.0.0.1", &trusted),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::models::DuplicateCandidate;
 use axum::{
     Json,
     http::StatusCode,
@@ -16,12 +17,34 @@ pub enum MovieramaError {
     UnexpectedError(String),
     #[error("User not authorized")]
     Unauthorized,
+    #[error("Access denied")]
+    Forbidden,
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("A similar movie already exists")]
+    DuplicateMovie(Vec<DuplicateCandidate>),
+    #[error("Too many requests")]
+    TooManyRequests,
+    #[error("The request body is too large")]
+    PayloadTooLarge,
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("The resource has been changed since it was read")]
+    PreconditionFailed,
+    #[error("This request requires an If-Match header")]
+    PreconditionRequired,
 }
 
 impl IntoResponse for MovieramaError {
     fn into_response(self) -> Response {
+        // Lets clients point the user to the existing movies
+        let candidates = match &self {
+            MovieramaError::DuplicateMovie(candidates) => Some(candidates.clone()),
+            _ => None,
+        };
+
         let (status, message) = match self {
             MovieramaError::DatabaseError(_) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
@@ -29,13 +52,28 @@ impl IntoResponse for MovieramaError {
             MovieramaError::NotFound => (StatusCode::NOT_FOUND, self.to_string()),
             MovieramaError::UnexpectedError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
             MovieramaError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
+            MovieramaError::Forbidden => (StatusCode::FORBIDDEN, self.to_string()),
             MovieramaError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
+            MovieramaError::Conflict(e) => (StatusCode::CONFLICT, e),
+            MovieramaError::DuplicateMovie(_) => (StatusCode::CONFLICT, self.to_string()),
+            MovieramaError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            MovieramaError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()),
+            MovieramaError::UnsupportedMediaType(e) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, e),
+            MovieramaError::PreconditionFailed => {
+                (StatusCode::PRECONDITION_FAILED, self.to_string())
+            }
+            MovieramaError::PreconditionRequired => {
+                (StatusCode::PRECONDITION_REQUIRED, self.to_string())
+            }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": message,
-        }));
+        });
+        if let Some(candidates) = candidates {
+            body["candidates"] = json!(candidates);
+        }
 
-        (status, body).into_response()
+        (status, Json(body)).into_response()
     }
 }
@@ -0,0 +1,102 @@
+use crate::{
+    auth::{Claims, ClientInfo},
+    exceptions::MovieramaError,
+    models::{AuditEvent, NewReaction, Reaction, Role, UpdateReaction},
+    pagination::{Page, Pageable, Sort},
+    services::{
+        audit_service::{self, AuditContext, AuditFilter},
+        reaction_service, user_service,
+    },
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+const DEFAULT_PAGE: u32 = 0;
+const DEFAULT_SIZE: u32 = 20;
+const MAX_SIZE: u32 = 100;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(rename = "actorId")]
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    #[serde(rename = "targetType")]
+    pub target_type: Option<String>,
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<u32>,
+    pub size: Option<u32>,
+}
+
+/// GET /admin/audit
+pub async fn list_audit_events(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Query(params): Query<AuditQuery>,
+) -> Result<Json<Page<AuditEvent>>, MovieramaError> {
+    claims.require_session()?;
+    user_service::require_role(&pool, claims.user_id, Role::Admin).await?;
+
+    let page = params.page.unwrap_or(DEFAULT_PAGE);
+    let size = params.size.unwrap_or(DEFAULT_SIZE).min(MAX_SIZE);
+    // Events are always returned newest first
+    let pageable = Pageable::new(page, size, Sort::from_query("occurredAt,desc"));
+
+    let filter = AuditFilter {
+        actor_id: params.actor_id,
+        action: params.action,
+        target_type: params.target_type,
+        target_id: params.target_id,
+        from: params.from,
+        to: params.to,
+    };
+
+    let (events, total_elements) = audit_service::list_events(&pool, &filter, &pageable).await?;
+    Ok(Json(Page::new(events, pageable, total_elements)))
+}
+
+/// GET /admin/reactions, retired reactions included
+pub async fn list_reactions(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<Reaction>>, MovieramaError> {
+    claims.require_session()?;
+    user_service::require_role(&pool, claims.user_id, Role::Admin).await?;
+    let reactions = reaction_service::list_reactions(&pool, true).await?;
+    Ok(Json(reactions))
+}
+
+/// POST /admin/reactions
+pub async fn create_reaction(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<NewReaction>,
+) -> Result<Json<Reaction>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let reaction = reaction_service::create_reaction(&pool, claims.user_id, &payload, &ctx).await?;
+    Ok(Json(reaction))
+}
+
+/// PUT /admin/reactions/{code}
+pub async fn update_reaction(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path(code): Path<String>,
+    Json(payload): Json<UpdateReaction>,
+) -> Result<Json<Reaction>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let reaction =
+        reaction_service::update_reaction(&pool, claims.user_id, &code, &payload, &ctx).await?;
+    Ok(Json(reaction))
+}
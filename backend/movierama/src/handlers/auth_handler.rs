@@ -1,25 +1,257 @@
 use crate::{
+    auth::{Claims, ClientInfo},
     exceptions::MovieramaError,
-    models::{AuthResponse, LoginUser, RegisterUser},
-    services::auth_service,
+    models::{
+        AccessToken, AuthResponse, CreatedAccessToken, Invite, LoginResponse, LoginUser,
+        NewAccessToken, NewInvite, RecoveryCodes, RegisterUser, RegistrationInfo, Session,
+        TotpCode, TotpEnrollment, VerifyMfa,
+    },
+    services::{
+        access_token_service,
+        audit_service::AuditContext,
+        auth_service, invite_service, mfa_service,
+        oidc_service::{self, OidcProvider},
+        session_service,
+    },
 };
-use axum::{Json, extract::State};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    response::Redirect,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
 use sqlx::PgPool;
 
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: String,
+    pub state: String,
+}
+
 /// POST /register
 pub async fn register(
     State(pool): State<PgPool>,
+    client: ClientInfo,
     Json(payload): Json<RegisterUser>,
 ) -> Result<Json<AuthResponse>, MovieramaError> {
-    let user = auth_service::register_user(&pool, &payload).await?;
+    let user = auth_service::register_user(&pool, &payload, &client).await?;
     Ok(Json(user))
 }
 
+/// GET /registration
+pub async fn registration_info() -> Result<Json<RegistrationInfo>, MovieramaError> {
+    let policy = invite_service::registration_policy()?;
+    Ok(Json(RegistrationInfo { policy }))
+}
+
 /// POST /login
 pub async fn login(
     State(pool): State<PgPool>,
+    client: ClientInfo,
     Json(payload): Json<LoginUser>,
+) -> Result<Json<LoginResponse>, MovieramaError> {
+    let token = auth_service::login_user(&pool, &payload, &client).await?;
+    Ok(Json(token))
+}
+
+/// POST /2fa/enroll
+pub async fn enroll_totp(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<TotpEnrollment>, MovieramaError> {
+    claims.require_session()?;
+    let enrollment = mfa_service::enroll(&pool, claims.user_id).await?;
+    Ok(Json(enrollment))
+}
+
+/// POST /2fa/confirm
+pub async fn confirm_totp(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let codes = mfa_service::confirm(&pool, claims.user_id, &payload.code, &ctx).await?;
+    Ok(Json(codes))
+}
+
+/// POST /2fa/disable
+pub async fn disable_totp(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<TotpCode>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    mfa_service::disable(&pool, claims.user_id, &payload.code, &ctx).await?;
+    Ok(Json(json!("Two-factor authentication disabled")))
+}
+
+/// POST /2fa/verify
+pub async fn verify_totp(
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Json(payload): Json<VerifyMfa>,
 ) -> Result<Json<AuthResponse>, MovieramaError> {
-    let token = auth_service::login_user(&pool, &payload).await?;
+    let token = mfa_service::verify_login(&pool, &payload, &client).await?;
     Ok(Json(token))
 }
+
+/// GET /oidc/{provider}/authorize
+pub async fn oidc_authorize(
+    State(pool): State<PgPool>,
+    Path(provider): Path<String>,
+) -> Result<Redirect, MovieramaError> {
+    let provider = OidcProvider::from_env(&provider)?;
+    let url = oidc_service::authorization_url(&pool, &provider).await?;
+    Ok(Redirect::to(&url))
+}
+
+/// GET /oidc/{provider}/callback
+pub async fn oidc_callback(
+    State(pool): State<PgPool>,
+    Path(provider): Path<String>,
+    client: ClientInfo,
+    Query(params): Query<OidcCallbackQuery>,
+) -> Result<Json<LoginResponse>, MovieramaError> {
+    let provider = OidcProvider::from_env(&provider)?;
+    let token =
+        oidc_service::complete_login(&pool, &provider, &params.code, &params.state, &client)
+            .await?;
+    Ok(Json(token))
+}
+
+/// GET /tokens
+pub async fn list_access_tokens(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<AccessToken>>, MovieramaError> {
+    claims.require_session()?;
+    let tokens = access_token_service::list_access_tokens(&pool, claims.user_id).await?;
+    Ok(Json(tokens))
+}
+
+/// POST /tokens
+pub async fn create_access_token(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<NewAccessToken>,
+) -> Result<Json<CreatedAccessToken>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let token =
+        access_token_service::create_access_token(&pool, claims.user_id, payload, &ctx).await?;
+    Ok(Json(token))
+}
+
+/// DELETE /tokens/{token_id}
+pub async fn revoke_access_token(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path(token_id): Path<i32>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let success =
+        access_token_service::revoke_access_token(&pool, claims.user_id, token_id, &ctx).await?;
+    if success {
+        Ok(Json(json!(format!(
+            "Token with id {} revoked successfully",
+            token_id
+        ))))
+    } else {
+        Err(MovieramaError::NotFound)
+    }
+}
+
+/// GET /sessions
+pub async fn list_sessions(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<Session>>, MovieramaError> {
+    claims.require_session()?;
+    let sessions = session_service::list_sessions(&pool, claims.user_id, claims.sid).await?;
+    Ok(Json(sessions))
+}
+
+/// DELETE /sessions/{session_id}
+pub async fn revoke_session(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path(session_id): Path<i32>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let success = session_service::revoke_session(&pool, claims.user_id, session_id, &ctx).await?;
+    if success {
+        Ok(Json(json!(format!(
+            "Session with id {} revoked successfully",
+            session_id
+        ))))
+    } else {
+        Err(MovieramaError::NotFound)
+    }
+}
+
+/// DELETE /sessions
+pub async fn revoke_other_sessions(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let sid = claims.sid.ok_or(MovieramaError::Forbidden)?;
+    let revoked = session_service::revoke_other_sessions(&pool, claims.user_id, sid, &ctx).await?;
+    Ok(Json(json!(format!("{} other sessions revoked", revoked))))
+}
+
+/// GET /invites
+pub async fn list_invites(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<Invite>>, MovieramaError> {
+    claims.require_session()?;
+    let invites = invite_service::list_invites(&pool, claims.user_id).await?;
+    Ok(Json(invites))
+}
+
+/// POST /invites
+pub async fn create_invite(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<NewInvite>,
+) -> Result<Json<Invite>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let invite = invite_service::create_invite(&pool, claims.user_id, payload, &ctx).await?;
+    Ok(Json(invite))
+}
+
+/// DELETE /invites/{invite_id}
+pub async fn revoke_invite(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path(invite_id): Path<i32>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let success = invite_service::revoke_invite(&pool, claims.user_id, invite_id, &ctx).await?;
+    if success {
+        Ok(Json(json!(format!(
+            "Invite with id {} revoked successfully",
+            invite_id
+        ))))
+    } else {
+        Err(MovieramaError::NotFound)
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{
+    auth::{Claims, ClientInfo},
+    exceptions::MovieramaError,
+    models::{Comment, EditComment, NewComment, Scope},
+    pagination::{Page, Pageable},
+    services::{
+        audit_service::AuditContext,
+        comment_service::{self, CommentSort},
+    },
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+const DEFAULT_PAGE: u32 = 0;
+const DEFAULT_SIZE: u32 = 20;
+const MAX_SIZE: u32 = 100;
+
+#[derive(Deserialize)]
+pub struct CommentsQuery {
+    pub page: Option<u32>,
+    pub size: Option<u32>,
+    /// `newest` (default), `oldest` or `top`
+    #[serde(default)]
+    pub sort: CommentSort,
+    /// Lists the replies to this comment instead of the top level
+    #[serde(rename = "parentId")]
+    pub parent_id: Option<i32>,
+}
+
+/// GET /movies/{movie_id}/comments
+pub async fn list_comments(
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+    Query(params): Query<CommentsQuery>,
+) -> Result<Json<Page<Comment>>, MovieramaError> {
+    let page = params.page.unwrap_or(DEFAULT_PAGE);
+    let size = params.size.unwrap_or(DEFAULT_SIZE).min(MAX_SIZE);
+    let pageable = Pageable::new(page, size, params.sort.to_sort());
+
+    let (comments, total_elements) =
+        comment_service::list_comments(&pool, movie_id, params.parent_id, params.sort, &pageable)
+            .await?;
+    Ok(Json(Page::new(comments, pageable, total_elements)))
+}
+
+/// POST /movies/{movie_id}/comments
+pub async fn create_comment(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+    Json(payload): Json<NewComment>,
+) -> Result<Json<Comment>, MovieramaError> {
+    claims.require_scope(Scope::CommentsWrite)?;
+    let comment =
+        comment_service::create_comment(&pool, claims.user_id, movie_id, &payload).await?;
+    Ok(Json(comment))
+}
+
+/// PUT /movies/{movie_id}/comments/{comment_id}
+pub async fn edit_comment(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path((movie_id, comment_id)): Path<(i32, i32)>,
+    Json(payload): Json<EditComment>,
+) -> Result<Json<Comment>, MovieramaError> {
+    claims.require_scope(Scope::CommentsWrite)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let comment =
+        comment_service::edit_comment(&pool, claims.user_id, movie_id, comment_id, &payload, &ctx)
+            .await?;
+    Ok(Json(comment))
+}
+
+/// DELETE /movies/{movie_id}/comments/{comment_id}
+pub async fn delete_comment(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path((movie_id, comment_id)): Path<(i32, i32)>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_scope(Scope::CommentsWrite)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    comment_service::delete_comment(&pool, claims.user_id, movie_id, comment_id, &ctx).await?;
+    Ok(Json(json!(format!(
+        "Comment with id {} deleted successfully",
+        comment_id
+    ))))
+}
@@ -1,3 +1,7 @@
+pub mod admin_handler;
 pub mod auth_handler;
+pub mod comments_handler;
 pub mod movies_handler;
+pub mod reviews_handler;
+pub mod users_handler;
 pub mod votes_handler;
@@ -1,19 +1,31 @@
 use std::collections::HashMap;
 
 use crate::{
-    auth::Claims,
+    auth::{Claims, ClientInfo},
     exceptions::MovieramaError,
-    models::{Movie, NewMovie, VoteType},
+    metadata::MetadataProvider,
+    models::{
+        MergeMovie, Movie, MovieMetadata, MovieRevision, NewMovie, RevisionDiff, Scope,
+        TrashedMovie, VoteType,
+    },
     pagination::{Page, Pageable, Sort},
-    services::{movie_service, vote_service},
+    services::{
+        audit_service::AuditContext,
+        movie_service::{self, MovieFilter, TrendingWindow},
+        poster_service, revision_service, vote_service,
+    },
+    storage::BlobStore,
 };
 use axum::{
     Json,
-    extract::{Path, Query, State},
+    extract::{Multipart, Path, Query, State, multipart::MultipartError},
+    http::{HeaderMap, header},
+    response::IntoResponse,
 };
 use serde::Deserialize;
 use serde_json::{Value, json};
 use sqlx::PgPool;
+use std::sync::Arc;
 
 #[derive(Deserialize)]
 pub struct PageableQuery {
@@ -22,6 +34,35 @@ pub struct PageableQuery {
     pub sort: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    pub title: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateMovieQuery {
+    /// Fill in missing details from the metadata provider
+    #[serde(default)]
+    pub enrich: bool,
+    /// Create the movie even if it looks like a duplicate
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TrendingQuery {
+    #[serde(default)]
+    pub window: TrendingWindow,
+    pub page: Option<u32>,
+    pub size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    /// Revision to compare against, the previous one by default
+    pub from: Option<i32>,
+}
+
 const DEFAULT_PAGE: u32 = 0;
 const DEFAULT_SIZE: u32 = 10;
 const DEFAULT_SORT: &str = "dateAdded,desc";
@@ -30,6 +71,7 @@ const DEFAULT_SORT: &str = "dateAdded,desc";
 pub async fn list_movies(
     State(pool): State<PgPool>,
     Query(params): Query<PageableQuery>,
+    Query(filter): Query<MovieFilter>,
 ) -> Result<Json<Page<Movie>>, MovieramaError> {
     let page = params.page.unwrap_or(DEFAULT_PAGE);
     let size = params.size.unwrap_or(DEFAULT_SIZE);
@@ -37,7 +79,8 @@ pub async fn list_movies(
 
     let pageable = Pageable::new(page, size, sort.clone());
 
-    let (movies, total_elements) = movie_service::list_all_movies(&pool, &pageable).await?;
+    let (movies, total_elements) =
+        movie_service::list_all_movies(&pool, &pageable, &filter).await?;
     Ok(Json(Page::new(movies, pageable, total_elements)))
 }
 
@@ -45,6 +88,7 @@ pub async fn list_movies(
 pub async fn list_movies_by_username(
     State(pool): State<PgPool>,
     Query(params): Query<PageableQuery>,
+    Query(filter): Query<MovieFilter>,
     Path(username): Path<String>,
 ) -> Result<Json<Page<Movie>>, MovieramaError> {
     let page = params.page.unwrap_or(DEFAULT_PAGE);
@@ -54,7 +98,22 @@ pub async fn list_movies_by_username(
     let pageable = Pageable::new(page, size, sort.clone());
 
     let (movies, total_elements) =
-        movie_service::list_all_movies_by_username(&pool, &pageable, &username).await?;
+        movie_service::list_all_movies_by_username(&pool, &pageable, &username, &filter).await?;
+    Ok(Json(Page::new(movies, pageable, total_elements)))
+}
+
+/// GET /movies/trending
+pub async fn list_trending_movies(
+    State(pool): State<PgPool>,
+    Query(params): Query<TrendingQuery>,
+) -> Result<Json<Page<Movie>>, MovieramaError> {
+    let page = params.page.unwrap_or(DEFAULT_PAGE);
+    let size = params.size.unwrap_or(DEFAULT_SIZE);
+    // Always ranked by net votes within the window
+    let pageable = Pageable::new(page, size, Sort::from_query("netVotes,desc"));
+
+    let (movies, total_elements) =
+        movie_service::list_trending_movies(&pool, params.window, &pageable).await?;
     Ok(Json(Page::new(movies, pageable, total_elements)))
 }
 
@@ -62,42 +121,117 @@ pub async fn list_movies_by_username(
 pub async fn get_movie(
     State(pool): State<PgPool>,
     Path(movie_id): Path<i32>,
-) -> Result<Json<Movie>, MovieramaError> {
+) -> Result<impl IntoResponse, MovieramaError> {
     let movie = movie_service::get_movie_by_id(&pool, movie_id).await?;
     match movie {
-        Some(m) => Ok(Json(m)),
+        Some(m) => Ok(with_etag(m)),
         None => Err(MovieramaError::NotFound),
     }
 }
 
 /// UPDATE /movies/{movie_id}
 pub async fn update_movie(
-    _claims: Claims,
+    claims: Claims,
+    client: ClientInfo,
+    headers: HeaderMap,
     State(pool): State<PgPool>,
+    State(blobs): State<Arc<dyn BlobStore>>,
     Path(movie_id): Path<i32>,
     Json(payload): Json<NewMovie>,
-) -> Result<Json<NewMovie>, MovieramaError> {
-    let movie = movie_service::update_movie(&pool, movie_id, payload).await?;
-    Ok(Json(movie))
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+    let expected_version = if_match_version(&headers)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let movie = movie_service::update_movie(
+        &pool,
+        blobs.as_ref(),
+        claims.user_id,
+        movie_id,
+        payload,
+        expected_version,
+        &ctx,
+    )
+    .await?;
+    Ok(with_etag(movie))
+}
+
+/// PATCH /movies/{movie_id}
+pub async fn patch_movie(
+    claims: Claims,
+    client: ClientInfo,
+    headers: HeaderMap,
+    State(pool): State<PgPool>,
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Path(movie_id): Path<i32>,
+    Json(patch): Json<Value>,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+    let expected_version = if_match_version(&headers)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let movie = movie_service::patch_movie(
+        &pool,
+        blobs.as_ref(),
+        claims.user_id,
+        movie_id,
+        &patch,
+        expected_version,
+        &ctx,
+    )
+    .await?;
+    Ok(with_etag(movie))
 }
 
 /// POST /movies
 pub async fn create_movie(
     claims: Claims,
     State(pool): State<PgPool>,
+    State(metadata): State<Arc<dyn MetadataProvider>>,
+    Query(params): Query<CreateMovieQuery>,
     Json(payload): Json<NewMovie>,
 ) -> Result<Json<Movie>, MovieramaError> {
-    let movie = movie_service::create_movie(&pool, claims.user_id, payload).await?;
+    claims.require_scope(Scope::MoviesWrite)?;
+    let payload = if params.enrich {
+        movie_service::enrich_movie(metadata.as_ref(), payload).await
+    } else {
+        payload
+    };
+    let movie = movie_service::create_movie(&pool, claims.user_id, payload, params.force).await?;
     Ok(Json(movie))
 }
 
+/// GET /movies/lookup?title=
+pub async fn lookup_movies(
+    claims: Claims,
+    State(metadata): State<Arc<dyn MetadataProvider>>,
+    Query(params): Query<LookupQuery>,
+) -> Result<Json<Vec<MovieMetadata>>, MovieramaError> {
+    claims.require_scope(Scope::Read)?;
+
+    let title = params.title.trim();
+    if title.is_empty() {
+        return Err(MovieramaError::BadRequest(
+            "title must not be empty".to_owned(),
+        ));
+    }
+
+    let movies = metadata.search(title).await?;
+    Ok(Json(movies))
+}
+
 /// DELETE /movies/{movie_id}
 pub async fn delete_movie(
-    _claims: Claims,
+    claims: Claims,
+    client: ClientInfo,
+    headers: HeaderMap,
     State(pool): State<PgPool>,
     Path(movie_id): Path<i32>,
 ) -> Result<Json<Value>, MovieramaError> {
-    let success = movie_service::delete_movie(&pool, movie_id).await?;
+    claims.require_scope(Scope::MoviesWrite)?;
+    let expected_version = if_match_version(&headers)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let success =
+        movie_service::delete_movie(&pool, claims.user_id, movie_id, expected_version, &ctx)
+            .await?;
     if success {
         Ok(Json(json!(format!(
             "Movie with id {} deleted successfully",
@@ -108,6 +242,158 @@ pub async fn delete_movie(
     }
 }
 
+/// GET /movies/trash
+pub async fn list_trash(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Query(params): Query<PageableQuery>,
+) -> Result<Json<Page<TrashedMovie>>, MovieramaError> {
+    claims.require_scope(Scope::Read)?;
+
+    let page = params.page.unwrap_or(DEFAULT_PAGE);
+    let size = params.size.unwrap_or(DEFAULT_SIZE);
+    // The trash is always listed most recently deleted first
+    let pageable = Pageable::new(page, size, Sort::from_query("deletedAt,desc"));
+
+    let (movies, total_elements) =
+        movie_service::list_trash(&pool, claims.user_id, &pageable).await?;
+    Ok(Json(Page::new(movies, pageable, total_elements)))
+}
+
+/// POST /movies/{movie_id}/restore
+pub async fn restore_movie(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let movie = movie_service::restore_movie(&pool, claims.user_id, movie_id, &ctx).await?;
+    Ok(with_etag(movie))
+}
+
+/// POST /movies/{movie_id}/merge
+pub async fn merge_movie(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Path(movie_id): Path<i32>,
+    Json(payload): Json<MergeMovie>,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let movie = movie_service::merge_movies(
+        &pool,
+        blobs.as_ref(),
+        claims.user_id,
+        movie_id,
+        payload.canonical_id,
+        &ctx,
+    )
+    .await?;
+    Ok(with_etag(movie))
+}
+
+/// POST /movies/{movie_id}/poster
+///
+/// Expects the image in a multipart field named `poster`.
+pub async fn upload_poster(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Path(movie_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        if field.name() != Some("poster") {
+            continue;
+        }
+
+        let content_type = field.content_type().map(str::to_owned);
+        let data = field.bytes().await.map_err(multipart_error)?;
+
+        let ctx = AuditContext::new(Some(claims.user_id), &client);
+        let movie = poster_service::upload_poster(
+            &pool,
+            blobs.as_ref(),
+            claims.user_id,
+            movie_id,
+            content_type.as_deref(),
+            data.to_vec(),
+            &ctx,
+        )
+        .await?;
+        return Ok(with_etag(movie));
+    }
+
+    Err(MovieramaError::BadRequest(
+        "The image must be sent in a field named 'poster'".to_owned(),
+    ))
+}
+
+fn multipart_error(e: MultipartError) -> MovieramaError {
+    if e.status() == axum::http::StatusCode::PAYLOAD_TOO_LARGE {
+        MovieramaError::PayloadTooLarge
+    } else {
+        MovieramaError::BadRequest(e.body_text())
+    }
+}
+
+/// GET /movies/{movie_id}/revisions
+pub async fn list_revisions(
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+) -> Result<Json<Vec<MovieRevision>>, MovieramaError> {
+    let revisions = revision_service::list_revisions(&pool, movie_id).await?;
+    Ok(Json(revisions))
+}
+
+/// GET /movies/{movie_id}/revisions/{revision}
+pub async fn get_revision(
+    State(pool): State<PgPool>,
+    Path((movie_id, revision)): Path<(i32, i32)>,
+) -> Result<Json<MovieRevision>, MovieramaError> {
+    let revision = revision_service::get_revision(&pool, movie_id, revision).await?;
+    Ok(Json(revision))
+}
+
+/// GET /movies/{movie_id}/revisions/{revision}/diff
+pub async fn diff_revision(
+    State(pool): State<PgPool>,
+    Path((movie_id, revision)): Path<(i32, i32)>,
+    Query(params): Query<DiffQuery>,
+) -> Result<Json<RevisionDiff>, MovieramaError> {
+    let diff = revision_service::diff_revisions(&pool, movie_id, params.from, revision).await?;
+    Ok(Json(diff))
+}
+
+/// POST /movies/{movie_id}/revisions/{revision}/revert
+pub async fn revert_movie(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Path((movie_id, revision)): Path<(i32, i32)>,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_scope(Scope::MoviesWrite)?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let movie = revision_service::revert_to_revision(
+        &pool,
+        blobs.as_ref(),
+        claims.user_id,
+        movie_id,
+        revision,
+        &ctx,
+    )
+    .await?;
+    Ok(with_etag(movie))
+}
+
 /// POST /movies/{movie_id}/vote
 pub async fn vote_movie(
     claims: Claims,
@@ -115,6 +401,8 @@ pub async fn vote_movie(
     Path(movie_id): Path<i32>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Movie>, MovieramaError> {
+    claims.require_scope(Scope::VotesWrite)?;
+
     let tp: VoteType = match params.get("type") {
         Some(tp) => tp.parse()?,
         None => {
@@ -127,3 +415,33 @@ pub async fn vote_movie(
     let movie = vote_service::vote_movie(&pool, claims.user_id, movie_id, tp).await?;
     Ok(Json(movie))
 }
+
+/// Sends the movie with its version as a strong `ETag`
+fn with_etag(movie: Movie) -> impl IntoResponse {
+    let etag = format!("\"{}\"", movie.version);
+    ([(header::ETAG, etag)], Json(movie))
+}
+
+/// Reads the version a write is based on from `If-Match`, where `*` accepts
+/// any version. Writes without the header are refused so that clients cannot
+/// overwrite changes they have not seen by accident.
+fn if_match_version(headers: &HeaderMap) -> Result<Option<i32>, MovieramaError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .ok_or(MovieramaError::PreconditionRequired)?
+        .to_str()
+        .map_err(|_| MovieramaError::PreconditionFailed)?
+        .trim();
+
+    if value == "*" {
+        return Ok(None);
+    }
+
+    // Weak or malformed tags can never match a current version
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .ok_or(MovieramaError::PreconditionFailed)
+}
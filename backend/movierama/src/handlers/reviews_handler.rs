@@ -0,0 +1,65 @@
+use crate::{
+    auth::Claims,
+    exceptions::MovieramaError,
+    models::{NewReview, Review, Scope},
+    pagination::{Page, Pageable, Sort},
+    services::review_service,
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+const DEFAULT_PAGE: u32 = 0;
+const DEFAULT_SIZE: u32 = 20;
+const MAX_SIZE: u32 = 100;
+
+#[derive(Deserialize)]
+pub struct ReviewsQuery {
+    pub page: Option<u32>,
+    pub size: Option<u32>,
+}
+
+/// GET /movies/{movie_id}/reviews
+pub async fn list_reviews(
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+    Query(params): Query<ReviewsQuery>,
+) -> Result<Json<Page<Review>>, MovieramaError> {
+    let page = params.page.unwrap_or(DEFAULT_PAGE);
+    let size = params.size.unwrap_or(DEFAULT_SIZE).min(MAX_SIZE);
+    let pageable = Pageable::new(page, size, Sort::from_query("updatedAt,desc"));
+
+    let (reviews, total_elements) =
+        review_service::list_reviews(&pool, movie_id, &pageable).await?;
+    Ok(Json(Page::new(reviews, pageable, total_elements)))
+}
+
+/// PUT /movies/{movie_id}/review
+pub async fn save_review(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+    Json(payload): Json<NewReview>,
+) -> Result<Json<Review>, MovieramaError> {
+    claims.require_scope(Scope::ReviewsWrite)?;
+    let review = review_service::save_review(&pool, claims.user_id, movie_id, &payload).await?;
+    Ok(Json(review))
+}
+
+/// DELETE /movies/{movie_id}/review
+pub async fn delete_review(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Path(movie_id): Path<i32>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_scope(Scope::ReviewsWrite)?;
+    review_service::delete_review(&pool, claims.user_id, movie_id).await?;
+    Ok(Json(json!(format!(
+        "Review of movie with id {} deleted successfully",
+        movie_id
+    ))))
+}
@@ -0,0 +1,92 @@
+use crate::{
+    auth::{Claims, ClientInfo},
+    exceptions::MovieramaError,
+    models::{DeleteAccount, DeletionScheduled, Scope, UpdateProfile, UserProfile},
+    services::{account_service, audit_service::AuditContext, user_service},
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::header,
+    response::IntoResponse,
+};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+/// GET /users/{username}
+pub async fn get_user_profile(
+    State(pool): State<PgPool>,
+    Path(username): Path<String>,
+) -> Result<Json<UserProfile>, MovieramaError> {
+    let profile = user_service::get_profile_by_username(&pool, &username).await?;
+    match profile {
+        Some(p) => Ok(Json(p)),
+        None => Err(MovieramaError::NotFound),
+    }
+}
+
+/// GET /users/me
+pub async fn get_my_profile(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<Json<UserProfile>, MovieramaError> {
+    claims.require_scope(Scope::Read)?;
+    let profile = user_service::get_profile_by_id(&pool, claims.user_id).await?;
+    Ok(Json(profile))
+}
+
+/// PATCH /users/me
+pub async fn update_my_profile(
+    claims: Claims,
+    State(pool): State<PgPool>,
+    Json(payload): Json<UpdateProfile>,
+) -> Result<Json<UserProfile>, MovieramaError> {
+    claims.require_session()?;
+    let profile = user_service::update_profile(&pool, claims.user_id, payload).await?;
+    Ok(Json(profile))
+}
+
+/// GET /users/me/export
+pub async fn export_my_account(
+    claims: Claims,
+    State(pool): State<PgPool>,
+) -> Result<impl IntoResponse, MovieramaError> {
+    claims.require_session()?;
+    let export = account_service::export_account(&pool, claims.user_id).await?;
+    let disposition = format!(
+        "attachment; filename=\"movierama-{}.json\"",
+        export.account.username
+    );
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)))
+}
+
+/// DELETE /users/me
+pub async fn delete_my_account(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+    Json(payload): Json<DeleteAccount>,
+) -> Result<Json<DeletionScheduled>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let scheduled =
+        account_service::schedule_deletion(&pool, claims.user_id, claims.sid, &payload, &ctx)
+            .await?;
+    Ok(Json(scheduled))
+}
+
+/// POST /users/me/restore
+pub async fn restore_my_account(
+    claims: Claims,
+    client: ClientInfo,
+    State(pool): State<PgPool>,
+) -> Result<Json<Value>, MovieramaError> {
+    claims.require_session()?;
+    let ctx = AuditContext::new(Some(claims.user_id), &client);
+    let cancelled = account_service::cancel_deletion(&pool, claims.user_id, &ctx).await?;
+    if cancelled {
+        Ok(Json(json!("Account deletion cancelled")))
+    } else {
+        Err(MovieramaError::NotFound)
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{auth::Claims, exceptions::MovieramaError, models::VoteType, services::vote_service};
+use crate::{
+    auth::Claims,
+    exceptions::MovieramaError,
+    models::{Reaction, Scope, VoteType},
+    services::{reaction_service, vote_service},
+};
 use axum::{Json, extract::State};
 use std::collections::HashMap;
 
@@ -8,6 +13,15 @@ pub async fn get_user_votes(
     State(pool): State<sqlx::PgPool>,
     Json(movie_ids): Json<Vec<i32>>,
 ) -> Result<Json<HashMap<i32, VoteType>>, MovieramaError> {
+    claims.require_scope(Scope::Read)?;
     let votes = vote_service::get_user_votes_for_movies(&pool, claims.user_id, &movie_ids).await?;
     Ok(Json(votes))
 }
+
+/// GET /votes/reactions
+pub async fn list_reactions(
+    State(pool): State<sqlx::PgPool>,
+) -> Result<Json<Vec<Reaction>>, MovieramaError> {
+    let reactions = reaction_service::list_reactions(&pool, false).await?;
+    Ok(Json(reactions))
+}
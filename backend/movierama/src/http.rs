@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An HTTP client for calls to other services. It gives up on servers that
+/// do not answer within `timeout`, so that a slow service cannot hold our
+/// own requests up indefinitely.
+pub fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+        .timeout(timeout)
+        .build()
+        .expect("HTTP client settings are valid")
+}
@@ -1,14 +1,24 @@
 use dotenvy::dotenv;
+use rate_limit::{InMemoryStore, RateLimiter};
 use sqlx::postgres::PgPoolOptions;
-use std::net::SocketAddr;
+use state::AppState;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 mod auth;
 mod exceptions;
 mod handlers;
+mod http;
+mod merge_patch;
+mod metadata;
 mod models;
 mod pagination;
+mod password;
+mod rate_limit;
 mod routes;
+mod security;
 mod services;
+mod state;
+mod storage;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -22,13 +32,58 @@ async fn main() -> anyhow::Result<()> {
         .connect(&database_url)
         .await?;
 
-    let app = routes::create_router(pool);
+    let blobs = storage::store_from_env()?;
+
+    tokio::spawn(purge_deleted_accounts(pool.clone(), blobs.clone()));
+    tokio::spawn(purge_trashed_movies(pool.clone(), blobs.clone()));
+
+    let rate_limiter = RateLimiter::from_env(Arc::new(InMemoryStore::default()))?;
+
+    let cors = security::cors_layer_from_env()?;
+
+    let state = AppState {
+        pool,
+        metadata: metadata::provider_from_env()?,
+        blobs,
+    };
+
+    let app = routes::create_router(state, rate_limiter, cors);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 9000));
     tracing::info!("Listening on http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
+
+/// Runs the account deletions whose grace period is over
+async fn purge_deleted_accounts(pool: sqlx::PgPool, blobs: Arc<dyn storage::BlobStore>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match services::account_service::purge_due_accounts(&pool, blobs.as_ref()).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Purged {} deleted accounts", count),
+            Err(e) => tracing::error!("Failed to purge deleted accounts: {}", e),
+        }
+    }
+}
+
+/// Empties the trash of the movies kept there past the retention period
+async fn purge_trashed_movies(pool: sqlx::PgPool, blobs: Arc<dyn storage::BlobStore>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match services::movie_service::purge_trashed_movies(&pool, blobs.as_ref()).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Purged {} trashed movies", count),
+            Err(e) => tracing::error!("Failed to purge trashed movies: {}", e),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use serde_json::{Map, Value};
+
+/// Applies a JSON Merge Patch (RFC 7396) to `target`. Members set to `null`
+/// are removed, objects are merged recursively and anything else, arrays
+/// included, replaces the current value.
+pub fn apply(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                apply(target.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merged(target: Value, patch: Value) -> Value {
+        let mut target = target;
+        apply(&mut target, &patch);
+        target
+    }
+
+    #[test]
+    fn test_rfc_examples() {
+        assert_eq!(
+            merged(json!({"a": "b"}), json!({"a": "c"})),
+            json!({"a": "c"})
+        );
+        assert_eq!(
+            merged(json!({"a": "b"}), json!({"b": "c"})),
+            json!({"a": "b", "b": "c"})
+        );
+        assert_eq!(merged(json!({"a": "b"}), json!({"a": null})), json!({}));
+        assert_eq!(
+            merged(json!({"a": [{"b": "c"}]}), json!({"a": [1]})),
+            json!({"a": [1]})
+        );
+        assert_eq!(
+            merged(json!({"e": null}), json!({"a": 1})),
+            json!({"e": null, "a": 1})
+        );
+        assert_eq!(
+            merged(json!([1, 2]), json!({"a": {"bb": {"ccc": null}}})),
+            json!({"a": {"bb": {}}})
+        );
+        assert_eq!(merged(json!({"a": "foo"}), json!("bar")), json!("bar"));
+    }
+}
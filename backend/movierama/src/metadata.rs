@@ -0,0 +1,444 @@
+use crate::{exceptions::MovieramaError, http, models::MovieMetadata};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// `tmdb`, `fixture` or `off` (default)
+const METADATA_PROVIDER: &str = "METADATA_PROVIDER";
+/// Lets a local mock server stand in for the real API
+const METADATA_BASE_URL: &str = "METADATA_BASE_URL";
+const METADATA_API_KEY: &str = "METADATA_API_KEY";
+const METADATA_IMAGE_BASE_URL: &str = "METADATA_IMAGE_BASE_URL";
+/// JSON file holding an array of movies, for the `fixture` provider
+const METADATA_FIXTURE_PATH: &str = "METADATA_FIXTURE_PATH";
+const METADATA_CACHE_TTL_SECS: &str = "METADATA_CACHE_TTL_SECS";
+/// Movie creation waits for the provider, so it has to answer quickly
+const METADATA_TIMEOUT_SECS: &str = "METADATA_TIMEOUT_SECS";
+
+const DEFAULT_BASE_URL: &str = "https://api.themoviedb.org/3";
+const DEFAULT_IMAGE_BASE_URL: &str = "https://image.tmdb.org/t/p/w500";
+const DEFAULT_CACHE_TTL_SECS: u64 = 60 * 60;
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// The oldest entries are dropped once the cache holds this many
+const MAX_CACHE_ENTRIES: usize = 1_000;
+const MAX_RESULTS: usize = 10;
+
+pub type ProviderFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<MovieMetadata>, MovieramaError>> + Send + 'a>>;
+
+/// A source of movie details to fill in what users would otherwise type by
+/// hand
+pub trait MetadataProvider: Send + Sync {
+    /// Finds movies by title, best matches first
+    fn search<'a>(&'a self, title: &'a str) -> ProviderFuture<'a>;
+}
+
+/// Picks the provider configured through `METADATA_PROVIDER`, with its
+/// results cached
+pub fn provider_from_env() -> Result<Arc<dyn MetadataProvider>, MovieramaError> {
+    let setting = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+    let provider: Arc<dyn MetadataProvider> = match setting(METADATA_PROVIDER).as_deref() {
+        None | Some("off") => return Ok(Arc::new(Disabled)),
+        Some("tmdb") => Arc::new(TmdbProvider {
+            client: http::client(Duration::from_secs(
+                setting(METADATA_TIMEOUT_SECS)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_TIMEOUT_SECS),
+            )),
+            base_url: setting(METADATA_BASE_URL).unwrap_or_else(|| DEFAULT_BASE_URL.to_owned()),
+            api_key: setting(METADATA_API_KEY),
+            image_base_url: setting(METADATA_IMAGE_BASE_URL)
+                .unwrap_or_else(|| DEFAULT_IMAGE_BASE_URL.to_owned()),
+        }),
+        Some("fixture") => {
+            let path = setting(METADATA_FIXTURE_PATH).ok_or_else(|| {
+                MovieramaError::UnexpectedError(format!("{} must be set", METADATA_FIXTURE_PATH))
+            })?;
+            Arc::new(FixtureProvider::load(&path)?)
+        }
+        Some(other) => {
+            return Err(MovieramaError::UnexpectedError(format!(
+                "Unknown metadata provider '{}', expected 'tmdb', 'fixture' or 'off'",
+                other
+            )));
+        }
+    };
+
+    let ttl = setting(METADATA_CACHE_TTL_SECS)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+
+    Ok(Arc::new(CachedProvider::new(
+        provider,
+        Duration::from_secs(ttl),
+    )))
+}
+
+/// Used when no provider is configured, never finds anything
+pub struct Disabled;
+
+impl MetadataProvider for Disabled {
+    fn search<'a>(&'a self, _title: &'a str) -> ProviderFuture<'a> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+}
+
+/// Talks to the TMDB API, or anything answering `/search/movie` the same way
+pub struct TmdbProvider {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub image_base_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TmdbSearch {
+    results: Vec<TmdbMovie>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TmdbMovie {
+    id: i32,
+    title: String,
+    overview: Option<String>,
+    /// Empty for unreleased movies
+    release_date: Option<String>,
+    poster_path: Option<String>,
+    original_language: Option<String>,
+}
+
+impl TmdbProvider {
+    fn to_metadata(&self, movie: TmdbMovie) -> MovieMetadata {
+        MovieMetadata {
+            title: movie.title,
+            overview: movie.overview.filter(|o| !o.is_empty()),
+            release_date: movie
+                .release_date
+                .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok()),
+            poster_url: movie.poster_path.map(|path| {
+                format!(
+                    "{}/{}",
+                    self.image_base_url.trim_end_matches('/'),
+                    path.trim_start_matches('/')
+                )
+            }),
+            original_language: movie.original_language,
+            imdb_id: None,
+            tmdb_id: Some(movie.id),
+        }
+    }
+}
+
+impl MetadataProvider for TmdbProvider {
+    fn search<'a>(&'a self, title: &'a str) -> ProviderFuture<'a> {
+        Box::pin(async move {
+            let url = format!("{}/search/movie", self.base_url.trim_end_matches('/'));
+            let mut request = self.client.get(url).query(&[("query", title)]);
+            if let Some(api_key) = &self.api_key {
+                request = request.bearer_auth(api_key);
+            }
+
+            let search: TmdbSearch = request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(provider_error)?
+                .json()
+                .await
+                .map_err(provider_error)?;
+
+            Ok(search
+                .results
+                .into_iter()
+                .take(MAX_RESULTS)
+                .map(|movie| self.to_metadata(movie))
+                .collect())
+        })
+    }
+}
+
+/// Serves movies from a JSON file, for development and tests without network
+/// access
+pub struct FixtureProvider {
+    movies: Vec<MovieMetadata>,
+}
+
+impl FixtureProvider {
+    pub fn load(path: &str) -> Result<Self, MovieramaError> {
+        let invalid = |e: String| {
+            MovieramaError::UnexpectedError(format!(
+                "Cannot load metadata fixture '{}': {}",
+                path, e
+            ))
+        };
+
+        let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let movies = serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+
+        Ok(FixtureProvider { movies })
+    }
+}
+
+impl MetadataProvider for FixtureProvider {
+    fn search<'a>(&'a self, title: &'a str) -> ProviderFuture<'a> {
+        let title = title.trim().to_lowercase();
+        let found = self
+            .movies
+            .iter()
+            .filter(|m| m.title.to_lowercase().contains(&title))
+            .take(MAX_RESULTS)
+            .cloned()
+            .collect();
+
+        Box::pin(async { Ok(found) })
+    }
+}
+
+/// Remembers the results of another provider, so that autocomplete does not
+/// hit the external API on every keystroke
+pub struct CachedProvider {
+    inner: Arc<dyn MetadataProvider>,
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, (Instant, Vec<MovieMetadata>)>,
+    /// Keys oldest first. A key stored again is queued again, and its earlier
+    /// place in the queue is skipped since its time no longer matches.
+    by_age: VecDeque<(Instant, String)>,
+}
+
+impl CachedProvider {
+    pub fn new(inner: Arc<dyn MetadataProvider>, ttl: Duration) -> Self {
+        CachedProvider::with_capacity(inner, ttl, MAX_CACHE_ENTRIES)
+    }
+
+    fn with_capacity(inner: Arc<dyn MetadataProvider>, ttl: Duration, capacity: usize) -> Self {
+        CachedProvider {
+            inner,
+            ttl,
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    fn cached(&self, key: &str) -> Option<Vec<MovieMetadata>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .by_key
+            .get(key)
+            .filter(|(stored_at, _)| stored_at.elapsed() < self.ttl)
+            .map(|(_, movies)| movies.clone())
+    }
+
+    fn store(&self, key: String, movies: Vec<MovieMetadata>) {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { by_key, by_age } = &mut *entries;
+
+        let stored_at = Instant::now();
+        by_key.insert(key.clone(), (stored_at, movies));
+        by_age.push_back((stored_at, key));
+
+        while let Some((queued_at, oldest)) = by_age.front() {
+            let current = by_key.get(oldest).is_some_and(|(at, _)| at == queued_at);
+            if current && by_key.len() <= self.capacity && queued_at.elapsed() < self.ttl {
+                break;
+            }
+            if current {
+                by_key.remove(oldest);
+            }
+            by_age.pop_front();
+        }
+    }
+}
+
+impl MetadataProvider for CachedProvider {
+    fn search<'a>(&'a self, title: &'a str) -> ProviderFuture<'a> {
+        Box::pin(async move {
+            let key = title.trim().to_lowercase();
+            if let Some(movies) = self.cached(&key) {
+                return Ok(movies);
+            }
+
+            // Failures are not cached, the next request tries again
+            let movies = self.inner.search(title).await?;
+            self.store(key, movies.clone());
+            Ok(movies)
+        })
+    }
+}
+
+fn provider_error(e: reqwest::Error) -> MovieramaError {
+    MovieramaError::UnexpectedError(format!("Metadata provider request failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{header, method, path, query_param},
+    };
+
+    #[tokio::test]
+    async fn test_tmdb_search() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/search/movie"))
+            .and(query_param("query", "heat"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "results": [{
+                    "id": 949,
+                    "title": "Heat",
+                    "overview": "Obsessive master thief Neil McCauley...",
+                    "release_date": "1995-12-15",
+                    "poster_path": "/heat.jpg",
+                    "original_language": "en"
+                }, {
+                    "id": 1,
+                    "title": "Heat 2",
+                    "overview": "",
+                    "release_date": "",
+                    "poster_path": null,
+                    "original_language": "en"
+                }]
+            })))
+            .mount(&server)
+            .await;
+
+        let provider = TmdbProvider {
+            client: http::client(Duration::from_secs(5)),
+            base_url: server.uri(),
+            api_key: Some("secret".into()),
+            image_base_url: "https://images.test/w500/".into(),
+        };
+
+        let movies = provider.search("heat").await.unwrap();
+        assert_eq!(movies.len(), 2);
+        assert_eq!(movies[0].tmdb_id, Some(949));
+        assert_eq!(
+            movies[0].release_date,
+            NaiveDate::from_ymd_opt(1995, 12, 15)
+        );
+        assert_eq!(
+            movies[0].poster_url.as_deref(),
+            Some("https://images.test/w500/heat.jpg")
+        );
+        assert_eq!(movies[1].overview, None);
+        assert_eq!(movies[1].release_date, None);
+    }
+
+    #[tokio::test]
+    async fn test_slow_provider_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/search/movie"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "results": [] }))
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        let provider = TmdbProvider {
+            client: http::client(Duration::from_millis(200)),
+            base_url: server.uri(),
+            api_key: None,
+            image_base_url: DEFAULT_IMAGE_BASE_URL.into(),
+        };
+
+        let started = Instant::now();
+        let result = provider.search("heat").await;
+        assert!(matches!(result, Err(MovieramaError::UnexpectedError(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_fixture_provider() {
+        let path =
+            std::env::temp_dir().join(format!("movierama-fixture-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"title": "Alien", "releaseDate": "1979-05-25"}, {"title": "Aliens"}]"#,
+        )
+        .unwrap();
+
+        let provider = FixtureProvider::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(provider.search("ALIEN").await.unwrap().len(), 2);
+        assert_eq!(provider.search("aliens").await.unwrap().len(), 1);
+        assert!(provider.search("Heat").await.unwrap().is_empty());
+    }
+
+    struct Counting(AtomicUsize);
+
+    impl MetadataProvider for Counting {
+        fn search<'a>(&'a self, title: &'a str) -> ProviderFuture<'a> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let found = vec![MovieMetadata {
+                title: title.to_owned(),
+                overview: None,
+                release_date: None,
+                poster_url: None,
+                original_language: None,
+                imdb_id: None,
+                tmdb_id: None,
+            }];
+            Box::pin(async { Ok(found) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_results_are_cached() {
+        let inner = Arc::new(Counting(AtomicUsize::new(0)));
+        let provider = CachedProvider::new(inner.clone(), Duration::from_secs(60));
+
+        provider.search("Heat").await.unwrap();
+        provider.search(" heat ").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 1);
+
+        provider.search("Ronin").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 2);
+
+        let expired = CachedProvider::new(inner.clone(), Duration::ZERO);
+        expired.search("Heat").await.unwrap();
+        expired.search("Heat").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_results_are_dropped() {
+        let inner = Arc::new(Counting(AtomicUsize::new(0)));
+        let provider = CachedProvider::with_capacity(inner.clone(), Duration::from_secs(60), 2);
+
+        provider.search("Heat").await.unwrap();
+        provider.search("Ronin").await.unwrap();
+        provider.search("Heat").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 2);
+
+        // Heat was stored first, so it makes room for Alien
+        provider.search("Alien").await.unwrap();
+        provider.search("Ronin").await.unwrap();
+        provider.search("Alien").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 3);
+
+        provider.search("Heat").await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+        assert_eq!(provider.entries.lock().unwrap().by_age.len(), 2);
+    }
+}
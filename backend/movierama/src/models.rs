@@ -1,7 +1,7 @@
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr};
 
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::Type;
 
 use crate::exceptions::MovieramaError;
@@ -10,24 +10,141 @@ use crate::exceptions::MovieramaError;
 // ===== Enums =====
 //
 
+/// Code of the reaction a vote was cast with, e.g. `LIKE`. Reactions are
+/// configured by admins, so whether a code exists is checked against the
+/// `reactions` table rather than here.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Type)]
+#[serde(transparent)]
+#[sqlx(transparent)]
+pub struct VoteType(String);
+
+impl VoteType {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The two reactions every install starts with
+#[cfg(test)]
+impl VoteType {
+    pub fn like() -> Self {
+        VoteType("LIKE".to_owned())
+    }
+
+    pub fn hate() -> Self {
+        VoteType("HATE".to_owned())
+    }
+}
+
+impl FromStr for VoteType {
+    type Err = MovieramaError;
+
+    fn from_str(input: &str) -> Result<VoteType, Self::Err> {
+        let code = input.trim().to_uppercase();
+        let valid = code.len() <= 32
+            && code.starts_with(|c: char| c.is_ascii_uppercase())
+            && code
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !valid {
+            return Err(MovieramaError::BadRequest(format!(
+                "Invalid vote type '{}'",
+                input
+            )));
+        }
+
+        Ok(VoteType(code))
+    }
+}
+
+/// Permission granted to a personal access token
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "movies:write")]
+    MoviesWrite,
+    #[serde(rename = "votes:write")]
+    VotesWrite,
+    #[serde(rename = "comments:write")]
+    CommentsWrite,
+    #[serde(rename = "reviews:write")]
+    ReviewsWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::MoviesWrite => "movies:write",
+            Scope::VotesWrite => "votes:write",
+            Scope::CommentsWrite => "comments:write",
+            Scope::ReviewsWrite => "reviews:write",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = MovieramaError;
+
+    fn from_str(input: &str) -> Result<Scope, Self::Err> {
+        match input {
+            "read" => Ok(Scope::Read),
+            "movies:write" => Ok(Scope::MoviesWrite),
+            "votes:write" => Ok(Scope::VotesWrite),
+            "comments:write" => Ok(Scope::CommentsWrite),
+            "reviews:write" => Ok(Scope::ReviewsWrite),
+            _ => Err(MovieramaError::BadRequest(
+                "Invalid scope, available options are 'read', 'movies:write', 'votes:write', 'comments:write' and 'reviews:write'."
+                    .to_owned(),
+            )),
+        }
+    }
+}
+
+/// What happens to a user's movies when their account is deleted
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type)]
 #[sqlx(type_name = "TEXT")]
 #[sqlx(rename_all = "UPPERCASE")]
 #[serde(rename_all = "UPPERCASE")]
-pub enum VoteType {
-    Like,
-    Hate,
+pub enum DeletionMode {
+    /// Keep the movies, attributed to an anonymous account
+    Anonymise,
+    /// Delete the movies together with their votes
+    Delete,
 }
 
-impl FromStr for VoteType {
+/// Ordered from least to most privileged
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Type)]
+#[sqlx(type_name = "TEXT")]
+#[sqlx(rename_all = "UPPERCASE")]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Who may create an account through `/auth/register`
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationPolicy {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+impl FromStr for RegistrationPolicy {
     type Err = MovieramaError;
 
-    fn from_str(input: &str) -> Result<VoteType, Self::Err> {
+    fn from_str(input: &str) -> Result<RegistrationPolicy, Self::Err> {
         match input {
-            "LIKE" => Ok(VoteType::Like),
-            "HATE" => Ok(VoteType::Hate),
-            _ => Err(MovieramaError::BadRequest(
-                "Invalid vote type, available options are 'LIKE' and 'HATE'.".to_owned(),
+            "open" => Ok(RegistrationPolicy::Open),
+            "invite_only" => Ok(RegistrationPolicy::InviteOnly),
+            "closed" => Ok(RegistrationPolicy::Closed),
+            _ => Err(MovieramaError::UnexpectedError(
+                "Invalid registration policy, available options are 'open', 'invite_only' and 'closed'."
+                    .to_owned(),
             )),
         }
     }
@@ -43,7 +160,113 @@ pub struct User {
     pub username: String,
     pub email: String,
     #[serde(skip_serializing)] // don’t expose password in API responses
-    pub password: String,
+    pub password: Option<String>, // None for accounts created through OIDC
+}
+
+/// Public view of a user, never includes the email or password
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub username: String,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    #[serde(rename = "avatarUrl")]
+    pub avatar_url: Option<String>,
+    #[serde(rename = "joinedAt")]
+    pub joined_at: DateTime<Utc>,
+    #[serde(rename = "movieCount")]
+    pub movie_count: u64,
+    #[serde(rename = "likesReceived")]
+    pub likes_received: u64,
+    #[serde(rename = "hatesReceived")]
+    pub hates_received: u64,
+}
+
+/// Everything stored about an account, returned to its owner only
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    #[serde(rename = "exportedAt")]
+    pub exported_at: DateTime<Utc>,
+    pub account: AccountDetails,
+    pub movies: Vec<ExportedMovie>,
+    pub votes: Vec<ExportedVote>,
+    pub comments: Vec<ExportedComment>,
+    pub reviews: Vec<ExportedReview>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDetails {
+    pub username: String,
+    pub email: String,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    #[serde(rename = "avatarUrl")]
+    pub avatar_url: Option<String>,
+    #[serde(rename = "joinedAt")]
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMovie {
+    pub id: i32,
+    pub title: String,
+    pub description: Option<String>,
+    #[serde(rename = "dateAdded")]
+    pub date_added: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedVote {
+    #[serde(rename = "movieId")]
+    pub movie_id: i32,
+    #[serde(rename = "movieTitle")]
+    pub movie_title: String,
+    #[serde(rename = "type")]
+    pub vote_type: VoteType,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedComment {
+    pub id: i32,
+    #[serde(rename = "movieId")]
+    pub movie_id: i32,
+    #[serde(rename = "parentId")]
+    pub parent_id: Option<i32>,
+    pub body: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedReview {
+    #[serde(rename = "movieId")]
+    pub movie_id: i32,
+    #[serde(rename = "movieTitle")]
+    pub movie_title: String,
+    pub rating: f32,
+    pub body: Option<String>,
+    pub spoiler: bool,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccount {
+    /// Not required for accounts that only sign in through OIDC
+    pub password: Option<String>,
+    /// Second factor code, required instead for OIDC accounts that have one
+    pub code: Option<String>,
+    pub movies: DeletionMode,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletionScheduled {
+    #[serde(rename = "scheduledFor")]
+    pub scheduled_for: DateTime<Utc>,
+    pub movies: DeletionMode,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,10 +274,15 @@ pub struct RegisterUser {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// Required when registration is invite-only
+    #[serde(rename = "inviteCode", default)]
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginUser {
+    /// Either the username or the email address, matched case-insensitively
+    #[serde(alias = "email")]
     pub username: String,
     pub password: String,
 }
@@ -64,6 +292,124 @@ pub struct AuthResponse {
     pub token: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    #[serde(rename = "mfaRequired")]
+    pub mfa_required: bool,
+    #[serde(rename = "mfaToken")]
+    pub mfa_token: String,
+}
+
+/// Result of a password login: either the final token, or a challenge when
+/// the account has two-factor authentication enabled.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired(MfaChallenge),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyMfa {
+    #[serde(rename = "mfaToken")]
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    #[serde(rename = "otpauthUri")]
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    #[serde(rename = "recoveryCodes")]
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessToken {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(rename = "lastUsedAt")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Invite {
+    pub id: i32,
+    pub code: String,
+    #[serde(rename = "maxUses")]
+    pub max_uses: i32,
+    pub uses: i32,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegistrationInfo {
+    pub policy: RegistrationPolicy,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    #[serde(rename = "occurredAt")]
+    pub occurred_at: DateTime<Utc>,
+    #[serde(rename = "actorId")]
+    pub actor_id: Option<i32>,
+    pub action: String,
+    #[serde(rename = "targetType")]
+    pub target_type: String,
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+    #[serde(rename = "requestId")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: i32,
+    /// Browser and operating system, derived from the user agent
+    pub device: Option<String>,
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "lastSeenAt")]
+    pub last_seen_at: DateTime<Utc>,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// Returned once on creation, the plain token cannot be retrieved again
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub details: AccessToken,
+    pub token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Movie {
     pub id: i32,
@@ -76,6 +422,129 @@ pub struct Movie {
     pub like_count: u64,
     #[serde(rename = "hates")]
     pub hate_count: u64,
+    /// Number of votes per reaction code, `likes` and `hates` included
+    pub reactions: BTreeMap<String, u64>,
+    #[serde(rename = "releaseDate")]
+    pub release_date: Option<NaiveDate>,
+    #[serde(rename = "runtimeMinutes")]
+    pub runtime_minutes: Option<i32>,
+    pub directors: Vec<String>,
+    pub cast: Vec<String>,
+    /// ISO 639-1 code, e.g. `en`
+    #[serde(rename = "originalLanguage")]
+    pub original_language: Option<String>,
+    #[serde(rename = "imdbId")]
+    pub imdb_id: Option<String>,
+    #[serde(rename = "tmdbId")]
+    pub tmdb_id: Option<i32>,
+    #[serde(rename = "posterUrl")]
+    pub poster_url: Option<String>,
+    /// Resized copies of an uploaded poster, in `<img srcset>` syntax
+    #[serde(rename = "srcset")]
+    pub poster_srcset: Option<String>,
+    /// Comments that have not been deleted
+    #[serde(rename = "commentCount")]
+    pub comment_count: u64,
+    /// Bayesian average of the star ratings, `None` without reviews
+    #[serde(rename = "avgRating")]
+    pub avg_rating: Option<f64>,
+    #[serde(rename = "ratingCount")]
+    pub rating_count: u64,
+    /// Number of ratings of each value, from half a star up to five stars
+    #[serde(rename = "ratingHistogram")]
+    pub rating_histogram: Vec<u64>,
+    /// Changes with every edit, also sent as the `ETag` header
+    pub version: i32,
+}
+
+/// A movie as known to an external movie database
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MovieMetadata {
+    pub title: String,
+    /// The synopsis
+    pub overview: Option<String>,
+    #[serde(rename = "releaseDate")]
+    pub release_date: Option<NaiveDate>,
+    #[serde(rename = "posterUrl")]
+    pub poster_url: Option<String>,
+    #[serde(rename = "originalLanguage")]
+    pub original_language: Option<String>,
+    #[serde(rename = "imdbId")]
+    pub imdb_id: Option<String>,
+    #[serde(rename = "tmdbId")]
+    pub tmdb_id: Option<i32>,
+}
+
+/// An existing movie that a new submission looks like a duplicate of
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateCandidate {
+    pub id: i32,
+    pub title: String,
+    #[serde(rename = "releaseDate")]
+    pub release_date: Option<NaiveDate>,
+    pub username: String,
+    /// Trigram similarity of the normalized titles, from 0 to 1
+    pub similarity: f32,
+}
+
+/// Body of `POST /movies/{id}/merge`
+#[derive(Debug, Deserialize)]
+pub struct MergeMovie {
+    /// The movie that is kept
+    #[serde(rename = "canonicalId")]
+    pub canonical_id: i32,
+}
+
+/// A deleted movie that its owner can still restore
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashedMovie {
+    pub id: i32,
+    pub title: String,
+    pub description: Option<String>,
+    #[serde(rename = "dateAdded")]
+    pub date_added: DateTime<Utc>,
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: DateTime<Utc>,
+    /// When the movie and its votes will be removed for good
+    #[serde(rename = "purgeAt")]
+    pub purge_at: DateTime<Utc>,
+}
+
+/// A saved version of a movie's title and description
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MovieRevision {
+    pub revision: i32,
+    pub title: String,
+    pub description: Option<String>,
+    /// Username of the editor, `None` once their account is deleted
+    #[serde(rename = "editedBy")]
+    pub edited_by: Option<String>,
+    #[serde(rename = "editedAt")]
+    pub edited_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub text: String,
+}
+
+/// Line by line changes from one revision to another
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RevisionDiff {
+    /// `None` when diffing the first revision against nothing
+    pub from: Option<i32>,
+    pub to: i32,
+    pub title: Vec<DiffLine>,
+    pub description: Vec<DiffLine>,
 }
 
 //
@@ -83,7 +552,157 @@ pub struct Movie {
 //
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(rename = "expiresInDays")]
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct NewInvite {
+    #[serde(rename = "maxUses")]
+    pub max_uses: Option<u32>,
+    #[serde(rename = "expiresInDays")]
+    pub expires_in_days: Option<u32>,
+}
+
+/// Partial profile update: absent fields are kept, `null` clears them
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateProfile {
+    #[serde(rename = "displayName", default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub bio: Option<Option<String>>,
+    #[serde(rename = "avatarUrl", default, deserialize_with = "present")]
+    pub avatar_url: Option<Option<String>>,
+}
+
+/// Distinguishes a field sent as `null` (`Some(None)`) from a missing one (`None`)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// The editable fields of a movie. Everything but the title is optional.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct NewMovie {
     pub title: String,
+    #[serde(default)]
     pub description: Option<String>,
+    #[serde(rename = "releaseDate", default)]
+    pub release_date: Option<NaiveDate>,
+    #[serde(rename = "runtimeMinutes", default)]
+    pub runtime_minutes: Option<i32>,
+    #[serde(default)]
+    pub directors: Vec<String>,
+    #[serde(default)]
+    pub cast: Vec<String>,
+    #[serde(rename = "originalLanguage", default)]
+    pub original_language: Option<String>,
+    #[serde(rename = "imdbId", default)]
+    pub imdb_id: Option<String>,
+    #[serde(rename = "tmdbId", default)]
+    pub tmdb_id: Option<i32>,
+    #[serde(rename = "posterUrl", default)]
+    pub poster_url: Option<String>,
+}
+
+/// A comment on a movie, or a reply to another comment
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Comment {
+    pub id: i32,
+    #[serde(rename = "movieId")]
+    pub movie_id: i32,
+    #[serde(rename = "parentId")]
+    pub parent_id: Option<i32>,
+    /// `None` once the author's account or the comment is gone
+    pub username: Option<String>,
+    /// `None` for deleted comments, which are only kept to hold their
+    /// replies together
+    pub body: Option<String>,
+    pub deleted: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "editedAt")]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Direct replies, deleted ones included
+    #[serde(rename = "replyCount")]
+    pub reply_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewComment {
+    pub body: String,
+    /// The comment this one replies to
+    #[serde(rename = "parentId", default)]
+    pub parent_id: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditComment {
+    pub body: String,
+}
+
+/// A user's star rating of a movie, with an optional written review
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Review {
+    pub id: i32,
+    #[serde(rename = "movieId")]
+    pub movie_id: i32,
+    pub username: String,
+    /// From 0.5 to 5 stars, in half stars
+    pub rating: f32,
+    pub body: Option<String>,
+    /// Clients should hide the text until the reader asks for it
+    pub spoiler: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewReview {
+    pub rating: f32,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub spoiler: bool,
+}
+
+/// A kind of vote, such as `LIKE` or `RE-WATCH`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    pub code: String,
+    pub label: String,
+    pub emoji: Option<String>,
+    /// Reactions are shown in ascending order of position
+    pub position: i32,
+    /// Retired reactions keep their votes but cannot be voted with
+    pub active: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewReaction {
+    pub code: String,
+    pub label: String,
+    #[serde(default)]
+    pub emoji: Option<String>,
+    #[serde(default)]
+    pub position: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateReaction {
+    pub label: String,
+    #[serde(default)]
+    pub emoji: Option<String>,
+    pub position: i32,
+    pub active: bool,
 }
@@ -54,6 +54,12 @@ impl Sort {
         field_map.insert("likeCount", "like_count");
         field_map.insert("hateCount", "hate_count");
         field_map.insert("username", "u.username");
+        field_map.insert("releaseDate", "m.release_date");
+        field_map.insert("commentCount", "comment_count");
+        field_map.insert("avgRating", "avg_rating");
+        field_map.insert("hot", "m.hot_score");
+        field_map.insert("controversial", "m.controversy_score");
+        field_map.insert("best", "m.best_score");
 
         let parts: Vec<String> = self
             .orders
@@ -65,7 +71,8 @@ impl Sort {
                 } else {
                     "ASC"
                 };
-                Some(format!("{} {}", db_field, dir))
+                // Movies without the value go last either way
+                Some(format!("{} {} NULLS LAST", db_field, dir))
             })
             .collect();
 
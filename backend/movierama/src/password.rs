@@ -0,0 +1,131 @@
+use crate::exceptions::MovieramaError;
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+
+const ARGON2_MEMORY_KIB: &str = "ARGON2_MEMORY_KIB";
+const ARGON2_ITERATIONS: &str = "ARGON2_ITERATIONS";
+const ARGON2_PARALLELISM: &str = "ARGON2_PARALLELISM";
+const PASSWORD_PEPPER: &str = "PASSWORD_PEPPER";
+
+/// Outcome of a successful password check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Current,
+    /// The hash uses weaker parameters, another algorithm or no pepper and
+    /// should be replaced now that the plain password is known
+    Outdated,
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordConfig {
+    pub params: Params,
+    pub pepper: Option<String>,
+}
+
+impl PasswordConfig {
+    /// Reads the Argon2id cost parameters and the optional pepper, falling
+    /// back to the library defaults for anything that is not set.
+    pub fn from_env() -> Result<Self, MovieramaError> {
+        let params = Params::new(
+            env_u32(ARGON2_MEMORY_KIB)?.unwrap_or(Params::DEFAULT_M_COST),
+            env_u32(ARGON2_ITERATIONS)?.unwrap_or(Params::DEFAULT_T_COST),
+            env_u32(ARGON2_PARALLELISM)?.unwrap_or(Params::DEFAULT_P_COST),
+            None,
+        )
+        .map_err(|e| {
+            MovieramaError::UnexpectedError(format!("Invalid Argon2 parameters: {}", e))
+        })?;
+
+        let pepper = std::env::var(PASSWORD_PEPPER)
+            .ok()
+            .filter(|p| !p.is_empty());
+
+        Ok(PasswordConfig { params, pepper })
+    }
+
+    fn argon2(&self) -> Result<Argon2<'_>, MovieramaError> {
+        match &self.pepper {
+            Some(pepper) => Argon2::new_with_secret(
+                pepper.as_bytes(),
+                Algorithm::Argon2id,
+                Version::V0x13,
+                self.params.clone(),
+            )
+            .map_err(|e| MovieramaError::UnexpectedError(e.to_string())),
+            None => Ok(Argon2::new(
+                Algorithm::Argon2id,
+                Version::V0x13,
+                self.params.clone(),
+            )),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, MovieramaError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self
+            .argon2()?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?
+            .to_string())
+    }
+
+    /// Checks the password against a stored hash, returning `Unauthorized`
+    /// when it does not match.
+    ///
+    /// Hashes stored before a pepper was configured are still accepted, and
+    /// reported as outdated so that the caller can pepper them.
+    pub fn verify(&self, hash: &str, password: &str) -> Result<Verification, MovieramaError> {
+        let parsed_hash =
+            PasswordHash::new(hash).map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+
+        let peppered = self
+            .argon2()?
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok();
+
+        let matches = peppered
+            || (self.pepper.is_some()
+                && Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed_hash)
+                    .is_ok());
+
+        if !matches {
+            return Err(MovieramaError::Unauthorized);
+        }
+
+        if !peppered || self.is_weaker(&parsed_hash) {
+            Ok(Verification::Outdated)
+        } else {
+            Ok(Verification::Current)
+        }
+    }
+
+    fn is_weaker(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() < self.params.m_cost()
+                    || params.t_cost() < self.params.t_cost()
+                    || params.p_cost() < self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+fn env_u32(name: &str) -> Result<Option<u32>, MovieramaError> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| MovieramaError::UnexpectedError(format!("{} must be a number", name))),
+        Err(_) => Ok(None),
+    }
+}
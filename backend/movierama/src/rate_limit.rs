@@ -0,0 +1,438 @@
+use crate::{
+    auth::{self, Claims},
+    exceptions::MovieramaError,
+};
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limits are given as `<requests>/<seconds>`, e.g. `10/60`, or `off`
+const RATE_LIMIT_AUTH: &str = "RATE_LIMIT_AUTH";
+const RATE_LIMIT_WRITES: &str = "RATE_LIMIT_WRITES";
+const RATE_LIMIT_READS: &str = "RATE_LIMIT_READS";
+
+/// Past this many buckets the least recently used one is dropped
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    /// Login, registration and the other `/auth` endpoints
+    Auth,
+    /// Requests that change data
+    Writes,
+    Reads,
+}
+
+impl RouteGroup {
+    fn of(request: &Request) -> RouteGroup {
+        if request.uri().path().starts_with("/api/v1/auth/") {
+            RouteGroup::Auth
+        } else if matches!(*request.method(), Method::GET | Method::HEAD) {
+            RouteGroup::Reads
+        } else {
+            RouteGroup::Writes
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "auth",
+            RouteGroup::Writes => "writes",
+            RouteGroup::Reads => "reads",
+        }
+    }
+}
+
+/// A token bucket holding up to `capacity` requests, refilled evenly over
+/// `period`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    fn parse(value: &str) -> Result<Option<RateLimit>, MovieramaError> {
+        if value == "off" {
+            return Ok(None);
+        }
+
+        let invalid = || {
+            MovieramaError::UnexpectedError(format!(
+                "Invalid rate limit '{}', expected '<requests>/<seconds>' or 'off'",
+                value
+            ))
+        };
+
+        let (capacity, seconds) = value.split_once('/').ok_or_else(invalid)?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+
+        if capacity == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Some(RateLimit {
+            capacity,
+            period: Duration::from_secs(seconds),
+        }))
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub remaining: u32,
+    /// Until a request will be allowed again, zero when allowed
+    pub retry_after: Duration,
+    /// Until the bucket is full again
+    pub reset_after: Duration,
+}
+
+pub type StoreFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Decision, MovieramaError>> + Send + 'a>>;
+
+/// Keeps the buckets. The in-memory store only works for a single instance,
+/// deployments running several can plug in a shared one.
+pub trait RateLimitStore: Send + Sync {
+    /// Takes one request out of the bucket for `key`, if there is one left
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit) -> StoreFuture<'a>;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    limit: RateLimit,
+    /// Position in `Buckets::by_use`
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// Keys from the least to the most recently used
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+#[derive(Debug)]
+pub struct InMemoryStore {
+    buckets: Mutex<Buckets>,
+    capacity: usize,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        InMemoryStore::with_capacity(MAX_BUCKETS)
+    }
+}
+
+impl InMemoryStore {
+    pub fn with_capacity(capacity: usize) -> Self {
+        InMemoryStore {
+            buckets: Mutex::default(),
+            capacity,
+        }
+    }
+
+    fn take(&self, key: &str, limit: RateLimit, now: Instant) -> Decision {
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+
+        buckets.uses += 1;
+        let last_use = buckets.uses;
+
+        match buckets.by_key.get(key) {
+            Some(bucket) => {
+                buckets.by_use.remove(&bucket.last_use);
+            }
+            None if buckets.by_key.len() >= self.capacity => {
+                if let Some((_, oldest)) = buckets.by_use.pop_first() {
+                    buckets.by_key.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        buckets.by_use.insert(last_use, key.to_owned());
+
+        let bucket = buckets.by_key.entry(key.to_owned()).or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+            limit,
+            last_use,
+        });
+        bucket.limit = limit;
+        bucket.last_use = last_use;
+        refill(bucket, now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let rate = limit.refill_per_sec();
+        let retry_after = if allowed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+        };
+
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            retry_after,
+            reset_after: Duration::from_secs_f64((limit.capacity as f64 - bucket.tokens) / rate),
+        }
+    }
+}
+
+fn refill(bucket: &mut Bucket, now: Instant) {
+    let limit = bucket.limit;
+    let elapsed = now
+        .saturating_duration_since(bucket.updated_at)
+        .as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_sec()).min(limit.capacity as f64);
+    bucket.updated_at = now;
+}
+
+impl RateLimitStore for InMemoryStore {
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit) -> StoreFuture<'a> {
+        Box::pin(async move { Ok(self.take(key, limit, Instant::now())) })
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    limits: HashMap<RouteGroup, RateLimit>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter {
+            store,
+            limits: HashMap::new(),
+        }
+    }
+
+    pub fn with_limit(mut self, group: RouteGroup, limit: Option<RateLimit>) -> Self {
+        match limit {
+            Some(limit) => self.limits.insert(group, limit),
+            None => self.limits.remove(&group),
+        };
+        self
+    }
+
+    /// Reads the limit of every route group, using the defaults for the ones
+    /// that are not configured
+    pub fn from_env(store: Arc<dyn RateLimitStore>) -> Result<Self, MovieramaError> {
+        let limit = |name: &str, default: &str| match std::env::var(name) {
+            Ok(value) => RateLimit::parse(&value),
+            Err(_) => RateLimit::parse(default),
+        };
+
+        Ok(RateLimiter::new(store)
+            .with_limit(RouteGroup::Auth, limit(RATE_LIMIT_AUTH, "10/60")?)
+            .with_limit(RouteGroup::Writes, limit(RATE_LIMIT_WRITES, "30/60")?)
+            .with_limit(RouteGroup::Reads, limit(RATE_LIMIT_READS, "300/60")?))
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitState {
+    pub pool: PgPool,
+    pub limiter: RateLimiter,
+}
+
+/// Middleware applying the limit of the request's route group, per user when
+/// the request is authenticated and per client IP otherwise
+pub async fn rate_limit(
+    State(state): State<RateLimitState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() == Method::OPTIONS {
+        return next.run(request).await;
+    }
+
+    let group = RouteGroup::of(&request);
+    let Some(&limit) = state.limiter.limits.get(&group) else {
+        return next.run(request).await;
+    };
+
+    let (mut parts, body) = request.into_parts();
+
+    // Invalid credentials are left for the handler to reject. Valid claims
+    // are kept so the handler does not authenticate the request twice.
+    let subject = match Claims::from_request_parts(&mut parts, &state.pool).await {
+        Ok(claims) => {
+            let subject = format!("user:{}", claims.user_id);
+            parts.extensions.insert(claims);
+            subject
+        }
+        Err(_) => format!(
+            "ip:{}",
+            auth::client_ip(&parts).unwrap_or_else(|| "unknown".to_owned())
+        ),
+    };
+    let key = format!("{}:{}", group.as_str(), subject);
+
+    let decision = match state.limiter.store.acquire(&key, limit).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            // Fail open, an unavailable store should not take the API down
+            tracing::error!("Rate limit store failed: {}", e);
+            return next.run(Request::from_parts(parts, body)).await;
+        }
+    };
+
+    let mut response = if decision.allowed {
+        next.run(Request::from_parts(parts, body)).await
+    } else {
+        let mut response = MovieramaError::TooManyRequests.into_response();
+        insert_header(
+            response.headers_mut(),
+            "retry-after",
+            ceil_secs(decision.retry_after),
+        );
+        response
+    };
+
+    let headers = response.headers_mut();
+    insert_header(headers, "ratelimit-limit", limit.capacity as u64);
+    insert_header(headers, "ratelimit-remaining", decision.remaining as u64);
+    insert_header(headers, "ratelimit-reset", ceil_secs(decision.reset_after));
+
+    response
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: u64) {
+    headers.insert(name, HeaderValue::from(value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        capacity: 2,
+        period: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn test_bucket_empties_and_refills() {
+        let store = InMemoryStore::default();
+        let start = Instant::now();
+
+        assert!(store.take("ip:1", LIMIT, start).allowed);
+        let second = store.take("ip:1", LIMIT, start);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+
+        let denied = store.take("ip:1", LIMIT, start);
+        assert!(!denied.allowed);
+        assert_eq!(ceil_secs(denied.retry_after), 5);
+        assert_eq!(ceil_secs(denied.reset_after), 10);
+
+        // Other clients have their own bucket
+        assert!(store.take("ip:2", LIMIT, start).allowed);
+
+        // One request is refilled every five seconds
+        assert!(
+            store
+                .take("ip:1", LIMIT, start + Duration::from_secs(5))
+                .allowed
+        );
+        assert!(
+            !store
+                .take("ip:1", LIMIT, start + Duration::from_secs(5))
+                .allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_forwarded_for_shares_bucket() {
+        use axum::{
+            Router, body::Body, extract::ConnectInfo, http::StatusCode, middleware, routing::post,
+        };
+        use std::net::SocketAddr;
+        use tower::ServiceExt;
+
+        let state = RateLimitState {
+            // Never connects, anonymous requests do not touch the database
+            pool: PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            limiter: RateLimiter::new(Arc::new(InMemoryStore::default()))
+                .with_limit(RouteGroup::Auth, Some(LIMIT)),
+        };
+        let app = Router::new()
+            .route("/api/v1/auth/login", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state, rate_limit));
+
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let mut statuses = Vec::new();
+        for i in 0..3 {
+            let mut request = Request::post("/api/v1/auth/login")
+                .header("x-forwarded-for", format!("198.51.100.{}", i))
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            statuses.push(app.clone().oneshot(request).await.unwrap().status());
+        }
+
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used_bucket_is_dropped() {
+        let store = InMemoryStore::with_capacity(2);
+        let start = Instant::now();
+
+        store.take("ip:1", LIMIT, start);
+        store.take("ip:1", LIMIT, start);
+        store.take("ip:2", LIMIT, start);
+        store.take("ip:1", LIMIT, start);
+        // ip:2 is now the least recently used
+        store.take("ip:3", LIMIT, start);
+
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), 2);
+        assert_eq!(buckets.by_use.len(), 2);
+        assert!(!buckets.by_key.contains_key("ip:2"));
+        assert_eq!(buckets.by_key["ip:1"].tokens, 0.0);
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(RateLimit::parse("2/10").unwrap(), Some(LIMIT));
+        assert_eq!(RateLimit::parse("off").unwrap(), None);
+        assert!(RateLimit::parse("0/10").is_err());
+        assert!(RateLimit::parse("ten").is_err());
+    }
+}
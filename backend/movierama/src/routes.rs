@@ -1,17 +1,27 @@
-use crate::handlers::{auth_handler, movies_handler, votes_handler};
+use crate::{
+    handlers::{
+        admin_handler, auth_handler, comments_handler, movies_handler, reviews_handler,
+        users_handler, votes_handler,
+    },
+    rate_limit::{self, RateLimitState, RateLimiter},
+    security,
+    services::poster_service,
+    state::AppState,
+    storage,
+};
 use axum::{
     Router,
-    http::{self, HeaderValue},
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
+};
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    services::ServeDir,
 };
-use sqlx::PgPool;
-use tower_http::cors::CorsLayer;
-
-pub fn create_router(pool: PgPool) -> Router {
-    let cors = CorsLayer::new()
-        .allow_headers([http::header::CONTENT_TYPE])
-        .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap());
 
+pub fn create_router(state: AppState, rate_limiter: RateLimiter, cors: CorsLayer) -> Router {
     let movie_routes = Router::new()
         .route(
             "/",
@@ -21,7 +31,46 @@ pub fn create_router(pool: PgPool) -> Router {
             "/{id}",
             get(movies_handler::get_movie)
                 .delete(movies_handler::delete_movie)
-                .put(movies_handler::update_movie),
+                .put(movies_handler::update_movie)
+                .patch(movies_handler::patch_movie),
+        )
+        .route("/trash", get(movies_handler::list_trash))
+        .route("/lookup", get(movies_handler::lookup_movies))
+        .route("/trending", get(movies_handler::list_trending_movies))
+        .route("/{id}/restore", post(movies_handler::restore_movie))
+        .route("/{id}/merge", post(movies_handler::merge_movie))
+        .route(
+            "/{id}/comments",
+            get(comments_handler::list_comments).post(comments_handler::create_comment),
+        )
+        .route(
+            "/{id}/comments/{comment_id}",
+            put(comments_handler::edit_comment).delete(comments_handler::delete_comment),
+        )
+        .route("/{id}/reviews", get(reviews_handler::list_reviews))
+        .route(
+            "/{id}/review",
+            put(reviews_handler::save_review).delete(reviews_handler::delete_review),
+        )
+        .route(
+            "/{id}/poster",
+            // Leaves room for the multipart framing around the image
+            post(movies_handler::upload_poster).layer(DefaultBodyLimit::max(
+                poster_service::max_poster_bytes() + 64 * 1024,
+            )),
+        )
+        .route("/{id}/revisions", get(movies_handler::list_revisions))
+        .route(
+            "/{id}/revisions/{revision}",
+            get(movies_handler::get_revision),
+        )
+        .route(
+            "/{id}/revisions/{revision}/diff",
+            get(movies_handler::diff_revision),
+        )
+        .route(
+            "/{id}/revisions/{revision}/revert",
+            post(movies_handler::revert_movie),
         )
         .route(
             "/user/{username}",
@@ -29,16 +78,91 @@ pub fn create_router(pool: PgPool) -> Router {
         )
         .route("/{id}/vote", post(movies_handler::vote_movie));
 
-    let vote_routes = Router::new().route("/user-votes", post(votes_handler::get_user_votes));
+    let vote_routes = Router::new()
+        .route("/user-votes", post(votes_handler::get_user_votes))
+        .route("/reactions", get(votes_handler::list_reactions));
 
     let auth_routes = Router::new()
         .route("/register", post(auth_handler::register))
-        .route("/login", post(auth_handler::login));
+        .route("/registration", get(auth_handler::registration_info))
+        .route("/login", post(auth_handler::login))
+        .route("/2fa/enroll", post(auth_handler::enroll_totp))
+        .route("/2fa/confirm", post(auth_handler::confirm_totp))
+        .route("/2fa/disable", post(auth_handler::disable_totp))
+        .route("/2fa/verify", post(auth_handler::verify_totp))
+        .route(
+            "/oidc/{provider}/authorize",
+            get(auth_handler::oidc_authorize),
+        )
+        .route(
+            "/oidc/{provider}/callback",
+            get(auth_handler::oidc_callback),
+        )
+        .route(
+            "/tokens",
+            get(auth_handler::list_access_tokens).post(auth_handler::create_access_token),
+        )
+        .route(
+            "/tokens/{token_id}",
+            delete(auth_handler::revoke_access_token),
+        )
+        .route(
+            "/sessions",
+            get(auth_handler::list_sessions).delete(auth_handler::revoke_other_sessions),
+        )
+        .route(
+            "/sessions/{session_id}",
+            delete(auth_handler::revoke_session),
+        )
+        .route(
+            "/invites",
+            get(auth_handler::list_invites).post(auth_handler::create_invite),
+        )
+        .route("/invites/{invite_id}", delete(auth_handler::revoke_invite));
+
+    let user_routes = Router::new()
+        .route(
+            "/me",
+            get(users_handler::get_my_profile)
+                .patch(users_handler::update_my_profile)
+                .delete(users_handler::delete_my_account),
+        )
+        .route("/me/export", get(users_handler::export_my_account))
+        .route("/me/restore", post(users_handler::restore_my_account))
+        .route("/{username}", get(users_handler::get_user_profile));
+
+    let admin_routes = Router::new()
+        .route("/audit", get(admin_handler::list_audit_events))
+        .route(
+            "/reactions",
+            get(admin_handler::list_reactions).post(admin_handler::create_reaction),
+        )
+        .route("/reactions/{code}", put(admin_handler::update_reaction));
 
-    Router::new()
+    let mut router = Router::new()
         .nest("/api/v1/movies", movie_routes)
         .nest("/api/v1/votes", vote_routes)
         .nest("/api/v1/auth", auth_routes)
+        .nest("/api/v1/users", user_routes)
+        .nest("/api/v1/admin", admin_routes);
+
+    if let Some(dir) = storage::served_dir_from_env() {
+        router = router.nest_service(storage::LOCAL_MEDIA_PATH, ServeDir::new(dir));
+    }
+
+    let router = router
+        .layer(middleware::from_fn_with_state(
+            RateLimitState {
+                pool: state.pool.clone(),
+                limiter: rate_limiter,
+            },
+            rate_limit::rate_limit,
+        ))
         .layer(cors)
-        .with_state(pool)
+        // Outermost, so that every handler and the audit log see the id
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state);
+
+    security::with_security_headers(router)
 }
@@ -0,0 +1,177 @@
+use crate::exceptions::MovieramaError;
+use axum::{
+    Router,
+    http::{HeaderName, HeaderValue, Method, header, request::Parts},
+};
+use std::str::FromStr;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    set_header::SetResponseHeaderLayer,
+};
+
+/// Comma separated, e.g. `https://movierama.app,https://*.movierama.app`
+const CORS_ALLOWED_ORIGINS: &str = "CORS_ALLOWED_ORIGINS";
+const CORS_ALLOWED_METHODS: &str = "CORS_ALLOWED_METHODS";
+const CORS_ALLOWED_HEADERS: &str = "CORS_ALLOWED_HEADERS";
+const CORS_ALLOW_CREDENTIALS: &str = "CORS_ALLOW_CREDENTIALS";
+
+const DEFAULT_ALLOWED_ORIGINS: &str = "http://localhost:5173";
+const DEFAULT_ALLOWED_METHODS: &str = "GET,POST,PUT,PATCH,DELETE";
+const DEFAULT_ALLOWED_HEADERS: &str = "content-type,authorization,if-match";
+
+/// Response headers the frontend may read
+const EXPOSED_HEADERS: [&str; 6] = [
+    "etag",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "retry-after",
+    "x-request-id",
+];
+
+/// An allowed origin, either exact or `scheme://*.domain` which matches any
+/// subdomain of `domain` but not `domain` itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginPattern {
+    Exact(String),
+    Subdomains { scheme: String, domain: String },
+}
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Exact(allowed) => allowed == origin,
+            OriginPattern::Subdomains { scheme, domain } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .and_then(|subdomain| subdomain.strip_suffix('.'))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'))
+                }),
+        }
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = MovieramaError;
+
+    fn from_str(input: &str) -> Result<OriginPattern, Self::Err> {
+        let input = input.trim().trim_end_matches('/').to_lowercase();
+
+        let invalid =
+            || MovieramaError::UnexpectedError(format!("Invalid CORS origin '{}'", input));
+
+        let (scheme, host) = input.split_once("://").ok_or_else(invalid)?;
+        if host.is_empty() || !matches!(scheme, "http" | "https") {
+            return Err(invalid());
+        }
+
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                Ok(OriginPattern::Subdomains {
+                    scheme: scheme.to_owned(),
+                    domain: domain.to_owned(),
+                })
+            }
+            Some(_) => Err(invalid()),
+            None if host.contains('*') => Err(invalid()),
+            None => Ok(OriginPattern::Exact(input)),
+        }
+    }
+}
+
+/// Builds the CORS layer from the `CORS_*` settings
+pub fn cors_layer_from_env() -> Result<CorsLayer, MovieramaError> {
+    let setting = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_owned());
+
+    let origins = split(&setting(CORS_ALLOWED_ORIGINS, DEFAULT_ALLOWED_ORIGINS))
+        .map(str::parse)
+        .collect::<Result<Vec<OriginPattern>, _>>()?;
+
+    let methods = split(&setting(CORS_ALLOWED_METHODS, DEFAULT_ALLOWED_METHODS))
+        .map(|m| {
+            Method::from_str(&m.to_uppercase()).map_err(|_| {
+                MovieramaError::UnexpectedError(format!("Invalid CORS method '{}'", m))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let headers = split(&setting(CORS_ALLOWED_HEADERS, DEFAULT_ALLOWED_HEADERS))
+        .map(|h| {
+            HeaderName::from_str(h).map_err(|_| {
+                MovieramaError::UnexpectedError(format!("Invalid CORS header '{}'", h))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let credentials = setting(CORS_ALLOW_CREDENTIALS, "false") == "true";
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(
+            move |origin: &HeaderValue, _: &Parts| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| origins.iter().any(|p| p.matches(origin)))
+            },
+        ))
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(credentials)
+        .expose_headers(EXPOSED_HEADERS.map(HeaderName::from_static)))
+}
+
+/// Adds the security headers to every response that does not set them itself.
+/// The API only serves JSON, so the CSP forbids loading anything.
+pub fn with_security_headers(router: Router) -> Router {
+    let headers = [
+        (
+            header::STRICT_TRANSPORT_SECURITY,
+            "max-age=31536000; includeSubDomains",
+        ),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; frame-ancestors 'none'",
+        ),
+        (header::REFERRER_POLICY, "no-referrer"),
+        (header::X_FRAME_OPTIONS, "DENY"),
+    ];
+
+    headers.into_iter().fold(router, |router, (name, value)| {
+        router.layer(SetResponseHeaderLayer::if_not_present(
+            name,
+            HeaderValue::from_static(value),
+        ))
+    })
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_patterns() {
+        let exact: OriginPattern = "https://movierama.app/".parse().unwrap();
+        assert!(exact.matches("https://movierama.app"));
+        assert!(!exact.matches("https://evil.movierama.app"));
+
+        let wildcard: OriginPattern = "https://*.movierama.app".parse().unwrap();
+        assert!(wildcard.matches("https://beta.movierama.app"));
+        assert!(wildcard.matches("https://a.b.movierama.app"));
+        assert!(!wildcard.matches("https://movierama.app"));
+        assert!(!wildcard.matches("https://evilmovierama.app"));
+        assert!(!wildcard.matches("http://beta.movierama.app"));
+        assert!(!wildcard.matches("https://x.movierama.app.evil.com"));
+
+        assert!("*".parse::<OriginPattern>().is_err());
+        assert!("https://beta.*.app".parse::<OriginPattern>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::{
+    auth::Claims,
+    exceptions::MovieramaError,
+    models::{AccessToken, CreatedAccessToken, NewAccessToken, Scope},
+    services::audit_service::{self, AuditContext, NewAuditEvent},
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+
+/// Lets the `Claims` extractor tell personal access tokens apart from JWTs
+const TOKEN_PREFIX: &str = "mr_pat_";
+const DEFAULT_EXPIRY_DAYS: u32 = 30;
+const MAX_EXPIRY_DAYS: u32 = 365;
+
+#[derive(Debug, FromRow)]
+pub struct AccessTokenRow {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<AccessTokenRow> for AccessToken {
+    type Error = MovieramaError;
+
+    fn try_from(row: AccessTokenRow) -> Result<Self, Self::Error> {
+        Ok(AccessToken {
+            id: row.id,
+            name: row.name,
+            scopes: parse_scopes(&row.scopes)?,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        })
+    }
+}
+
+pub fn is_access_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+pub async fn create_access_token(
+    pool: &PgPool,
+    user_id: i32,
+    data: NewAccessToken,
+    ctx: &AuditContext,
+) -> Result<CreatedAccessToken, MovieramaError> {
+    if data.name.trim().is_empty() {
+        return Err(MovieramaError::BadRequest(
+            "Token name must not be empty".to_owned(),
+        ));
+    }
+
+    if data.scopes.is_empty() {
+        return Err(MovieramaError::BadRequest(
+            "At least one scope is required".to_owned(),
+        ));
+    }
+
+    let expires_in_days = data.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if expires_in_days == 0 || expires_in_days > MAX_EXPIRY_DAYS {
+        return Err(MovieramaError::BadRequest(format!(
+            "expiresInDays must be between 1 and {}",
+            MAX_EXPIRY_DAYS
+        )));
+    }
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let token = format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(secret));
+
+    let scopes: Vec<String> = data.scopes.iter().map(|s| s.as_str().to_owned()).collect();
+
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query_as!(
+        AccessTokenRow,
+        r#"
+        INSERT INTO personal_access_tokens (user_id, name, token_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+        RETURNING id, name, scopes, created_at, expires_at, last_used_at
+        "#,
+        user_id,
+        data.name.trim(),
+        hash_token(&token),
+        &scopes,
+        expires_in_days as i32,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // The token itself is a secret and stays out of the log
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "access_token.create",
+            target_type: "access_token",
+            target_id: Some(row.id.to_string()),
+            before: None,
+            after: Some(json!({
+                "name": row.name,
+                "scopes": row.scopes,
+                "expiresAt": row.expires_at,
+            })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(CreatedAccessToken {
+        details: row.try_into()?,
+        token,
+    })
+}
+
+pub async fn list_access_tokens(
+    pool: &PgPool,
+    user_id: i32,
+) -> Result<Vec<AccessToken>, MovieramaError> {
+    sqlx::query_as!(
+        AccessTokenRow,
+        r#"
+        SELECT id, name, scopes, created_at, expires_at, last_used_at
+        FROM personal_access_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(AccessToken::try_from)
+    .collect()
+}
+
+pub async fn revoke_access_token(
+    pool: &PgPool,
+    user_id: i32,
+    token_id: i32,
+    ctx: &AuditContext,
+) -> Result<bool, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE personal_access_tokens
+        SET revoked_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+        "#,
+        token_id,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "access_token.revoke",
+            target_type: "access_token",
+            target_id: Some(token_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Resolves a personal access token to the claims of its owner, recording
+/// the time it was used. Returns `None` for unknown, revoked or expired tokens.
+pub async fn authenticate(pool: &PgPool, token: &str) -> Result<Option<Claims>, MovieramaError> {
+    let row = sqlx::query!(
+        r#"
+        UPDATE personal_access_tokens t
+        SET last_used_at = NOW()
+        FROM users u
+        WHERE u.id = t.user_id
+        AND t.token_hash = $1
+        AND t.revoked_at IS NULL
+        AND t.expires_at > NOW()
+        RETURNING t.user_id, u.username, t.scopes, t.expires_at
+        "#,
+        hash_token(token),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    Ok(Some(Claims {
+        sub: row.username,
+        user_id: row.user_id,
+        exp: row.expires_at.timestamp() as usize,
+        sid: None,
+        scopes: Some(parse_scopes(&row.scopes)?),
+    }))
+}
+
+/// Tokens are random and long, so a fast hash is enough and allows lookups
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn parse_scopes(scopes: &[String]) -> Result<Vec<Scope>, MovieramaError> {
+    scopes.iter().map(|s| s.parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::ClientInfo, models::RegisterUser, services::auth_service};
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let reg = RegisterUser {
+            username: username.into(),
+            email: format!("{}@mail.com", username),
+            password: "password".into(),
+            invite_code: None,
+        };
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        jsonwebtoken::decode::<Claims>(
+            &auth.token,
+            &jsonwebtoken::DecodingKey::from_secret("test-secret".as_bytes()),
+            &jsonwebtoken::Validation::default(),
+        )
+        .unwrap()
+        .claims
+        .user_id
+    }
+
+    fn new_token(name: &str, scopes: Vec<Scope>) -> NewAccessToken {
+        NewAccessToken {
+            name: name.into(),
+            scopes,
+            expires_in_days: None,
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_authenticate_with_access_token(pool: PgPool) {
+        let uid = create_user(&pool, "scripter").await;
+
+        let created = create_access_token(
+            &pool,
+            uid,
+            new_token("import", vec![Scope::MoviesWrite]),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        assert!(is_access_token(&created.token));
+
+        let claims = authenticate(&pool, &created.token).await.unwrap().unwrap();
+
+        assert_eq!(claims.user_id, uid);
+        assert_eq!(claims.sub, "scripter");
+        assert!(claims.require_scope(Scope::MoviesWrite).is_ok());
+        assert!(matches!(
+            claims.require_scope(Scope::VotesWrite),
+            Err(MovieramaError::Forbidden)
+        ));
+        assert!(matches!(
+            claims.require_session(),
+            Err(MovieramaError::Forbidden)
+        ));
+
+        let tokens = list_access_tokens(&pool, uid).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_revoked_token_is_rejected(pool: PgPool) {
+        let uid = create_user(&pool, "revoker").await;
+        let other = create_user(&pool, "other").await;
+
+        let created = create_access_token(
+            &pool,
+            uid,
+            new_token("ci", vec![Scope::Read]),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        // Only the owner can revoke it
+        assert!(
+            !revoke_access_token(&pool, other, created.details.id, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+        assert!(
+            revoke_access_token(&pool, uid, created.details.id, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+
+        assert!(authenticate(&pool, &created.token).await.unwrap().is_none());
+        assert!(list_access_tokens(&pool, uid).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_expired_token_is_rejected(pool: PgPool) {
+        let uid = create_user(&pool, "expirer").await;
+
+        let created = create_access_token(
+            &pool,
+            uid,
+            new_token("old", vec![Scope::Read]),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        sqlx::query!("UPDATE personal_access_tokens SET expires_at = NOW() - INTERVAL '1 minute'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(authenticate(&pool, &created.token).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_create_access_token_validation(pool: PgPool) {
+        let uid = create_user(&pool, "validator").await;
+
+        let no_scopes = create_access_token(
+            &pool,
+            uid,
+            new_token("empty", vec![]),
+            &AuditContext::default(),
+        )
+        .await;
+        assert!(matches!(no_scopes, Err(MovieramaError::BadRequest(_))));
+
+        let mut too_long = new_token("forever", vec![Scope::Read]);
+        too_long.expires_in_days = Some(MAX_EXPIRY_DAYS + 1);
+        let result = create_access_token(&pool, uid, too_long, &AuditContext::default()).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+    }
+}
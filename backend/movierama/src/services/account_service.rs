@@ -0,0 +1,720 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{
+        AccountDetails, AccountExport, DeleteAccount, DeletionMode, DeletionScheduled,
+        ExportedComment, ExportedMovie, ExportedReview, ExportedVote, User, VoteType,
+    },
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        auth_service, mfa_service, poster_service, session_service,
+    },
+    storage::BlobStore,
+};
+use chrono::Utc;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+
+const ACCOUNT_DELETION_GRACE_DAYS: &str = "ACCOUNT_DELETION_GRACE_DAYS";
+const DEFAULT_GRACE_DAYS: i32 = 14;
+
+/// Anonymised accounts are renamed to this prefix followed by their id, so no
+/// one may take a username starting with it.
+pub const ANONYMISED_USERNAME_PREFIX: &str = "deleted-";
+
+pub fn grace_period_days() -> i32 {
+    std::env::var(ACCOUNT_DELETION_GRACE_DAYS)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GRACE_DAYS)
+}
+
+pub async fn export_account(pool: &PgPool, user_id: i32) -> Result<AccountExport, MovieramaError> {
+    let account = sqlx::query_as!(
+        AccountDetails,
+        r#"
+        SELECT username, email, display_name, bio, avatar_url, created_at AS joined_at
+        FROM users
+        WHERE id = $1
+        "#,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    let movies = sqlx::query_as!(
+        ExportedMovie,
+        r#"
+        SELECT id, title, description, date_added
+        FROM movies
+        WHERE user_id = $1
+        ORDER BY date_added
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let votes = sqlx::query_as!(
+        ExportedVote,
+        r#"
+        SELECT v.movie_id, m.title AS movie_title, v.type AS "vote_type: VoteType"
+        FROM votes v
+        JOIN movies m ON m.id = v.movie_id
+        WHERE v.user_id = $1
+        ORDER BY v.id
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let comments = sqlx::query_as!(
+        ExportedComment,
+        r#"
+        SELECT id, movie_id, parent_id, body, created_at, deleted_at
+        FROM comments
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let reviews = sqlx::query_as!(
+        ExportedReview,
+        r#"
+        SELECT
+            r.movie_id,
+            m.title AS movie_title,
+            (r.rating_half_stars / 2.0)::REAL AS "rating!",
+            r.body,
+            r.spoiler,
+            r.updated_at
+        FROM reviews r
+        JOIN movies m ON m.id = r.movie_id
+        WHERE r.user_id = $1
+        ORDER BY r.created_at
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(AccountExport {
+        exported_at: Utc::now(),
+        account,
+        movies,
+        votes,
+        comments,
+        reviews,
+    })
+}
+
+/// Marks the account for deletion once the grace period is over. Until then
+/// the owner can still log in and cancel it.
+///
+/// The owner has to prove it is them: with their password, or for accounts
+/// that only sign in through OIDC with a second factor code when one is set
+/// up, and otherwise from a session they signed in to just now.
+pub async fn schedule_deletion(
+    pool: &PgPool,
+    user_id: i32,
+    session_id: Option<i32>,
+    data: &DeleteAccount,
+    ctx: &AuditContext,
+) -> Result<DeletionScheduled, MovieramaError> {
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, username, email, password
+        FROM users
+        WHERE id = $1 AND anonymised_at IS NULL
+        "#,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    if user.password.is_some() {
+        let password = data
+            .password
+            .as_deref()
+            .ok_or(MovieramaError::Unauthorized)?;
+        auth_service::verify_password(&user, password)?;
+    } else if mfa_service::is_enabled(pool, user_id).await? {
+        let code = data.code.as_deref().ok_or(MovieramaError::Unauthorized)?;
+        if !mfa_service::verify_code(pool, user_id, code).await? {
+            return Err(MovieramaError::Unauthorized);
+        }
+    } else {
+        let session_id = session_id.ok_or(MovieramaError::Unauthorized)?;
+        if !session_service::is_recent(pool, session_id, user_id).await? {
+            return Err(MovieramaError::Unauthorized);
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let scheduled_for = sqlx::query_scalar!(
+        r#"
+        UPDATE users
+        SET deletion_scheduled_at = NOW() + make_interval(days => $2), deletion_mode = $3
+        WHERE id = $1
+        RETURNING deletion_scheduled_at AS "deletion_scheduled_at!"
+        "#,
+        user_id,
+        grace_period_days(),
+        data.movies as DeletionMode,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "account.schedule_deletion",
+            target_type: "user",
+            target_id: Some(user_id.to_string()),
+            before: None,
+            after: Some(json!({ "scheduledFor": scheduled_for, "movies": data.movies })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(DeletionScheduled {
+        scheduled_for,
+        movies: data.movies,
+    })
+}
+
+pub async fn cancel_deletion(
+    pool: &PgPool,
+    user_id: i32,
+    ctx: &AuditContext,
+) -> Result<bool, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE users
+        SET deletion_scheduled_at = NULL, deletion_mode = NULL
+        WHERE id = $1 AND deletion_scheduled_at IS NOT NULL
+        "#,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "account.cancel_deletion",
+            target_type: "user",
+            target_id: Some(user_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub fn is_reserved_username(username: &str) -> bool {
+    username
+        .to_lowercase()
+        .starts_with(ANONYMISED_USERNAME_PREFIX)
+}
+
+/// Carries out the deletions whose grace period has passed, returning how
+/// many accounts were purged. An account that fails is left for the next run.
+pub async fn purge_due_accounts(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+) -> Result<usize, MovieramaError> {
+    let due = sqlx::query!(
+        r#"
+        SELECT id, deletion_mode AS "deletion_mode!: DeletionMode"
+        FROM users
+        WHERE deletion_scheduled_at <= NOW() AND deletion_mode IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut purged = 0;
+    for account in &due {
+        let result = match account.deletion_mode {
+            DeletionMode::Delete => delete_account(pool, store, account.id).await,
+            DeletionMode::Anonymise => anonymise_account(pool, account.id).await,
+        };
+        match result {
+            Ok(()) => purged += 1,
+            Err(e) => tracing::error!("Failed to purge account {}: {}", account.id, e),
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Removes the user, their movies and every vote through `ON DELETE CASCADE`,
+/// then the poster files of those movies.
+async fn delete_account(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    user_id: i32,
+) -> Result<(), MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    // Locked so that no poster is uploaded between reading and deleting
+    let blobs: Vec<String> = sqlx::query_scalar!(
+        "SELECT poster_blobs FROM movies WHERE user_id = $1 FOR UPDATE",
+        user_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    record_purge(&mut tx, user_id, DeletionMode::Delete).await?;
+
+    tx.commit().await?;
+
+    poster_service::remove_blobs(store, &blobs).await;
+
+    Ok(())
+}
+
+/// Keeps the user's movies but strips the account of any personal data and
+/// of every way to sign in.
+async fn anonymise_account(pool: &PgPool, user_id: i32) -> Result<(), MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM votes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM user_identities WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM personal_access_tokens WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM invites WHERE created_by = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET
+            username = $2 || id,
+            email = $2 || id || '@invalid',
+            password = NULL,
+            display_name = NULL,
+            bio = NULL,
+            avatar_url = NULL,
+            totp_secret = NULL,
+            totp_enabled = FALSE,
+            totp_last_step = NULL,
+            deletion_scheduled_at = NULL,
+            deletion_mode = NULL,
+            anonymised_at = NOW()
+        WHERE id = $1
+        "#,
+        user_id,
+        ANONYMISED_USERNAME_PREFIX,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_purge(&mut tx, user_id, DeletionMode::Anonymise).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// The purge runs as a background job, so the event has no actor
+async fn record_purge(
+    conn: &mut PgConnection,
+    user_id: i32,
+    mode: DeletionMode,
+) -> Result<(), MovieramaError> {
+    audit_service::record(
+        conn,
+        &AuditContext::default(),
+        NewAuditEvent {
+            action: "account.purge",
+            target_type: "user",
+            target_id: Some(user_id.to_string()),
+            before: None,
+            after: Some(json!({ "movies": mode })),
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ClientInfo;
+    use crate::models::{NewMovie, RegisterUser};
+    use crate::services::{movie_service, vote_service};
+    use crate::storage::LocalStore;
+
+    /// For accounts without uploaded posters, where nothing is ever stored
+    fn no_blobs() -> LocalStore {
+        LocalStore {
+            root: std::env::temp_dir().join("movierama-no-blobs"),
+            public_url: "/media".into(),
+        }
+    }
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let reg = RegisterUser {
+            username: username.into(),
+            email: format!("{}@mail.com", username),
+            password: "password".into(),
+            invite_code: None,
+        };
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        jsonwebtoken::decode::<crate::auth::Claims>(
+            &auth.token,
+            &jsonwebtoken::DecodingKey::from_secret("test-secret".as_bytes()),
+            &jsonwebtoken::Validation::default(),
+        )
+        .unwrap()
+        .claims
+        .user_id
+    }
+
+    async fn create_movie(pool: &PgPool, user_id: i32, title: &str) -> i32 {
+        movie_service::create_movie(
+            pool,
+            user_id,
+            NewMovie {
+                title: title.into(),
+                description: Some("desc".into()),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    fn delete_request(movies: DeletionMode) -> DeleteAccount {
+        DeleteAccount {
+            password: Some("password".into()),
+            code: None,
+            movies,
+        }
+    }
+
+    async fn expire_grace_period(pool: &PgPool) {
+        sqlx::query!(
+            "UPDATE users SET deletion_scheduled_at = NOW() - INTERVAL '1 minute' WHERE deletion_scheduled_at IS NOT NULL"
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_export_account(pool: PgPool) {
+        let uid = create_user(&pool, "exporter").await;
+        let other = create_user(&pool, "other").await;
+
+        create_movie(&pool, uid, "Mine").await;
+        let theirs = create_movie(&pool, other, "Theirs").await;
+        vote_service::insert_vote(&pool, uid, theirs, VoteType::like())
+            .await
+            .unwrap();
+
+        let export = export_account(&pool, uid).await.unwrap();
+
+        assert_eq!(export.account.email, "exporter@mail.com");
+        assert_eq!(export.movies.len(), 1);
+        assert_eq!(export.movies[0].title, "Mine");
+        assert_eq!(export.votes.len(), 1);
+        assert_eq!(export.votes[0].movie_title, "Theirs");
+        assert_eq!(export.votes[0].vote_type, VoteType::like());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_schedule_deletion_wrong_password(pool: PgPool) {
+        let uid = create_user(&pool, "careful").await;
+
+        let result = schedule_deletion(
+            &pool,
+            uid,
+            None,
+            &DeleteAccount {
+                password: Some("wrongpass".into()),
+                code: None,
+                movies: DeletionMode::Delete,
+            },
+            &AuditContext::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_schedule_deletion_without_password(pool: PgPool) {
+        let uid = sqlx::query_scalar!(
+            "INSERT INTO users (username, email) VALUES ('oidc', 'oidc@mail.com') RETURNING id"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let request = DeleteAccount {
+            password: None,
+            code: None,
+            movies: DeletionMode::Delete,
+        };
+        let ctx = AuditContext::default();
+
+        // Holding a token is not enough, the owner has to have signed in just now
+        let result = schedule_deletion(&pool, uid, None, &request, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+
+        let mut conn = pool.acquire().await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::days(1);
+        let sid =
+            session_service::create_session(&mut conn, uid, &ClientInfo::default(), expires_at)
+                .await
+                .unwrap();
+        sqlx::query!(
+            "UPDATE sessions SET created_at = NOW() - INTERVAL '1 hour' WHERE id = $1",
+            sid,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let result = schedule_deletion(&pool, uid, Some(sid), &request, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+
+        let sid =
+            session_service::create_session(&mut conn, uid, &ClientInfo::default(), expires_at)
+                .await
+                .unwrap();
+        schedule_deletion(&pool, uid, Some(sid), &request, &ctx)
+            .await
+            .unwrap();
+        assert!(cancel_deletion(&pool, uid, &ctx).await.unwrap());
+
+        // With a second factor set up, a fresh session does not replace its code
+        sqlx::query!(
+            "UPDATE users SET totp_secret = 'JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP', totp_enabled = TRUE WHERE id = $1",
+            uid,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let result = schedule_deletion(&pool, uid, Some(sid), &request, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+
+        let wrong_code = DeleteAccount {
+            code: Some("000000".into()),
+            ..request
+        };
+        let result = schedule_deletion(&pool, uid, Some(sid), &wrong_code, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_deletion_waits_for_grace_period(pool: PgPool) {
+        let uid = create_user(&pool, "patient").await;
+
+        let scheduled = schedule_deletion(
+            &pool,
+            uid,
+            None,
+            &delete_request(DeletionMode::Delete),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        assert!(scheduled.scheduled_for > Utc::now());
+
+        assert_eq!(purge_due_accounts(&pool, &no_blobs()).await.unwrap(), 0);
+
+        assert!(
+            cancel_deletion(&pool, uid, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+        expire_grace_period(&pool).await;
+        assert_eq!(purge_due_accounts(&pool, &no_blobs()).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_purge_deletes_account_and_movies(pool: PgPool) {
+        let uid = create_user(&pool, "leaver").await;
+        let movie_id = create_movie(&pool, uid, "Gone").await;
+
+        let root = std::env::temp_dir().join(format!("movierama-leaver-{}", std::process::id()));
+        let store = LocalStore {
+            root: root.clone(),
+            public_url: "/media".into(),
+        };
+        let key = format!("posters/{}.jpg", movie_id);
+        store.put(&key, "image/jpeg", vec![0xFF]).await.unwrap();
+        sqlx::query!(
+            "UPDATE movies SET poster_blobs = ARRAY[$2] WHERE id = $1",
+            movie_id,
+            key,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        schedule_deletion(
+            &pool,
+            uid,
+            None,
+            &delete_request(DeletionMode::Delete),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        expire_grace_period(&pool).await;
+
+        assert_eq!(purge_due_accounts(&pool, &store).await.unwrap(), 1);
+
+        assert!(
+            movie_service::get_movie_by_id(&pool, movie_id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(!root.join(key).exists());
+        assert!(matches!(
+            export_account(&pool, uid).await,
+            Err(MovieramaError::NotFound)
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_purge_anonymises_account(pool: PgPool) {
+        let uid = create_user(&pool, "ghost").await;
+        let other = create_user(&pool, "stayer").await;
+        let movie_id = create_movie(&pool, uid, "Kept").await;
+        let other_movie = create_movie(&pool, other, "Voted").await;
+        vote_service::insert_vote(&pool, uid, other_movie, VoteType::hate())
+            .await
+            .unwrap();
+
+        schedule_deletion(
+            &pool,
+            uid,
+            None,
+            &delete_request(DeletionMode::Anonymise),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        expire_grace_period(&pool).await;
+
+        assert_eq!(purge_due_accounts(&pool, &no_blobs()).await.unwrap(), 1);
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.username, format!("deleted-{}", uid));
+
+        let voted = movie_service::get_movie_by_id(&pool, other_movie)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(voted.hate_count, 0);
+
+        let export = export_account(&pool, uid).await.unwrap();
+        assert!(export.account.email.ends_with("@invalid"));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_purge_continues_past_failures(pool: PgPool) {
+        let stuck = create_user(&pool, "stuck").await;
+        let leaver = create_user(&pool, "leaver").await;
+
+        // A name taken before the prefix was reserved blocks the anonymisation
+        sqlx::query!(
+            "INSERT INTO users (username, email) VALUES ($1, 'legacy@mail.com')",
+            format!("{}{}", ANONYMISED_USERNAME_PREFIX, stuck),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let ctx = AuditContext::default();
+        schedule_deletion(
+            &pool,
+            stuck,
+            None,
+            &delete_request(DeletionMode::Anonymise),
+            &ctx,
+        )
+        .await
+        .unwrap();
+        schedule_deletion(
+            &pool,
+            leaver,
+            None,
+            &delete_request(DeletionMode::Delete),
+            &ctx,
+        )
+        .await
+        .unwrap();
+        expire_grace_period(&pool).await;
+
+        assert_eq!(purge_due_accounts(&pool, &no_blobs()).await.unwrap(), 1);
+
+        assert!(matches!(
+            export_account(&pool, leaver).await,
+            Err(MovieramaError::NotFound)
+        ));
+        // Still due, so the next run tries again
+        let export = export_account(&pool, stuck).await.unwrap();
+        assert_eq!(export.account.email, "stuck@mail.com");
+    }
+}
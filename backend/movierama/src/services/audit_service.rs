@@ -0,0 +1,364 @@
+use crate::{
+    auth::ClientInfo, exceptions::MovieramaError, models::AuditEvent, pagination::Pageable,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::{PgConnection, PgPool};
+
+/// Who performed an action and the request it came from
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    /// `None` for anonymous requests and background jobs
+    pub actor_id: Option<i32>,
+    pub ip_address: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new(actor_id: Option<i32>, client: &ClientInfo) -> Self {
+        AuditContext {
+            actor_id,
+            ip_address: client.ip_address.clone(),
+            request_id: client.request_id.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NewAuditEvent<'a> {
+    pub action: &'a str,
+    pub target_type: &'a str,
+    pub target_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Appends an event. Pass the transaction of the audited change so that
+/// both are committed or neither is.
+pub async fn record(
+    conn: &mut PgConnection,
+    ctx: &AuditContext,
+    event: NewAuditEvent<'_>,
+) -> Result<(), MovieramaError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_events
+            (actor_id, action, target_type, target_id, before, after, ip_address, request_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        ctx.actor_id,
+        event.action,
+        event.target_type,
+        event.target_id,
+        event.before,
+        event.after,
+        ctx.ip_address,
+        ctx.request_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_events(
+    pool: &PgPool,
+    filter: &AuditFilter,
+    pageable: &Pageable,
+) -> Result<(Vec<AuditEvent>, u64), MovieramaError> {
+    let total_elements = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM audit_events
+        WHERE ($1::INTEGER IS NULL OR actor_id = $1)
+        AND ($2::TEXT IS NULL OR action = $2)
+        AND ($3::TEXT IS NULL OR target_type = $3)
+        AND ($4::TEXT IS NULL OR target_id = $4)
+        AND ($5::TIMESTAMPTZ IS NULL OR occurred_at >= $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR occurred_at < $6)
+        "#,
+        filter.actor_id,
+        filter.action,
+        filter.target_type,
+        filter.target_id,
+        filter.from,
+        filter.to,
+    )
+    .fetch_one(pool)
+    .await? as u64;
+
+    let events = sqlx::query_as!(
+        AuditEvent,
+        r#"
+        SELECT
+            id,
+            occurred_at,
+            actor_id,
+            action,
+            target_type,
+            target_id,
+            before,
+            after,
+            ip_address,
+            request_id
+        FROM audit_events
+        WHERE ($1::INTEGER IS NULL OR actor_id = $1)
+        AND ($2::TEXT IS NULL OR action = $2)
+        AND ($3::TEXT IS NULL OR target_type = $3)
+        AND ($4::TEXT IS NULL OR target_id = $4)
+        AND ($5::TIMESTAMPTZ IS NULL OR occurred_at >= $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR occurred_at < $6)
+        ORDER BY occurred_at DESC, id DESC
+        LIMIT $7 OFFSET $8
+        "#,
+        filter.actor_id,
+        filter.action,
+        filter.target_type,
+        filter.target_id,
+        filter.from,
+        filter.to,
+        pageable.page_size as i64,
+        pageable.offset as i64,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok((events, total_elements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{NewAccessToken, NewMovie, Scope},
+        pagination::Sort,
+        services::{access_token_service, movie_service},
+        storage::LocalStore,
+    };
+
+    fn first_page() -> Pageable {
+        Pageable::new(0, 10, Sort::from_query("occurredAt,desc"))
+    }
+
+    /// None of these movies has an uploaded poster, so nothing is ever stored
+    fn no_blobs() -> LocalStore {
+        LocalStore {
+            root: std::env::temp_dir().join("movierama-no-blobs"),
+            public_url: "/media".into(),
+        }
+    }
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_update_records_snapshots(pool: PgPool) {
+        let user_id = create_user(&pool, "editor").await;
+        let movie = movie_service::create_movie(
+            &pool,
+            user_id,
+            NewMovie {
+                title: "Alien".into(),
+                description: None,
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+
+        let ctx = AuditContext {
+            actor_id: Some(user_id),
+            ip_address: Some("203.0.113.7".into()),
+            request_id: Some("req-1".into()),
+        };
+        movie_service::update_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            NewMovie {
+                title: "Aliens".into(),
+                description: Some("This time it's war".into()),
+                ..Default::default()
+            },
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+
+        let filter = AuditFilter {
+            action: Some("movie.update".into()),
+            ..Default::default()
+        };
+        let (events, total) = list_events(&pool, &filter, &first_page()).await.unwrap();
+
+        assert_eq!(total, 1);
+        let event = &events[0];
+        assert_eq!(event.actor_id, Some(user_id));
+        assert_eq!(event.target_id, Some(movie.id.to_string()));
+        assert_eq!(event.request_id.as_deref(), Some("req-1"));
+        assert_eq!(event.before.as_ref().unwrap()["title"], "Alien");
+        assert_eq!(event.after.as_ref().unwrap()["title"], "Aliens");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_failed_change_is_not_recorded(pool: PgPool) {
+        let result = movie_service::update_movie(
+            &pool,
+            &no_blobs(),
+            1,
+            99999,
+            NewMovie {
+                title: "Nothing".into(),
+                description: None,
+                ..Default::default()
+            },
+            None,
+            &AuditContext::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::NotFound)));
+
+        let (_, total) = list_events(&pool, &AuditFilter::default(), &first_page())
+            .await
+            .unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_events_are_append_only(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        record(
+            &mut conn,
+            &AuditContext::default(),
+            NewAuditEvent {
+                action: "movie.delete",
+                target_type: "movie",
+                target_id: Some("1".into()),
+                before: None,
+                after: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let update = sqlx::query!("UPDATE audit_events SET action = 'nothing'")
+            .execute(&pool)
+            .await;
+        assert!(update.is_err());
+
+        let delete = sqlx::query!("DELETE FROM audit_events")
+            .execute(&pool)
+            .await;
+        assert!(delete.is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_filter_and_paginate(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        for (actor_id, action) in [(1, "auth.login"), (2, "auth.login"), (1, "movie.delete")] {
+            let ctx = AuditContext {
+                actor_id: Some(actor_id),
+                ..Default::default()
+            };
+            let event = NewAuditEvent {
+                action,
+                target_type: "user",
+                target_id: Some(actor_id.to_string()),
+                before: None,
+                after: None,
+            };
+            record(&mut conn, &ctx, event).await.unwrap();
+        }
+
+        let by_actor = AuditFilter {
+            actor_id: Some(1),
+            ..Default::default()
+        };
+        let (events, total) = list_events(&pool, &by_actor, &first_page()).await.unwrap();
+        assert_eq!(total, 2);
+        // Newest first
+        assert_eq!(events[0].action, "movie.delete");
+
+        let second_page = Pageable::new(1, 2, Sort::from_query("occurredAt,desc"));
+        let (events, total) = list_events(&pool, &AuditFilter::default(), &second_page)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(events.len(), 1);
+
+        let future = AuditFilter {
+            from: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        let (_, total) = list_events(&pool, &future, &first_page()).await.unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_access_token_changes_are_recorded(pool: PgPool) {
+        let user_id = create_user(&pool, "scripter").await;
+        let ctx = AuditContext {
+            actor_id: Some(user_id),
+            ..Default::default()
+        };
+
+        let created = access_token_service::create_access_token(
+            &pool,
+            user_id,
+            NewAccessToken {
+                name: "ci".into(),
+                scopes: vec![Scope::Read],
+                expires_in_days: None,
+            },
+            &ctx,
+        )
+        .await
+        .unwrap();
+        let token_id = created.details.id;
+        access_token_service::revoke_access_token(&pool, user_id, token_id, &ctx)
+            .await
+            .unwrap();
+        // Nothing changes the second time, so nothing is recorded
+        access_token_service::revoke_access_token(&pool, user_id, token_id, &ctx)
+            .await
+            .unwrap();
+
+        let filter = AuditFilter {
+            target_type: Some("access_token".into()),
+            target_id: Some(token_id.to_string()),
+            ..Default::default()
+        };
+        let (events, total) = list_events(&pool, &filter, &first_page()).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(events[0].action, "access_token.revoke");
+        assert_eq!(events[1].action, "access_token.create");
+        assert_eq!(events[1].actor_id, Some(user_id));
+        let after = events[1].after.as_ref().unwrap();
+        assert_eq!(after["name"], "ci");
+        assert!(!after.to_string().contains(&created.token));
+    }
+}
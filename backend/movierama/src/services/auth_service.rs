@@ -1,26 +1,65 @@
 use crate::{
-    auth::Claims,
+    auth::{self, Claims, ClientInfo},
     exceptions::MovieramaError,
-    models::{AuthResponse, LoginUser, RegisterUser, User},
-};
-use argon2::{
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
-    password_hash::{SaltString, rand_core::OsRng},
+    models::{
+        AuthResponse, LoginResponse, LoginUser, MfaChallenge, RegisterUser, RegistrationPolicy,
+        User,
+    },
+    password::{PasswordConfig, Verification},
+    services::{
+        account_service,
+        audit_service::{self, AuditContext, NewAuditEvent},
+        invite_service, mfa_service, session_service,
+    },
 };
 use jsonwebtoken::{EncodingKey, Header, encode};
 use sqlx::PgPool;
 
-const JWT_SECRET: &str = "JWT_SECRET";
-
 pub async fn register_user(
     pool: &PgPool,
     data: &RegisterUser,
+    client: &ClientInfo,
 ) -> Result<AuthResponse, MovieramaError> {
-    let salt = SaltString::generate(&mut OsRng);
-    let hashed = Argon2::default()
-        .hash_password(data.password.as_bytes(), &salt)
-        .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?
-        .to_string();
+    let policy = invite_service::registration_policy()?;
+    let user = create_user(pool, data, policy).await?;
+
+    let token = create_token(pool, &user, client).await?;
+
+    Ok(AuthResponse { token })
+}
+
+async fn create_user(
+    pool: &PgPool,
+    data: &RegisterUser,
+    policy: RegistrationPolicy,
+) -> Result<User, MovieramaError> {
+    // Logins containing an '@' are looked up by email
+    if data.username.contains('@') {
+        return Err(MovieramaError::BadRequest(
+            "Username must not contain '@'".to_owned(),
+        ));
+    }
+    if account_service::is_reserved_username(&data.username) {
+        return Err(MovieramaError::BadRequest(format!(
+            "Username must not start with '{}'",
+            account_service::ANONYMISED_USERNAME_PREFIX
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    match policy {
+        RegistrationPolicy::Open => {}
+        RegistrationPolicy::InviteOnly => {
+            let code = data.invite_code.as_deref().ok_or_else(|| {
+                MovieramaError::BadRequest("An invite code is required to register".to_owned())
+            })?;
+            invite_service::consume_invite(&mut tx, code).await?;
+        }
+        RegistrationPolicy::Closed => return Err(MovieramaError::Forbidden),
+    }
+
+    let hashed = PasswordConfig::from_env()?.hash(&data.password)?;
 
     let user = sqlx::query_as!(
         User,
@@ -33,62 +72,182 @@ pub async fn register_user(
         data.email,
         hashed,
     )
-    .fetch_one(pool)
-    .await?;
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(identity_taken)?;
 
-    let token = create_token(user, &data.password)?;
+    tx.commit().await?;
 
-    Ok(AuthResponse { token })
+    Ok(user)
 }
 
-pub async fn login_user(pool: &PgPool, data: &LoginUser) -> Result<AuthResponse, MovieramaError> {
-    let user = sqlx::query_as!(
-        User,
-        r#"
-        SELECT id, username, email, password
-        FROM users
-        WHERE username = $1
-        "#,
-        data.username,
-    )
-    .fetch_optional(pool)
-    .await?;
+/// Reports a clash on the case-insensitive unique identities as a conflict
+fn identity_taken(e: sqlx::Error) -> MovieramaError {
+    let constraint = e.as_database_error().and_then(|e| e.constraint());
+    match constraint {
+        Some("users_username_lower_key") => {
+            MovieramaError::Conflict("This username is already taken".to_owned())
+        }
+        Some("users_email_lower_key") => {
+            MovieramaError::Conflict("This email is already registered".to_owned())
+        }
+        _ => MovieramaError::DatabaseError(e),
+    }
+}
+
+pub async fn login_user(
+    pool: &PgPool,
+    data: &LoginUser,
+    client: &ClientInfo,
+) -> Result<LoginResponse, MovieramaError> {
+    // Usernames cannot contain an '@', so anything with one is an email. Two
+    // queries rather than one, so that each can use its column's index.
+    let user = if data.username.contains('@') {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, email, password
+            FROM users
+            WHERE LOWER(email) = LOWER($1)
+            "#,
+            data.username,
+        )
+        .fetch_optional(pool)
+        .await?
+    } else {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, email, password
+            FROM users
+            WHERE LOWER(username) = LOWER($1)
+            "#,
+            data.username,
+        )
+        .fetch_optional(pool)
+        .await?
+    };
 
     let user = match user {
         Some(u) => u,
         None => return Err(MovieramaError::NotFound),
     };
 
-    let token = create_token(user, &data.password)?;
+    let verification = match verify_password(&user, &data.password) {
+        Ok(verification) => verification,
+        Err(MovieramaError::Unauthorized) => {
+            let mut conn = pool.acquire().await?;
+            audit_service::record(
+                &mut conn,
+                &AuditContext::new(None, client),
+                NewAuditEvent {
+                    action: "auth.login_failed",
+                    target_type: "user",
+                    target_id: Some(user.id.to_string()),
+                    before: None,
+                    after: None,
+                },
+            )
+            .await?;
+            return Err(MovieramaError::Unauthorized);
+        }
+        Err(e) => return Err(e),
+    };
 
-    Ok(AuthResponse { token })
+    if verification == Verification::Outdated {
+        rehash_password(pool, user.id, &data.password).await?;
+    }
+
+    finish_login(pool, &user, client).await
 }
 
-fn create_token(user: User, password: &str) -> Result<String, MovieramaError> {
-    let parsed_hash = PasswordHash::new(&user.password)
-        .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+/// Issues the access token for a user whose primary credentials have been
+/// checked, or an MFA challenge if the account has a second factor.
+pub async fn finish_login(
+    pool: &PgPool,
+    user: &User,
+    client: &ClientInfo,
+) -> Result<LoginResponse, MovieramaError> {
+    if mfa_service::is_enabled(pool, user.id).await? {
+        let mfa_token = mfa_service::create_mfa_token(user)?;
+        return Ok(LoginResponse::MfaRequired(MfaChallenge {
+            mfa_required: true,
+            mfa_token,
+        }));
+    }
+
+    let token = create_token(pool, user, client).await?;
+
+    Ok(LoginResponse::Authenticated(AuthResponse { token }))
+}
 
-    if Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_err()
-    {
+pub fn verify_password(user: &User, password: &str) -> Result<Verification, MovieramaError> {
+    let Some(hash) = &user.password else {
         return Err(MovieramaError::Unauthorized);
-    }
+    };
 
-    // Create JWT
+    PasswordConfig::from_env()?.verify(hash, password)
+}
+
+/// Replaces the stored hash with one made with the current parameters
+async fn rehash_password(
+    pool: &PgPool,
+    user_id: i32,
+    password: &str,
+) -> Result<(), MovieramaError> {
+    let hashed = PasswordConfig::from_env()?.hash(password)?;
+
+    sqlx::query!(
+        "UPDATE users SET password = $2 WHERE id = $1",
+        user_id,
+        hashed,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Starts a session for an already authenticated user and issues its
+/// access token
+pub async fn create_token(
+    pool: &PgPool,
+    user: &User,
+    client: &ClientInfo,
+) -> Result<String, MovieramaError> {
     let expiration = chrono::Utc::now()
         .checked_add_signed(chrono::Duration::hours(24))
-        .unwrap()
-        .timestamp() as usize;
+        .unwrap();
+
+    let mut tx = pool.begin().await?;
+
+    let session_id = session_service::create_session(&mut tx, user.id, client, expiration).await?;
+
+    audit_service::record(
+        &mut tx,
+        &AuditContext::new(Some(user.id), client),
+        NewAuditEvent {
+            action: "auth.login",
+            target_type: "session",
+            target_id: Some(session_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
 
+    tx.commit().await?;
+
+    // Create JWT
     let claims = Claims {
         sub: user.username.clone(),
         user_id: user.id,
-        exp: expiration,
+        exp: expiration.timestamp() as usize,
+        sid: Some(session_id),
+        scopes: None,
     };
 
-    let jwt_secret =
-        std::env::var(JWT_SECRET).map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+    let jwt_secret = auth::jwt_secret()?;
 
     let token = encode(
         &Header::default(),
@@ -111,9 +270,12 @@ mod tests {
             username: "user1".into(),
             email: "user1@mail.com".into(),
             password: "password".into(),
+            invite_code: None,
         };
 
-        let result = register_user(&pool, &data).await.unwrap();
+        let result = register_user(&pool, &data, &ClientInfo::default())
+            .await
+            .unwrap();
         assert!(!result.token.is_empty());
     }
 
@@ -126,7 +288,9 @@ mod tests {
                 username: "demo".into(),
                 email: "demo@mail.com".into(),
                 password: "password".into(),
+                invite_code: None,
             },
+            &ClientInfo::default(),
         )
         .await
         .unwrap();
@@ -138,11 +302,15 @@ mod tests {
                 username: "demo".into(),
                 password: "password".into(),
             },
+            &ClientInfo::default(),
         )
         .await
         .unwrap();
 
-        assert!(!resp.token.is_empty());
+        match resp {
+            LoginResponse::Authenticated(auth) => assert!(!auth.token.is_empty()),
+            LoginResponse::MfaRequired(_) => panic!("MFA is not enabled for this user"),
+        }
     }
 
     #[sqlx::test(migrations = "./migrations")]
@@ -153,7 +321,9 @@ mod tests {
                 username: "pavlos".into(),
                 email: "pavlos@mail.com".into(),
                 password: "password".into(),
+                invite_code: None,
             },
+            &ClientInfo::default(),
         )
         .await
         .unwrap();
@@ -164,6 +334,7 @@ mod tests {
                 username: "pavlos".into(),
                 password: "wrongpass".into(),
             },
+            &ClientInfo::default(),
         )
         .await;
 
@@ -178,6 +349,7 @@ mod tests {
                 username: "ghost".into(),
                 password: "password".into(),
             },
+            &ClientInfo::default(),
         )
         .await;
 
@@ -190,12 +362,237 @@ mod tests {
             username: "dup".into(),
             email: "dup@mail.com".into(),
             password: "password".into(),
+            invite_code: None,
+        };
+
+        register_user(&pool, &data, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        let duplicate = register_user(&pool, &data, &ClientInfo::default()).await;
+
+        assert!(matches!(duplicate, Err(MovieramaError::Conflict(_))));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_register_user_reserved_username(pool: PgPool) {
+        let data = RegisterUser {
+            username: "Deleted-1".into(),
+            email: "imposter@mail.com".into(),
+            password: "password".into(),
+            invite_code: None,
+        };
+
+        let result = register_user(&pool, &data, &ClientInfo::default()).await;
+
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_register_user_duplicate_username_case(pool: PgPool) {
+        register_user(
+            &pool,
+            &RegisterUser {
+                username: "alice".into(),
+                email: "alice@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let same_username = register_user(
+            &pool,
+            &RegisterUser {
+                username: "Alice".into(),
+                email: "other@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await;
+        assert!(matches!(same_username, Err(MovieramaError::Conflict(_))));
+
+        let same_email = register_user(
+            &pool,
+            &RegisterUser {
+                username: "alice2".into(),
+                email: "ALICE@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await;
+        assert!(matches!(same_email, Err(MovieramaError::Conflict(_))));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_login_user_by_email_any_case(pool: PgPool) {
+        register_user(
+            &pool,
+            &RegisterUser {
+                username: "Bob".into(),
+                email: "bob@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        for login in ["bob", "BOB@Mail.com"] {
+            let resp = login_user(
+                &pool,
+                &LoginUser {
+                    username: login.into(),
+                    password: "password".into(),
+                },
+                &ClientInfo::default(),
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(resp, LoginResponse::Authenticated(_)));
+        }
+
+        let login: LoginUser =
+            serde_json::from_str(r#"{"email": "bob@mail.com", "password": "password"}"#).unwrap();
+        assert_eq!(login.username, "bob@mail.com");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_login_rehashes_weak_password_hash(pool: PgPool) {
+        register_user(
+            &pool,
+            &RegisterUser {
+                username: "legacy".into(),
+                email: "legacy@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let weak = PasswordConfig {
+            params: argon2::Params::new(8, 1, 1, None).unwrap(),
+            pepper: None,
+        };
+        sqlx::query!(
+            "UPDATE users SET password = $1 WHERE username = 'legacy'",
+            weak.hash("password").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        login_user(
+            &pool,
+            &LoginUser {
+                username: "legacy".into(),
+                password: "password".into(),
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let stored = sqlx::query_scalar!("SELECT password FROM users WHERE username = 'legacy'")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let current = PasswordConfig::from_env().unwrap();
+        assert_eq!(
+            current.verify(&stored, "password").unwrap(),
+            Verification::Current
+        );
+    }
+
+    #[test]
+    fn test_password_pepper() {
+        let unpeppered = PasswordConfig {
+            params: argon2::Params::new(8, 1, 1, None).unwrap(),
+            pepper: None,
+        };
+        let peppered = PasswordConfig {
+            pepper: Some("pepper".into()),
+            ..unpeppered.clone()
+        };
+
+        let hash = peppered.hash("password").unwrap();
+        assert_eq!(
+            peppered.verify(&hash, "password").unwrap(),
+            Verification::Current
+        );
+        assert!(matches!(
+            unpeppered.verify(&hash, "password"),
+            Err(MovieramaError::Unauthorized)
+        ));
+
+        // Hashes from before the pepper was introduced still verify, flagged for a rehash
+        let legacy = unpeppered.hash("password").unwrap();
+        assert_eq!(
+            peppered.verify(&legacy, "password").unwrap(),
+            Verification::Outdated
+        );
+        assert!(matches!(
+            peppered.verify(&legacy, "wrongpass"),
+            Err(MovieramaError::Unauthorized)
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_invite_only_registration(pool: PgPool) {
+        let host = create_user(
+            &pool,
+            &RegisterUser {
+                username: "host".into(),
+                email: "host@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            RegistrationPolicy::Open,
+        )
+        .await
+        .unwrap();
+        let invite = invite_service::create_invite(
+            &pool,
+            host.id,
+            Default::default(),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut guest = RegisterUser {
+            username: "guest".into(),
+            email: "guest@mail.com".into(),
+            password: "password".into(),
+            invite_code: None,
         };
+        let without_code = create_user(&pool, &guest, RegistrationPolicy::InviteOnly).await;
+        assert!(matches!(without_code, Err(MovieramaError::BadRequest(_))));
 
-        register_user(&pool, &data).await.unwrap();
+        guest.invite_code = Some(invite.code);
+        create_user(&pool, &guest, RegistrationPolicy::InviteOnly)
+            .await
+            .unwrap();
 
-        let duplicate = register_user(&pool, &data).await;
+        // The invite was for a single use
+        guest.username = "guest2".into();
+        guest.email = "guest2@mail.com".into();
+        let reused = create_user(&pool, &guest, RegistrationPolicy::InviteOnly).await;
+        assert!(matches!(reused, Err(MovieramaError::BadRequest(_))));
 
-        assert!(matches!(duplicate, Err(MovieramaError::DatabaseError(_))));
+        let closed = create_user(&pool, &guest, RegistrationPolicy::Closed).await;
+        assert!(matches!(closed, Err(MovieramaError::Forbidden)));
     }
 }
@@ -0,0 +1,524 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{Comment, EditComment, NewComment},
+    pagination::{Pageable, Sort},
+    services::audit_service::{self, AuditContext, NewAuditEvent},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+
+const MAX_COMMENT_LENGTH: usize = 10_000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentSort {
+    #[default]
+    Newest,
+    Oldest,
+    /// Most replies first
+    Top,
+}
+
+impl CommentSort {
+    fn to_sql(self) -> &'static str {
+        match self {
+            CommentSort::Newest => "c.created_at DESC, c.id DESC",
+            CommentSort::Oldest => "c.created_at ASC, c.id ASC",
+            CommentSort::Top => "reply_count DESC, c.created_at DESC, c.id DESC",
+        }
+    }
+
+    /// The same order in the form pages report it
+    pub fn to_sort(self) -> Sort {
+        Sort::from_query(match self {
+            CommentSort::Newest => "createdAt,desc",
+            CommentSort::Oldest => "createdAt,asc",
+            CommentSort::Top => "replyCount,desc",
+        })
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct CommentRow {
+    id: i32,
+    movie_id: i32,
+    parent_id: Option<i32>,
+    username: Option<String>,
+    body: String,
+    created_at: DateTime<Utc>,
+    edited_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    reply_count: i64,
+}
+
+impl From<CommentRow> for Comment {
+    fn from(r: CommentRow) -> Self {
+        let deleted = r.deleted_at.is_some();
+        Comment {
+            id: r.id,
+            movie_id: r.movie_id,
+            parent_id: r.parent_id,
+            username: r.username.filter(|_| !deleted),
+            body: Some(r.body).filter(|_| !deleted),
+            deleted,
+            created_at: r.created_at,
+            edited_at: r.edited_at,
+            reply_count: r.reply_count as u64,
+        }
+    }
+}
+
+/// Lists the comments on a movie, or the replies to one of them when
+/// `parent_id` is given. Deleted comments only show up when something
+/// replied to them.
+pub async fn list_comments(
+    pool: &PgPool,
+    movie_id: i32,
+    parent_id: Option<i32>,
+    sort: CommentSort,
+    pageable: &Pageable,
+) -> Result<(Vec<Comment>, u64), MovieramaError> {
+    require_movie(pool, movie_id).await?;
+
+    let total_elements = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM comments c
+        WHERE c.movie_id = $1
+        AND c.parent_id IS NOT DISTINCT FROM $2
+        AND (c.deleted_at IS NULL OR EXISTS (SELECT 1 FROM comments r WHERE r.parent_id = c.id))
+        "#,
+        movie_id,
+        parent_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let query = format!(
+        r#"
+        SELECT
+            c.id,
+            c.movie_id,
+            c.parent_id,
+            u.username,
+            c.body,
+            c.created_at,
+            c.edited_at,
+            c.deleted_at,
+            (SELECT COUNT(*) FROM comments r WHERE r.parent_id = c.id) AS reply_count
+        FROM comments c
+        LEFT JOIN users u ON u.id = c.user_id
+        WHERE c.movie_id = $1
+        AND c.parent_id IS NOT DISTINCT FROM $2
+        AND (c.deleted_at IS NULL OR EXISTS (SELECT 1 FROM comments r WHERE r.parent_id = c.id))
+        ORDER BY {}
+        LIMIT $3 OFFSET $4
+        "#,
+        sort.to_sql()
+    );
+
+    let comments = sqlx::query_as::<_, CommentRow>(&query)
+        .bind(movie_id)
+        .bind(parent_id)
+        .bind(pageable.page_size as i64)
+        .bind(pageable.offset as i64)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(Comment::from)
+        .collect();
+
+    Ok((comments, total_elements as u64))
+}
+
+pub async fn create_comment(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    data: &NewComment,
+) -> Result<Comment, MovieramaError> {
+    let body = validate_body(&data.body)?;
+    require_movie(pool, movie_id).await?;
+
+    if let Some(parent_id) = data.parent_id {
+        let parent = sqlx::query!(
+            "SELECT deleted_at FROM comments WHERE id = $1 AND movie_id = $2",
+            parent_id,
+            movie_id,
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| {
+            MovieramaError::BadRequest("The parent comment is not on this movie".to_owned())
+        })?;
+
+        if parent.deleted_at.is_some() {
+            return Err(MovieramaError::BadRequest(
+                "Cannot reply to a deleted comment".to_owned(),
+            ));
+        }
+    }
+
+    let comment_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO comments (movie_id, parent_id, user_id, body)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+        movie_id,
+        data.parent_id,
+        user_id,
+        body,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    get_comment(pool, comment_id).await
+}
+
+/// Replaces the text of a comment. Only its author can edit it.
+pub async fn edit_comment(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    comment_id: i32,
+    data: &EditComment,
+    ctx: &AuditContext,
+) -> Result<Comment, MovieramaError> {
+    let body = validate_body(&data.body)?;
+
+    let mut tx = pool.begin().await?;
+
+    let previous = lock_own_comment(&mut tx, user_id, movie_id, comment_id).await?;
+
+    sqlx::query!(
+        "UPDATE comments SET body = $2, edited_at = NOW() WHERE id = $1",
+        comment_id,
+        body,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "comment.edit",
+            target_type: "comment",
+            target_id: Some(comment_id.to_string()),
+            before: Some(json!({ "body": previous })),
+            after: Some(json!({ "body": body })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    get_comment(pool, comment_id).await
+}
+
+/// Marks a comment as deleted. Its replies stay where they are, under a
+/// placeholder. Only its author can delete it.
+pub async fn delete_comment(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    comment_id: i32,
+    ctx: &AuditContext,
+) -> Result<(), MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let previous = lock_own_comment(&mut tx, user_id, movie_id, comment_id).await?;
+
+    sqlx::query!(
+        "UPDATE comments SET deleted_at = NOW() WHERE id = $1",
+        comment_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "comment.delete",
+            target_type: "comment",
+            target_id: Some(comment_id.to_string()),
+            before: Some(json!({ "body": previous })),
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn get_comment(pool: &PgPool, comment_id: i32) -> Result<Comment, MovieramaError> {
+    let row = sqlx::query_as!(
+        CommentRow,
+        r#"
+        SELECT
+            c.id,
+            c.movie_id,
+            c.parent_id,
+            u.username AS "username?",
+            c.body,
+            c.created_at,
+            c.edited_at,
+            c.deleted_at,
+            (SELECT COUNT(*) FROM comments r WHERE r.parent_id = c.id) AS "reply_count!"
+        FROM comments c
+        LEFT JOIN users u ON u.id = c.user_id
+        WHERE c.id = $1
+        "#,
+        comment_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    Ok(row.into())
+}
+
+/// Locks a live comment on a live movie for its author, returning its text
+async fn lock_own_comment(
+    conn: &mut sqlx::PgConnection,
+    user_id: i32,
+    movie_id: i32,
+    comment_id: i32,
+) -> Result<String, MovieramaError> {
+    let comment = sqlx::query!(
+        r#"
+        SELECT c.user_id, c.body
+        FROM comments c
+        JOIN movies m ON m.id = c.movie_id
+        WHERE c.id = $1 AND c.movie_id = $2
+        AND c.deleted_at IS NULL AND m.deleted_at IS NULL
+        FOR UPDATE OF c
+        "#,
+        comment_id,
+        movie_id,
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    if comment.user_id != Some(user_id) {
+        return Err(MovieramaError::Forbidden);
+    }
+
+    Ok(comment.body)
+}
+
+async fn require_movie(pool: &PgPool, movie_id: i32) -> Result<(), MovieramaError> {
+    sqlx::query_scalar!(
+        "SELECT id FROM movies WHERE id = $1 AND deleted_at IS NULL",
+        movie_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    Ok(())
+}
+
+fn validate_body(body: &str) -> Result<&str, MovieramaError> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(MovieramaError::BadRequest(
+            "Comments must not be empty".to_owned(),
+        ));
+    }
+    if body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(MovieramaError::BadRequest(format!(
+            "Comments must be at most {} characters",
+            MAX_COMMENT_LENGTH
+        )));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::NewMovie, services::movie_service};
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn create_movie(pool: &PgPool, user_id: i32) -> i32 {
+        let movie = NewMovie {
+            title: "Rashomon".into(),
+            ..Default::default()
+        };
+        movie_service::create_movie(pool, user_id, movie, true)
+            .await
+            .unwrap()
+            .id
+    }
+
+    fn comment(body: &str, parent_id: Option<i32>) -> NewComment {
+        NewComment {
+            body: body.into(),
+            parent_id,
+        }
+    }
+
+    fn first_page(sort: CommentSort) -> Pageable {
+        Pageable::new(0, 10, sort.to_sort())
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_threads_and_sorting(pool: PgPool) {
+        let uid = create_user(&pool, "critic").await;
+        let movie_id = create_movie(&pool, uid).await;
+
+        let first = create_comment(&pool, uid, movie_id, &comment("First", None))
+            .await
+            .unwrap();
+        let second = create_comment(&pool, uid, movie_id, &comment(" Second ", None))
+            .await
+            .unwrap();
+        assert_eq!(second.body.as_deref(), Some("Second"));
+
+        let reply = create_comment(&pool, uid, movie_id, &comment("Reply", Some(first.id)))
+            .await
+            .unwrap();
+        assert_eq!(reply.parent_id, Some(first.id));
+
+        let (newest, total) = list_comments(
+            &pool,
+            movie_id,
+            None,
+            CommentSort::Newest,
+            &first_page(CommentSort::Newest),
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(newest[0].id, second.id);
+
+        let (top, _) = list_comments(
+            &pool,
+            movie_id,
+            None,
+            CommentSort::Top,
+            &first_page(CommentSort::Top),
+        )
+        .await
+        .unwrap();
+        assert_eq!(top[0].id, first.id);
+        assert_eq!(top[0].reply_count, 1);
+
+        let (replies, total) = list_comments(
+            &pool,
+            movie_id,
+            Some(first.id),
+            CommentSort::Oldest,
+            &first_page(CommentSort::Oldest),
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(replies[0].id, reply.id);
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.comment_count, 3);
+
+        let other_movie = create_movie(&pool, uid).await;
+        let result =
+            create_comment(&pool, uid, other_movie, &comment("Lost", Some(first.id))).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let result = create_comment(&pool, uid, movie_id, &comment("   ", None)).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_only_author_edits_and_deletes(pool: PgPool) {
+        let author = create_user(&pool, "author").await;
+        let other = create_user(&pool, "other").await;
+        let movie_id = create_movie(&pool, author).await;
+        let ctx = AuditContext::default();
+
+        let parent = create_comment(&pool, author, movie_id, &comment("Tpyo", None))
+            .await
+            .unwrap();
+        let lonely = create_comment(&pool, author, movie_id, &comment("Alone", None))
+            .await
+            .unwrap();
+        create_comment(&pool, other, movie_id, &comment("Agreed", Some(parent.id)))
+            .await
+            .unwrap();
+
+        let edit = EditComment {
+            body: "Typo".into(),
+        };
+        let result = edit_comment(&pool, other, movie_id, parent.id, &edit, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let edited = edit_comment(&pool, author, movie_id, parent.id, &edit, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(edited.body.as_deref(), Some("Typo"));
+        assert!(edited.edited_at.is_some());
+
+        let result = delete_comment(&pool, other, movie_id, parent.id, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        delete_comment(&pool, author, movie_id, parent.id, &ctx)
+            .await
+            .unwrap();
+        delete_comment(&pool, author, movie_id, lonely.id, &ctx)
+            .await
+            .unwrap();
+
+        // The deleted parent stays as a placeholder for its reply, the
+        // comment without replies disappears
+        let (comments, total) = list_comments(
+            &pool,
+            movie_id,
+            None,
+            CommentSort::Newest,
+            &first_page(CommentSort::Newest),
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(comments[0].id, parent.id);
+        assert!(comments[0].deleted);
+        assert_eq!(comments[0].body, None);
+        assert_eq!(comments[0].reply_count, 1);
+
+        let result = edit_comment(&pool, author, movie_id, parent.id, &edit, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::NotFound)));
+
+        let result = create_comment(&pool, other, movie_id, &comment("Hm", Some(parent.id))).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.comment_count, 1);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{Invite, NewInvite, RegistrationPolicy, Role},
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        user_service,
+    },
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+
+const REGISTRATION_POLICY: &str = "REGISTRATION_POLICY";
+/// `users` (default) lets anyone with an account invite others, `admins`
+/// restricts it to administrators
+const INVITE_CREATORS: &str = "INVITE_CREATORS";
+
+const DEFAULT_MAX_USES: u32 = 1;
+const MAX_MAX_USES: u32 = 100;
+const DEFAULT_EXPIRY_DAYS: u32 = 7;
+const MAX_EXPIRY_DAYS: u32 = 90;
+
+/// Unambiguous characters, so codes can be read out or typed by hand
+const INVITE_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub fn registration_policy() -> Result<RegistrationPolicy, MovieramaError> {
+    match std::env::var(REGISTRATION_POLICY) {
+        Ok(policy) => policy.parse(),
+        Err(_) => Ok(RegistrationPolicy::Open),
+    }
+}
+
+pub async fn create_invite(
+    pool: &PgPool,
+    user_id: i32,
+    data: NewInvite,
+    ctx: &AuditContext,
+) -> Result<Invite, MovieramaError> {
+    if std::env::var(INVITE_CREATORS).as_deref() == Ok("admins") {
+        user_service::require_role(pool, user_id, Role::Admin).await?;
+    }
+
+    let max_uses = data.max_uses.unwrap_or(DEFAULT_MAX_USES);
+    if max_uses == 0 || max_uses > MAX_MAX_USES {
+        return Err(MovieramaError::BadRequest(format!(
+            "maxUses must be between 1 and {}",
+            MAX_MAX_USES
+        )));
+    }
+
+    let expires_in_days = data.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if expires_in_days == 0 || expires_in_days > MAX_EXPIRY_DAYS {
+        return Err(MovieramaError::BadRequest(format!(
+            "expiresInDays must be between 1 and {}",
+            MAX_EXPIRY_DAYS
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let invite = sqlx::query_as!(
+        Invite,
+        r#"
+        INSERT INTO invites (code, created_by, max_uses, expires_at)
+        VALUES ($1, $2, $3, NOW() + make_interval(days => $4))
+        RETURNING id, code, max_uses, uses, created_at, expires_at
+        "#,
+        generate_invite_code(),
+        user_id,
+        max_uses as i32,
+        expires_in_days as i32,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // The code lets anyone register, so it stays out of the log
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "invite.create",
+            target_type: "invite",
+            target_id: Some(invite.id.to_string()),
+            before: None,
+            after: Some(json!({
+                "maxUses": invite.max_uses,
+                "expiresAt": invite.expires_at,
+            })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(invite)
+}
+
+pub async fn list_invites(pool: &PgPool, user_id: i32) -> Result<Vec<Invite>, MovieramaError> {
+    let invites = sqlx::query_as!(
+        Invite,
+        r#"
+        SELECT id, code, max_uses, uses, created_at, expires_at
+        FROM invites
+        WHERE created_by = $1 AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(invites)
+}
+
+pub async fn revoke_invite(
+    pool: &PgPool,
+    user_id: i32,
+    invite_id: i32,
+    ctx: &AuditContext,
+) -> Result<bool, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE invites
+        SET revoked_at = NOW()
+        WHERE id = $1 AND created_by = $2 AND revoked_at IS NULL
+        "#,
+        invite_id,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "invite.revoke",
+            target_type: "invite",
+            target_id: Some(invite_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Uses up one registration of the invite. Checking and incrementing happen
+/// in a single statement, so concurrent sign-ups cannot exceed the limit, and
+/// the use is given back if the caller's transaction rolls back.
+pub async fn consume_invite(conn: &mut PgConnection, code: &str) -> Result<(), MovieramaError> {
+    let consumed = sqlx::query!(
+        r#"
+        UPDATE invites
+        SET uses = uses + 1
+        WHERE code = $1
+        AND revoked_at IS NULL
+        AND expires_at > NOW()
+        AND uses < max_uses
+        "#,
+        code.trim().to_uppercase(),
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(MovieramaError::BadRequest(
+            "Invalid or expired invite code".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn generate_invite_code() -> String {
+    (0..12)
+        .map(|_| {
+            let idx = OsRng.next_u32() as usize % INVITE_CODE_ALPHABET.len();
+            INVITE_CODE_ALPHABET[idx] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_invite_usage_limit(pool: PgPool) {
+        let inviter = create_user(&pool, "inviter").await;
+        let invite = create_invite(
+            &pool,
+            inviter,
+            NewInvite {
+                max_uses: Some(1),
+                ..Default::default()
+            },
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        consume_invite(&mut tx, &invite.code.to_lowercase())
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        let result = consume_invite(&mut tx, &invite.code).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_rolled_back_use_is_returned(pool: PgPool) {
+        let inviter = create_user(&pool, "host").await;
+        let invite = create_invite(
+            &pool,
+            inviter,
+            NewInvite::default(),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        // E.g. the registration failed because the username was taken
+        let mut tx = pool.begin().await.unwrap();
+        consume_invite(&mut tx, &invite.code).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let invites = list_invites(&pool, inviter).await.unwrap();
+        assert_eq!(invites[0].uses, 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_revoked_and_expired_invites_are_rejected(pool: PgPool) {
+        let inviter = create_user(&pool, "revoker").await;
+
+        let revoked = create_invite(
+            &pool,
+            inviter,
+            NewInvite::default(),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            revoke_invite(&pool, inviter, revoked.id, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+
+        let expired = create_invite(
+            &pool,
+            inviter,
+            NewInvite::default(),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+        sqlx::query!(
+            "UPDATE invites SET expires_at = NOW() - INTERVAL '1 minute' WHERE id = $1",
+            expired.id,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        for code in [revoked.code, expired.code] {
+            let mut tx = pool.begin().await.unwrap();
+            let result = consume_invite(&mut tx, &code).await;
+            assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+        }
+    }
+}
@@ -0,0 +1,662 @@
+use crate::{
+    auth::{self, ClientInfo, MFA_AUDIENCE, MfaClaims},
+    exceptions::MovieramaError,
+    models::{AuthResponse, RecoveryCodes, TotpEnrollment, User, VerifyMfa},
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        auth_service,
+    },
+};
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use rand::distributions::{Distribution, Uniform};
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const TOTP_ISSUER: &str = "Movierama";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+// Number of steps accepted on either side of the current one (clock drift)
+const TOTP_SKEW_STEPS: u64 = 1;
+
+const MFA_TOKEN_MINUTES: i64 = 5;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+struct TotpState {
+    username: String,
+    totp_secret: Option<String>,
+    totp_enabled: bool,
+}
+
+pub async fn is_enabled(pool: &PgPool, user_id: i32) -> Result<bool, MovieramaError> {
+    let enabled = sqlx::query_scalar!("SELECT totp_enabled FROM users WHERE id = $1", user_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(enabled.unwrap_or(false))
+}
+
+/// Generates a new secret for the user. It only becomes active once a code
+/// generated from it is confirmed.
+pub async fn enroll(pool: &PgPool, user_id: i32) -> Result<TotpEnrollment, MovieramaError> {
+    let state = get_totp_state(pool, user_id).await?;
+
+    if state.totp_enabled {
+        return Err(MovieramaError::BadRequest(
+            "Two-factor authentication is already enabled".to_owned(),
+        ));
+    }
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = build_totp(&secret, &state.username)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_secret = $1, totp_last_step = NULL
+        WHERE id = $2
+        "#,
+        secret,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(TotpEnrollment {
+        otpauth_uri: totp.get_url(),
+        secret,
+    })
+}
+
+/// Activates two-factor authentication and returns a fresh set of recovery
+/// codes. The plain codes are only ever shown here.
+pub async fn confirm(
+    pool: &PgPool,
+    user_id: i32,
+    code: &str,
+    ctx: &AuditContext,
+) -> Result<RecoveryCodes, MovieramaError> {
+    let state = get_totp_state(pool, user_id).await?;
+
+    if state.totp_enabled {
+        return Err(MovieramaError::BadRequest(
+            "Two-factor authentication is already enabled".to_owned(),
+        ));
+    }
+
+    let Some(secret) = &state.totp_secret else {
+        return Err(MovieramaError::BadRequest(
+            "Two-factor enrollment has not been started".to_owned(),
+        ));
+    };
+
+    let step = match matching_step(&build_totp(secret, &state.username)?, code) {
+        Some(step) => step,
+        None => return Err(MovieramaError::Unauthorized),
+    };
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let codes = recovery_codes.clone();
+    let code_hashes = tokio::task::spawn_blocking(move || {
+        codes
+            .iter()
+            .map(|code| hash_recovery_code(code))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))??;
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_enabled = TRUE, totp_last_step = $1
+        WHERE id = $2
+        "#,
+        step,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for code_hash in &code_hashes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            user_id,
+            code_hash,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "mfa.enable",
+            target_type: "user",
+            target_id: Some(user_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Turns two-factor authentication off. Requires a valid code so a stolen
+/// access token alone cannot remove the second factor.
+pub async fn disable(
+    pool: &PgPool,
+    user_id: i32,
+    code: &str,
+    ctx: &AuditContext,
+) -> Result<(), MovieramaError> {
+    let state = get_totp_state(pool, user_id).await?;
+
+    if !state.totp_enabled {
+        return Err(MovieramaError::BadRequest(
+            "Two-factor authentication is not enabled".to_owned(),
+        ));
+    }
+
+    if !verify_second_factor(pool, user_id, &state, code).await? {
+        return Err(MovieramaError::Unauthorized);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_enabled = FALSE, totp_secret = NULL, totp_last_step = NULL
+        WHERE id = $1
+        "#,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "mfa.disable",
+            target_type: "user",
+            target_id: Some(user_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Second login step: exchanges an MFA pending token and a TOTP or recovery
+/// code for the access token.
+pub async fn verify_login(
+    pool: &PgPool,
+    data: &VerifyMfa,
+    client: &ClientInfo,
+) -> Result<AuthResponse, MovieramaError> {
+    let jwt_secret = auth::jwt_secret()?;
+
+    let mut validation = Validation::default();
+    validation.set_audience(&[MFA_AUDIENCE]);
+
+    let claims = decode::<MfaClaims>(
+        &data.mfa_token,
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|_| MovieramaError::Unauthorized)?
+    .claims;
+
+    let state = get_totp_state(pool, claims.user_id).await?;
+
+    if !state.totp_enabled
+        || !verify_second_factor(pool, claims.user_id, &state, &data.code).await?
+    {
+        return Err(MovieramaError::Unauthorized);
+    }
+
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, username, email, password
+        FROM users
+        WHERE id = $1
+        "#,
+        claims.user_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let token = auth_service::create_token(pool, &user, client).await?;
+
+    Ok(AuthResponse { token })
+}
+
+pub fn create_mfa_token(user: &User) -> Result<String, MovieramaError> {
+    let expiration = chrono::Utc::now()
+        .checked_add_signed(chrono::Duration::minutes(MFA_TOKEN_MINUTES))
+        .unwrap()
+        .timestamp() as usize;
+
+    let claims = MfaClaims {
+        sub: user.username.clone(),
+        user_id: user.id,
+        aud: MFA_AUDIENCE.to_owned(),
+        exp: expiration,
+    };
+
+    let jwt_secret = auth::jwt_secret()?;
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )
+    .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))
+}
+
+async fn get_totp_state(pool: &PgPool, user_id: i32) -> Result<TotpState, MovieramaError> {
+    sqlx::query_as!(
+        TotpState,
+        r#"
+        SELECT username, totp_secret, totp_enabled
+        FROM users
+        WHERE id = $1
+        "#,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)
+}
+
+/// Checks a second factor code of a signed-in user, e.g. to confirm a
+/// sensitive change.
+pub async fn verify_code(pool: &PgPool, user_id: i32, code: &str) -> Result<bool, MovieramaError> {
+    let state = get_totp_state(pool, user_id).await?;
+    if !state.totp_enabled {
+        return Ok(false);
+    }
+
+    verify_second_factor(pool, user_id, &state, code).await
+}
+
+/// Accepts either a TOTP code or an unused recovery code. Both are single
+/// use: the TOTP step is recorded and the recovery code is marked as used.
+async fn verify_second_factor(
+    pool: &PgPool,
+    user_id: i32,
+    state: &TotpState,
+    code: &str,
+) -> Result<bool, MovieramaError> {
+    let code = code.trim();
+
+    if let Some(secret) = &state.totp_secret
+        && let Some(step) = matching_step(&build_totp(secret, &state.username)?, code)
+    {
+        // Only move forward, so a code cannot be replayed within its window
+        let rows_affected = sqlx::query!(
+            r#"
+            UPDATE users
+            SET totp_last_step = $1
+            WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)
+            "#,
+            step,
+            user_id,
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+        return Ok(rows_affected > 0);
+    }
+
+    // Checking recovery codes is slow, wrong TOTP guesses should not get to it
+    if !is_recovery_code_format(code) {
+        return Ok(false);
+    }
+
+    consume_recovery_code(pool, user_id, code).await
+}
+
+async fn consume_recovery_code(
+    pool: &PgPool,
+    user_id: i32,
+    code: &str,
+) -> Result<bool, MovieramaError> {
+    let code = code.to_lowercase();
+
+    let candidates = sqlx::query!(
+        r#"
+        SELECT id, code_hash
+        FROM recovery_codes
+        WHERE user_id = $1 AND used_at IS NULL
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // Up to one Argon2 verification per unused code, off the async workers
+    let matched = tokio::task::spawn_blocking(move || -> Result<Option<i32>, MovieramaError> {
+        for candidate in candidates {
+            let parsed_hash = PasswordHash::new(&candidate.code_hash)
+                .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+
+            if Argon2::default()
+                .verify_password(code.as_bytes(), &parsed_hash)
+                .is_ok()
+            {
+                return Ok(Some(candidate.id));
+            }
+        }
+        Ok(None)
+    })
+    .await
+    .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))??;
+
+    let Some(id) = matched else {
+        return Ok(false);
+    };
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE recovery_codes
+        SET used_at = NOW()
+        WHERE id = $1 AND used_at IS NULL
+        "#,
+        id,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+fn build_totp(secret: &str, username: &str) -> Result<TOTP, MovieramaError> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+
+    // Skew is handled by `matching_step`, which needs to know the exact step
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_owned()),
+        username.to_owned(),
+    )
+    .map_err(|e| MovieramaError::BadRequest(e.to_string()))
+}
+
+/// Returns the time step the code was generated for, if it is within the
+/// accepted window around the current time.
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let current_step = chrono::Utc::now().timestamp() as u64 / TOTP_STEP;
+
+    (current_step - TOTP_SKEW_STEPS..=current_step + TOTP_SKEW_STEPS)
+        .find(|step| totp.check(code, step * TOTP_STEP))
+        .map(|step| step as i64)
+}
+
+fn generate_recovery_code() -> String {
+    // Uniform rather than a byte modulo the length, which would favour the
+    // first letters
+    let letters = Uniform::from(0..RECOVERY_CODE_ALPHABET.len());
+    let code: String = (0..10)
+        .map(|_| RECOVERY_CODE_ALPHABET[letters.sample(&mut OsRng)] as char)
+        .collect();
+
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Whether the code is shaped like one from [`generate_recovery_code`]: two
+/// groups of five letters or digits from its alphabet, joined by a dash
+fn is_recovery_code_format(code: &str) -> bool {
+    let code = code.as_bytes();
+    code.len() == 11
+        && code.iter().enumerate().all(|(i, b)| {
+            if i == 5 {
+                *b == b'-'
+            } else {
+                RECOVERY_CODE_ALPHABET.contains(&b.to_ascii_lowercase())
+            }
+        })
+}
+
+fn hash_recovery_code(code: &str) -> Result<String, MovieramaError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(code.as_bytes(), &salt)
+        .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Claims,
+        models::{LoginResponse, LoginUser, RegisterUser},
+    };
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let reg = RegisterUser {
+            username: username.into(),
+            email: format!("{}@mail.com", username),
+            password: "password".into(),
+            invite_code: None,
+        };
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        jsonwebtoken::decode::<Claims>(
+            &auth.token,
+            &DecodingKey::from_secret("test-secret".as_bytes()),
+            &Validation::default(),
+        )
+        .unwrap()
+        .claims
+        .user_id
+    }
+
+    fn code_at_step_offset(enrollment: &TotpEnrollment, username: &str, offset: i64) -> String {
+        let totp = build_totp(&enrollment.secret, username).unwrap();
+        let time = chrono::Utc::now().timestamp() + offset * TOTP_STEP as i64;
+        totp.generate(time as u64)
+    }
+
+    async fn login(pool: &PgPool, username: &str) -> LoginResponse {
+        auth_service::login_user(
+            pool,
+            &LoginUser {
+                username: username.into(),
+                password: "password".into(),
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn enable_mfa(
+        pool: &PgPool,
+        user_id: i32,
+        username: &str,
+    ) -> (TotpEnrollment, Vec<String>) {
+        let enrollment = enroll(pool, user_id).await.unwrap();
+        let code = code_at_step_offset(&enrollment, username, -1);
+        let codes = confirm(pool, user_id, &code, &AuditContext::default())
+            .await
+            .unwrap();
+        (enrollment, codes.recovery_codes)
+    }
+
+    fn mfa_token(resp: LoginResponse) -> String {
+        match resp {
+            LoginResponse::MfaRequired(challenge) => challenge.mfa_token,
+            LoginResponse::Authenticated(_) => panic!("expected an MFA challenge"),
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_enroll_returns_otpauth_uri(pool: PgPool) {
+        let uid = create_user(&pool, "enroller").await;
+
+        let enrollment = enroll(&pool, uid).await.unwrap();
+
+        assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/"));
+        assert!(enrollment.otpauth_uri.contains(&enrollment.secret));
+
+        // Not active until confirmed
+        assert!(!is_enabled(&pool, uid).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_confirm_with_wrong_code(pool: PgPool) {
+        let uid = create_user(&pool, "wrongconfirm").await;
+        enroll(&pool, uid).await.unwrap();
+
+        let result = confirm(&pool, uid, "000000", &AuditContext::default()).await;
+
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+        assert!(!is_enabled(&pool, uid).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_two_step_login(pool: PgPool) {
+        let uid = create_user(&pool, "twostep").await;
+        let (enrollment, recovery_codes) = enable_mfa(&pool, uid, "twostep").await;
+
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+
+        let token = mfa_token(login(&pool, "twostep").await);
+
+        // The pending token is not an access token
+        let as_access_token = jsonwebtoken::decode::<Claims>(
+            &token,
+            &DecodingKey::from_secret("test-secret".as_bytes()),
+            &Validation::default(),
+        );
+        assert!(as_access_token.is_err());
+
+        let code = code_at_step_offset(&enrollment, "twostep", 0);
+        let auth = verify_login(
+            &pool,
+            &VerifyMfa {
+                mfa_token: token.clone(),
+                code: code.clone(),
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+        assert!(!auth.token.is_empty());
+
+        // The same code cannot be replayed
+        let replay = verify_login(
+            &pool,
+            &VerifyMfa {
+                mfa_token: token,
+                code,
+            },
+            &ClientInfo::default(),
+        )
+        .await;
+        assert!(matches!(replay, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_generate_recovery_code() {
+        let code = generate_recovery_code();
+        let (first, second) = code.split_once('-').unwrap();
+        assert_eq!((first.len(), second.len()), (5, 5));
+        assert!(
+            code.bytes()
+                .filter(|&b| b != b'-')
+                .all(|b| RECOVERY_CODE_ALPHABET.contains(&b))
+        );
+
+        assert!(is_recovery_code_format(&code));
+        assert!(is_recovery_code_format(&code.to_uppercase()));
+        assert!(!is_recovery_code_format("123456"));
+        assert!(!is_recovery_code_format("abcde_fghjk"));
+        assert!(!is_recovery_code_format("abcde-fghj1"));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_recovery_code_is_single_use(pool: PgPool) {
+        let uid = create_user(&pool, "recovery").await;
+        let (_, recovery_codes) = enable_mfa(&pool, uid, "recovery").await;
+
+        let token = mfa_token(login(&pool, "recovery").await);
+        let data = VerifyMfa {
+            mfa_token: token,
+            code: recovery_codes[0].to_uppercase(),
+        };
+
+        assert!(
+            verify_login(&pool, &data, &ClientInfo::default())
+                .await
+                .is_ok()
+        );
+
+        let reuse = verify_login(&pool, &data, &ClientInfo::default()).await;
+        assert!(matches!(reuse, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_disable(pool: PgPool) {
+        let uid = create_user(&pool, "disabler").await;
+        let (_, recovery_codes) = enable_mfa(&pool, uid, "disabler").await;
+
+        assert!(matches!(
+            disable(&pool, uid, "123456", &AuditContext::default()).await,
+            Err(MovieramaError::Unauthorized)
+        ));
+
+        disable(&pool, uid, &recovery_codes[1], &AuditContext::default())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            login(&pool, "disabler").await,
+            LoginResponse::Authenticated(_)
+        ));
+    }
+}
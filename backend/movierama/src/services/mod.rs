@@ -1,3 +1,16 @@
+pub mod access_token_service;
+pub mod account_service;
+pub mod audit_service;
 pub mod auth_service;
+pub mod comment_service;
+pub mod invite_service;
+pub mod mfa_service;
 pub mod movie_service;
+pub mod oidc_service;
+pub mod poster_service;
+pub mod reaction_service;
+pub mod review_service;
+pub mod revision_service;
+pub mod session_service;
+pub mod user_service;
 pub mod vote_service;
@@ -1,10 +1,37 @@
 use crate::{
     exceptions::MovieramaError,
-    models::{Movie, NewMovie},
+    merge_patch,
+    metadata::MetadataProvider,
+    models::{DuplicateCandidate, Movie, NewMovie, Role, TrashedMovie},
     pagination::Pageable,
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        poster_service, review_service, revision_service, user_service,
+    },
+    storage::BlobStore,
 };
-use chrono::Utc;
-use sqlx::{FromRow, PgPool};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::{FromRow, PgConnection, PgPool, types::Json};
+use std::collections::BTreeMap;
+
+const MOVIE_TRASH_RETENTION_DAYS: &str = "MOVIE_TRASH_RETENTION_DAYS";
+const DEFAULT_RETENTION_DAYS: i32 = 30;
+
+const MOVIE_DUPLICATE_SIMILARITY: &str = "MOVIE_DUPLICATE_SIMILARITY";
+const DEFAULT_DUPLICATE_SIMILARITY: f32 = 0.6;
+const MAX_DUPLICATE_CANDIDATES: i64 = 5;
+
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 10_000;
+
+pub fn trash_retention_days() -> i32 {
+    std::env::var(MOVIE_TRASH_RETENTION_DAYS)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
 
 #[derive(Debug, FromRow)]
 pub struct MovieRow {
@@ -13,21 +40,122 @@ pub struct MovieRow {
     pub description: Option<String>,
     pub date_added: chrono::DateTime<Utc>,
     pub username: String,
-    pub like_count: i64,
-    pub hate_count: i64,
+    pub like_count: i32,
+    pub hate_count: i32,
+    pub reactions: Json<BTreeMap<String, i64>>,
+    pub release_date: Option<NaiveDate>,
+    pub runtime_minutes: Option<i32>,
+    pub directors: Vec<String>,
+    pub cast_members: Vec<String>,
+    pub original_language: Option<String>,
+    pub imdb_id: Option<String>,
+    pub tmdb_id: Option<i32>,
+    pub poster_url: Option<String>,
+    pub poster_srcset: Option<String>,
+    pub comment_count: i64,
+    pub avg_rating: Option<f64>,
+    pub rating_count: i64,
+    pub rating_histogram: Vec<i64>,
+    pub version: i32,
+}
+
+impl From<MovieRow> for Movie {
+    fn from(r: MovieRow) -> Self {
+        Movie {
+            id: r.id,
+            title: r.title,
+            description: r.description,
+            date_added: r.date_added,
+            username: r.username,
+            like_count: r.like_count as u64,
+            hate_count: r.hate_count as u64,
+            reactions: r
+                .reactions
+                .0
+                .into_iter()
+                .map(|(code, n)| (code, n as u64))
+                .collect(),
+            release_date: r.release_date,
+            runtime_minutes: r.runtime_minutes,
+            directors: r.directors,
+            cast: r.cast_members,
+            original_language: r.original_language,
+            imdb_id: r.imdb_id,
+            tmdb_id: r.tmdb_id,
+            poster_url: r.poster_url,
+            poster_srcset: r.poster_srcset,
+            comment_count: r.comment_count as u64,
+            avg_rating: r.avg_rating,
+            rating_count: r.rating_count as u64,
+            rating_histogram: r.rating_histogram.into_iter().map(|n| n as u64).collect(),
+            version: r.version,
+        }
+    }
+}
+
+/// How alike two normalized titles must be, from 0 to 1, for one movie to
+/// be taken for a duplicate of the other
+pub fn duplicate_similarity() -> f32 {
+    std::env::var(MOVIE_DUPLICATE_SIMILARITY)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_DUPLICATE_SIMILARITY)
+}
+
+/// How far back trending listings count votes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TrendingWindow {
+    #[serde(rename = "24h")]
+    Day,
+    #[default]
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+impl TrendingWindow {
+    pub fn duration(&self) -> chrono::Duration {
+        match self {
+            TrendingWindow::Day => chrono::Duration::hours(24),
+            TrendingWindow::Week => chrono::Duration::days(7),
+            TrendingWindow::Month => chrono::Duration::days(30),
+        }
+    }
+}
+
+/// Narrows movie listings down, every bound is inclusive
+#[derive(Debug, Default, Deserialize)]
+pub struct MovieFilter {
+    #[serde(rename = "yearFrom")]
+    pub year_from: Option<i32>,
+    #[serde(rename = "yearTo")]
+    pub year_to: Option<i32>,
 }
 
 pub async fn list_all_movies(
     pool: &PgPool,
     pageable: &Pageable,
+    filter: &MovieFilter,
 ) -> Result<(Vec<Movie>, u64), MovieramaError> {
     let offset = pageable.offset as i64;
     let limit = pageable.page_size as i64;
     let order_clause = pageable.sort.to_sql("m.date_added");
+    let (prior_weight, prior_mean) = review_service::rating_prior(pool).await?;
 
-    let total_row = sqlx::query!("SELECT COUNT(*) as count FROM movies")
-        .fetch_one(pool)
-        .await?;
+    let total_row = sqlx::query!(
+        r#"
+        SELECT COUNT(*) as count
+        FROM movies m
+        WHERE m.deleted_at IS NULL
+        AND ($1::INTEGER IS NULL OR m.release_date >= make_date($1, 1, 1))
+        AND ($2::INTEGER IS NULL OR m.release_date < make_date($2 + 1, 1, 1))
+        "#,
+        filter.year_from,
+        filter.year_to,
+    )
+    .fetch_one(pool)
+    .await?;
     let total_elements = total_row.count.unwrap_or(0) as u64;
 
     let query = format!(
@@ -38,12 +166,31 @@ pub async fn list_all_movies(
             m.description,
             m.date_added,
             u.username,
-            COALESCE(SUM(CASE WHEN v.type = 'LIKE' THEN 1 ELSE 0 END), 0) AS like_count,
-            COALESCE(SUM(CASE WHEN v.type = 'HATE' THEN 1 ELSE 0 END), 0) AS hate_count
+            m.likes AS like_count,
+            m.hates AS hate_count,
+            movie_reactions(m.id) AS reactions,
+            m.release_date,
+            m.runtime_minutes,
+            m.directors,
+            m.cast_members,
+            m.original_language,
+            m.imdb_id,
+            m.tmdb_id,
+            m.poster_url,
+            m.poster_srcset,
+            (
+                SELECT COUNT(*) FROM comments c
+                WHERE c.movie_id = m.id AND c.deleted_at IS NULL
+            ) AS comment_count,
+            movie_rating(m.id, $5, $6) AS avg_rating,
+            (SELECT COUNT(*) FROM reviews r WHERE r.movie_id = m.id) AS rating_count,
+            movie_rating_histogram(m.id) AS rating_histogram,
+            m.version
         FROM movies m
         JOIN users u ON m.user_id = u.id
-        LEFT JOIN votes v ON v.movie_id = m.id
-        GROUP BY m.id, u.id
+        WHERE m.deleted_at IS NULL
+        AND ($3::INTEGER IS NULL OR m.release_date >= make_date($3, 1, 1))
+        AND ($4::INTEGER IS NULL OR m.release_date < make_date($4 + 1, 1, 1))
         ORDER BY {}
         LIMIT $1 OFFSET $2
         "#,
@@ -53,21 +200,14 @@ pub async fn list_all_movies(
     let rows = sqlx::query_as::<_, MovieRow>(&query)
         .bind(limit)
         .bind(offset)
+        .bind(filter.year_from)
+        .bind(filter.year_to)
+        .bind(prior_weight)
+        .bind(prior_mean)
         .fetch_all(pool)
         .await?;
 
-    let movies = rows
-        .into_iter()
-        .map(|r| Movie {
-            id: r.id,
-            title: r.title,
-            description: r.description,
-            date_added: r.date_added,
-            username: r.username,
-            like_count: r.like_count as u64,
-            hate_count: r.hate_count as u64,
-        })
-        .collect();
+    let movies = rows.into_iter().map(Movie::from).collect();
 
     Ok((movies, total_elements))
 }
@@ -76,19 +216,25 @@ pub async fn list_all_movies_by_username(
     pool: &PgPool,
     pageable: &Pageable,
     username: &str,
+    filter: &MovieFilter,
 ) -> Result<(Vec<Movie>, u64), MovieramaError> {
     let offset = pageable.offset as i64;
     let limit = pageable.page_size as i64;
     let order_clause = pageable.sort.to_sql("m.date_added");
+    let (prior_weight, prior_mean) = review_service::rating_prior(pool).await?;
 
     let total_row = sqlx::query!(
         r#"
         SELECT COUNT(*) as count
         FROM movies m
         JOIN users u ON m.user_id = u.id
-        WHERE u.username = $1
+        WHERE LOWER(u.username) = LOWER($1) AND m.deleted_at IS NULL
+        AND ($2::INTEGER IS NULL OR m.release_date >= make_date($2, 1, 1))
+        AND ($3::INTEGER IS NULL OR m.release_date < make_date($3 + 1, 1, 1))
         "#,
-        username
+        username,
+        filter.year_from,
+        filter.year_to,
     )
     .fetch_one(pool)
     .await?;
@@ -102,13 +248,31 @@ pub async fn list_all_movies_by_username(
             m.description,
             m.date_added,
             u.username,
-            COALESCE(SUM(CASE WHEN v.type = 'LIKE' THEN 1 ELSE 0 END), 0) AS like_count,
-            COALESCE(SUM(CASE WHEN v.type = 'HATE' THEN 1 ELSE 0 END), 0) AS hate_count
+            m.likes AS like_count,
+            m.hates AS hate_count,
+            movie_reactions(m.id) AS reactions,
+            m.release_date,
+            m.runtime_minutes,
+            m.directors,
+            m.cast_members,
+            m.original_language,
+            m.imdb_id,
+            m.tmdb_id,
+            m.poster_url,
+            m.poster_srcset,
+            (
+                SELECT COUNT(*) FROM comments c
+                WHERE c.movie_id = m.id AND c.deleted_at IS NULL
+            ) AS comment_count,
+            movie_rating(m.id, $6, $7) AS avg_rating,
+            (SELECT COUNT(*) FROM reviews r WHERE r.movie_id = m.id) AS rating_count,
+            movie_rating_histogram(m.id) AS rating_histogram,
+            m.version
         FROM movies m
         JOIN users u ON m.user_id = u.id
-        LEFT JOIN votes v ON v.movie_id = m.id
-        WHERE username = $3
-        GROUP BY m.id, u.id
+        WHERE LOWER(u.username) = LOWER($3) AND m.deleted_at IS NULL
+        AND ($4::INTEGER IS NULL OR m.release_date >= make_date($4, 1, 1))
+        AND ($5::INTEGER IS NULL OR m.release_date < make_date($5 + 1, 1, 1))
         ORDER BY {}
         LIMIT $1 OFFSET $2
         "#,
@@ -119,29 +283,94 @@ pub async fn list_all_movies_by_username(
         .bind(limit)
         .bind(offset)
         .bind(username)
+        .bind(filter.year_from)
+        .bind(filter.year_to)
+        .bind(prior_weight)
+        .bind(prior_mean)
         .fetch_all(pool)
         .await?;
 
-    let movies = rows
-        .into_iter()
-        .map(|r| Movie {
-            id: r.id,
-            title: r.title,
-            description: r.description,
-            date_added: r.date_added,
-            username: r.username,
-            like_count: r.like_count as u64,
-            hate_count: r.hate_count as u64,
-        })
-        .collect();
+    let movies = rows.into_iter().map(Movie::from).collect();
 
     Ok((movies, total_elements))
 }
 
+/// Lists the movies with more likes than hates among the votes cast or
+/// changed within the window, most net likes first
+pub async fn list_trending_movies(
+    pool: &PgPool,
+    window: TrendingWindow,
+    pageable: &Pageable,
+) -> Result<(Vec<Movie>, u64), MovieramaError> {
+    let since = Utc::now() - window.duration();
+    let (prior_weight, prior_mean) = review_service::rating_prior(pool).await?;
+
+    let total_elements = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM trending_votes($1) t
+        JOIN movies m ON m.id = t.movie_id
+        WHERE m.deleted_at IS NULL
+        "#,
+        since,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let rows = sqlx::query_as::<_, MovieRow>(
+        r#"
+        SELECT
+            m.id,
+            m.title,
+            m.description,
+            m.date_added,
+            u.username,
+            m.likes AS like_count,
+            m.hates AS hate_count,
+            movie_reactions(m.id) AS reactions,
+            m.release_date,
+            m.runtime_minutes,
+            m.directors,
+            m.cast_members,
+            m.original_language,
+            m.imdb_id,
+            m.tmdb_id,
+            m.poster_url,
+            m.poster_srcset,
+            (
+                SELECT COUNT(*) FROM comments c
+                WHERE c.movie_id = m.id AND c.deleted_at IS NULL
+            ) AS comment_count,
+            movie_rating(m.id, $4, $5) AS avg_rating,
+            (SELECT COUNT(*) FROM reviews r WHERE r.movie_id = m.id) AS rating_count,
+            movie_rating_histogram(m.id) AS rating_histogram,
+            m.version
+        FROM trending_votes($3) t
+        JOIN movies m ON m.id = t.movie_id
+        JOIN users u ON m.user_id = u.id
+        WHERE m.deleted_at IS NULL
+        ORDER BY t.net_votes DESC, m.date_added DESC
+        LIMIT $1 OFFSET $2
+        "#,
+    )
+    .bind(pageable.page_size as i64)
+    .bind(pageable.offset as i64)
+    .bind(since)
+    .bind(prior_weight)
+    .bind(prior_mean)
+    .fetch_all(pool)
+    .await?;
+
+    let movies = rows.into_iter().map(Movie::from).collect();
+
+    Ok((movies, total_elements as u64))
+}
+
 pub async fn get_movie_by_id(
     pool: &PgPool,
     movie_id: i32,
 ) -> Result<Option<Movie>, MovieramaError> {
+    let (prior_weight, prior_mean) = review_service::rating_prior(pool).await?;
     let movie = sqlx::query_as!(
         MovieRow,
         r#"
@@ -151,125 +380,806 @@ pub async fn get_movie_by_id(
             m.description,
             m.date_added,
             u.username,
-            COALESCE(SUM(CASE WHEN v.type = 'LIKE' THEN 1 ELSE 0 END), 0) AS "like_count!: i64",
-            COALESCE(SUM(CASE WHEN v.type = 'HATE' THEN 1 ELSE 0 END), 0) AS "hate_count!: i64"
+            m.likes AS like_count,
+            m.hates AS hate_count,
+            movie_reactions(m.id) AS "reactions!: Json<BTreeMap<String, i64>>",
+            m.release_date,
+            m.runtime_minutes,
+            m.directors,
+            m.cast_members,
+            m.original_language,
+            m.imdb_id,
+            m.tmdb_id,
+            m.poster_url,
+            m.poster_srcset,
+            (
+                SELECT COUNT(*) FROM comments c
+                WHERE c.movie_id = m.id AND c.deleted_at IS NULL
+            ) AS "comment_count!: i64",
+            movie_rating(m.id, $2, $3) AS "avg_rating?",
+            (SELECT COUNT(*) FROM reviews r WHERE r.movie_id = m.id) AS "rating_count!",
+            movie_rating_histogram(m.id) AS "rating_histogram!",
+            m.version
         FROM movies m
         JOIN users u ON m.user_id = u.id
-        LEFT JOIN votes v ON v.movie_id = m.id
-        WHERE m.id = $1
-        GROUP BY m.id, u.id
+        WHERE m.id = $1 AND m.deleted_at IS NULL
         "#,
         movie_id,
+        prior_weight,
+        prior_mean,
     )
     .fetch_optional(pool)
     .await?
-    .map(|m| Movie {
-        id: m.id,
-        title: m.title,
-        description: m.description,
-        date_added: m.date_added,
-        username: m.username,
-        like_count: m.like_count as u64,
-        hate_count: m.hate_count as u64,
-    });
+    .map(Movie::from);
 
     Ok(movie)
 }
 
-pub async fn delete_movie(pool: &PgPool, movie_id: i32) -> Result<bool, MovieramaError> {
-    let rows_affected = sqlx::query!(
+/// Moves the movie to its owner's trash. It keeps its votes and can be
+/// restored until it is purged. `expected_version` works as in
+/// [`update_movie`].
+pub async fn delete_movie(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    expected_version: Option<i32>,
+    ctx: &AuditContext,
+) -> Result<bool, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let Some(locked) = lock_editable(&mut tx, movie_id).await? else {
+        return Ok(false);
+    };
+    require_editor(&mut tx, user_id, locked.owner_id).await?;
+
+    if expected_version.is_some_and(|version| version != locked.version) {
+        return Err(MovieramaError::PreconditionFailed);
+    }
+
+    sqlx::query!(
+        "UPDATE movies SET deleted_at = NOW(), version = version + 1 WHERE id = $1",
+        movie_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "movie.delete",
+            target_type: "movie",
+            target_id: Some(movie_id.to_string()),
+            before: Some(json!(locked.movie)),
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn create_movie(
+    pool: &PgPool,
+    user_id: i32,
+    data: NewMovie,
+    force: bool,
+) -> Result<Movie, MovieramaError> {
+    validate(&data)?;
+
+    if !force {
+        let candidates = find_duplicates(pool, &data).await?;
+        if !candidates.is_empty() {
+            return Err(MovieramaError::DuplicateMovie(candidates));
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let movie_id = sqlx::query_scalar!(
         r#"
-        DELETE
+        INSERT INTO movies (
+            title,
+            description,
+            user_id,
+            release_date,
+            runtime_minutes,
+            directors,
+            cast_members,
+            original_language,
+            imdb_id,
+            tmdb_id,
+            poster_url
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        RETURNING id
+        "#,
+        data.title,
+        data.description,
+        user_id,
+        data.release_date,
+        data.runtime_minutes,
+        &data.directors,
+        &data.cast,
+        data.original_language,
+        data.imdb_id,
+        data.tmdb_id,
+        data.poster_url,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(duplicate_external_id)?;
+
+    revision_service::record_revision(&mut tx, movie_id, &data, Some(user_id)).await?;
+
+    tx.commit().await?;
+
+    get_movie_by_id(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)
+}
+
+/// Finds the movies whose normalized title is close to the new one, most
+/// similar first. When both movies have a release date, the years have to
+/// match too, so remakes are not flagged.
+pub async fn find_duplicates(
+    pool: &PgPool,
+    data: &NewMovie,
+) -> Result<Vec<DuplicateCandidate>, MovieramaError> {
+    let candidates = sqlx::query_as!(
+        DuplicateCandidate,
+        r#"
+        SELECT
+            m.id,
+            m.title,
+            m.release_date,
+            u.username,
+            similarity(m.normalized_title, normalize_title($1)) AS "similarity!"
         FROM movies m
-        WHERE m.id = $1
+        JOIN users u ON u.id = m.user_id
+        WHERE m.deleted_at IS NULL
+        -- Lets the trigram index narrow the search down first
+        AND m.normalized_title % normalize_title($1)
+        AND similarity(m.normalized_title, normalize_title($1)) >= $2
+        AND (
+            $3::INTEGER IS NULL
+            OR m.release_date IS NULL
+            OR EXTRACT(YEAR FROM m.release_date)::INTEGER = $3
+        )
+        ORDER BY 5 DESC, m.id
+        LIMIT $4
         "#,
-        movie_id,
+        data.title,
+        duplicate_similarity(),
+        data.release_date.map(|date| date.year()),
+        MAX_DUPLICATE_CANDIDATES,
     )
-    .execute(pool)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(candidates)
+}
+
+/// Folds a duplicate into the canonical movie. Comments, reviews and votes
+/// move over, except where a user reviewed or voted on both: then their
+/// review or vote of the canonical movie is kept. The duplicate is then
+/// deleted for good, along with its uploaded poster. Only moderators can
+/// merge movies.
+pub async fn merge_movies(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    moderator_id: i32,
+    duplicate_id: i32,
+    canonical_id: i32,
+    ctx: &AuditContext,
+) -> Result<Movie, MovieramaError> {
+    user_service::require_role(pool, moderator_id, Role::Moderator).await?;
+
+    if duplicate_id == canonical_id {
+        return Err(MovieramaError::BadRequest(
+            "A movie cannot be merged into itself".to_owned(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // Locked in id order, so that concurrent merges cannot deadlock
+    let locked = sqlx::query!(
+        r#"
+        SELECT id, title
+        FROM movies
+        WHERE id IN ($1, $2) AND deleted_at IS NULL
+        ORDER BY id
+        FOR UPDATE
+        "#,
+        duplicate_id,
+        canonical_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let duplicate = locked
+        .iter()
+        .find(|m| m.id == duplicate_id)
+        .ok_or(MovieramaError::NotFound)?;
+    if locked.len() != 2 {
+        return Err(MovieramaError::NotFound);
+    }
+
+    let moved = sqlx::query!(
+        r#"
+        UPDATE votes
+        SET movie_id = $2
+        WHERE movie_id = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM votes kept
+            WHERE kept.movie_id = $2 AND kept.user_id = votes.user_id
+        )
+        "#,
+        duplicate_id,
+        canonical_id,
+    )
+    .execute(&mut *tx)
     .await?
     .rows_affected();
 
-    Ok(rows_affected > 0)
+    // As with votes, a user's review of the canonical movie wins
+    sqlx::query!(
+        r#"
+        UPDATE reviews
+        SET movie_id = $2
+        WHERE movie_id = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM reviews kept
+            WHERE kept.movie_id = $2 AND kept.user_id = reviews.user_id
+        )
+        "#,
+        duplicate_id,
+        canonical_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Threads move over as they are
+    sqlx::query!(
+        "UPDATE comments SET movie_id = $2 WHERE movie_id = $1",
+        duplicate_id,
+        canonical_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // The votes left behind are those of users who voted on both
+    let blobs = sqlx::query_scalar!(
+        "DELETE FROM movies WHERE id = $1 RETURNING poster_blobs",
+        duplicate_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "movie.merge",
+            target_type: "movie",
+            target_id: Some(duplicate_id.to_string()),
+            before: Some(json!({ "title": duplicate.title })),
+            after: Some(json!({
+                "mergedInto": canonical_id,
+                "votesMoved": moved,
+            })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+    poster_service::remove_blobs(store, &blobs).await;
+
+    get_movie_by_id(pool, canonical_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)
 }
 
-pub async fn create_movie(
+/// Fills in the synopsis, release date and poster the user left out, from
+/// the provider's movie with the same title (and year, if one was given).
+/// Without a clear match, or when the provider is unavailable, the movie is
+/// returned as it is.
+pub async fn enrich_movie(provider: &dyn MetadataProvider, data: NewMovie) -> NewMovie {
+    let found = match provider.search(&data.title).await {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::warn!("Could not enrich movie '{}': {}", data.title, e);
+            return data;
+        }
+    };
+
+    let year = data.release_date.map(|date| date.year());
+    let Some(metadata) = found.into_iter().find(|m| {
+        m.title.eq_ignore_ascii_case(data.title.trim())
+            && year.is_none_or(|year| m.release_date.map(|date| date.year()) == Some(year))
+    }) else {
+        return data;
+    };
+
+    NewMovie {
+        description: data.description.or(metadata.overview),
+        release_date: data.release_date.or(metadata.release_date),
+        poster_url: data.poster_url.or(metadata.poster_url),
+        ..data
+    }
+}
+
+/// Replaces the editable fields. With an `expected_version` the update only
+/// goes through if nobody changed the movie since that version. Changing the
+/// poster URL removes the copies of an uploaded poster.
+pub async fn update_movie(
     pool: &PgPool,
+    store: &dyn BlobStore,
     user_id: i32,
+    movie_id: i32,
     data: NewMovie,
+    expected_version: Option<i32>,
+    ctx: &AuditContext,
 ) -> Result<Movie, MovieramaError> {
-    let rec = sqlx::query_as!(
-        MovieRow,
+    validate(&data)?;
+
+    let mut tx = pool.begin().await?;
+
+    let Editable {
+        movie: current,
+        version: current_version,
+        owner_id,
+    } = lock_editable(&mut tx, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)?;
+    require_editor(&mut tx, user_id, owner_id).await?;
+
+    if expected_version.is_some_and(|version| version != current_version) {
+        return Err(MovieramaError::PreconditionFailed);
+    }
+
+    if data != current {
+        let replaced_blobs = if data.poster_url != current.poster_url {
+            sqlx::query_scalar!("SELECT poster_blobs FROM movies WHERE id = $1", movie_id)
+                .fetch_one(&mut *tx)
+                .await?
+        } else {
+            Vec::new()
+        };
+
+        sqlx::query!(
+            r#"
+            UPDATE movies
+            SET
+                title = $2,
+                description = $3,
+                release_date = $4,
+                runtime_minutes = $5,
+                directors = $6,
+                cast_members = $7,
+                original_language = $8,
+                imdb_id = $9,
+                tmdb_id = $10,
+                poster_url = $11,
+                -- The thumbnails belong to the uploaded poster only
+                poster_srcset = CASE
+                    WHEN poster_url IS DISTINCT FROM $11 THEN NULL
+                    ELSE poster_srcset
+                END,
+                poster_blobs = CASE
+                    WHEN poster_url IS DISTINCT FROM $11 THEN '{}'
+                    ELSE poster_blobs
+                END,
+                version = version + 1
+            WHERE id = $1
+            "#,
+            movie_id,
+            data.title,
+            data.description,
+            data.release_date,
+            data.runtime_minutes,
+            &data.directors,
+            &data.cast,
+            data.original_language,
+            data.imdb_id,
+            data.tmdb_id,
+            data.poster_url,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(duplicate_external_id)?;
+
+        // Revisions track the text, metadata changes only show in the audit log
+        if data.title != current.title || data.description != current.description {
+            revision_service::record_revision(&mut tx, movie_id, &data, ctx.actor_id).await?;
+        }
+
+        audit_service::record(
+            &mut tx,
+            ctx,
+            NewAuditEvent {
+                action: "movie.update",
+                target_type: "movie",
+                target_id: Some(movie_id.to_string()),
+                before: Some(json!(current)),
+                after: Some(json!(data)),
+            },
+        )
+        .await?;
+
+        tx.commit().await?;
+        poster_service::remove_blobs(store, &replaced_blobs).await;
+    } else {
+        // Nothing to save, let go of the lock before reading the movie back
+        tx.rollback().await?;
+    }
+
+    get_movie_by_id(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to the movie's editable fields:
+/// absent members are kept, `null` clears a field and anything else replaces
+/// it. The merged movie goes through the same checks as a full update.
+pub async fn patch_movie(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    user_id: i32,
+    movie_id: i32,
+    patch: &Value,
+    expected_version: Option<i32>,
+    ctx: &AuditContext,
+) -> Result<Movie, MovieramaError> {
+    if !patch.is_object() {
+        return Err(MovieramaError::BadRequest(
+            "The patch must be a JSON object".to_owned(),
+        ));
+    }
+
+    let (current, current_version) = get_editable(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)?;
+
+    let mut document = json!(current);
+    merge_patch::apply(&mut document, patch);
+
+    let merged: NewMovie = serde_json::from_value(document)
+        .map_err(|e| MovieramaError::BadRequest(format!("Invalid movie: {}", e)))?;
+
+    // Without an expected version the patch still has to apply to the movie
+    // it was merged with, not to one changed in the meantime
+    let expected_version = expected_version.unwrap_or(current_version);
+    update_movie(
+        pool,
+        store,
+        user_id,
+        movie_id,
+        merged,
+        Some(expected_version),
+        ctx,
+    )
+    .await
+}
+
+/// Returns the editable fields of a movie that is not deleted, with its version
+pub async fn get_editable(
+    pool: &PgPool,
+    movie_id: i32,
+) -> Result<Option<(NewMovie, i32)>, MovieramaError> {
+    let mut conn = pool.acquire().await?;
+    let editable = lock_editable(&mut conn, movie_id).await?;
+    Ok(editable.map(|e| (e.movie, e.version)))
+}
+
+/// The editable fields of a movie, with what edits are checked against
+struct Editable {
+    movie: NewMovie,
+    version: i32,
+    owner_id: i32,
+}
+
+/// Only the owner of a movie and moderators may change or delete it. Runs
+/// on the caller's transaction, which already holds the movie locked.
+async fn require_editor(
+    conn: &mut PgConnection,
+    user_id: i32,
+    owner_id: i32,
+) -> Result<(), MovieramaError> {
+    if owner_id != user_id && user_service::role_of(conn, user_id).await? < Role::Moderator {
+        return Err(MovieramaError::Forbidden);
+    }
+
+    Ok(())
+}
+
+/// Like [`get_editable`], locking the row until the transaction ends
+async fn lock_editable(
+    conn: &mut PgConnection,
+    movie_id: i32,
+) -> Result<Option<Editable>, MovieramaError> {
+    let row = sqlx::query!(
         r#"
-        INSERT INTO movies (title, description, user_id)
-        VALUES ($1, $2, $3)
-        RETURNING 
+        SELECT
+            title,
+            description,
+            release_date,
+            runtime_minutes,
+            directors,
+            cast_members,
+            original_language,
+            imdb_id,
+            tmdb_id,
+            poster_url,
+            version,
+            user_id
+        FROM movies
+        WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+        movie_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(|r| Editable {
+        movie: NewMovie {
+            title: r.title,
+            description: r.description,
+            release_date: r.release_date,
+            runtime_minutes: r.runtime_minutes,
+            directors: r.directors,
+            cast: r.cast_members,
+            original_language: r.original_language,
+            imdb_id: r.imdb_id,
+            tmdb_id: r.tmdb_id,
+            poster_url: r.poster_url,
+        },
+        version: r.version,
+        owner_id: r.user_id,
+    }))
+}
+
+pub async fn list_trash(
+    pool: &PgPool,
+    user_id: i32,
+    pageable: &Pageable,
+) -> Result<(Vec<TrashedMovie>, u64), MovieramaError> {
+    let total_elements = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM movies
+        WHERE user_id = $1 AND deleted_at IS NOT NULL
+        "#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await? as u64;
+
+    let movies = sqlx::query_as!(
+        TrashedMovie,
+        r#"
+        SELECT
             id,
             title,
             description,
             date_added,
-            (SELECT username FROM users WHERE id = $3) AS "username!: String",
-            0 AS "like_count!: i64",
-            0 AS "hate_count!: i64"
+            deleted_at AS "deleted_at!",
+            deleted_at + make_interval(days => $2) AS "purge_at!"
+        FROM movies
+        WHERE user_id = $1 AND deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, id DESC
+        LIMIT $3 OFFSET $4
         "#,
-        data.title,
-        data.description,
         user_id,
+        trash_retention_days(),
+        pageable.page_size as i64,
+        pageable.offset as i64,
     )
-    .fetch_one(pool)
+    .fetch_all(pool)
     .await?;
 
-    Ok(Movie {
-        id: rec.id,
-        title: rec.title,
-        description: rec.description,
-        date_added: rec.date_added,
-        username: rec.username,
-        like_count: 0,
-        hate_count: 0,
-    })
+    Ok((movies, total_elements))
+}
+
+/// Takes a movie out of the trash. Only its owner can restore it.
+pub async fn restore_movie(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    ctx: &AuditContext,
+) -> Result<Movie, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let restored = sqlx::query!(
+        r#"
+        UPDATE movies
+        SET deleted_at = NULL, version = version + 1
+        WHERE id = $1 AND user_id = $2 AND deleted_at IS NOT NULL
+        RETURNING title, description
+        "#,
+        movie_id,
+        user_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(duplicate_external_id)?
+    .ok_or(MovieramaError::NotFound)?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "movie.restore",
+            target_type: "movie",
+            target_id: Some(movie_id.to_string()),
+            before: None,
+            after: Some(json!({
+                "title": restored.title,
+                "description": restored.description,
+            })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    get_movie_by_id(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)
+}
+
+/// Permanently removes the movies that have been in the trash for longer
+/// than the retention period, their votes going with them through
+/// `ON DELETE CASCADE`. Returns how many movies were removed.
+pub async fn purge_trashed_movies(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+) -> Result<u64, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let purged = sqlx::query!(
+        r#"
+        DELETE FROM movies
+        WHERE deleted_at <= NOW() - make_interval(days => $1)
+        RETURNING id, title, description, poster_blobs
+        "#,
+        trash_retention_days(),
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for movie in &purged {
+        audit_service::record(
+            &mut tx,
+            &AuditContext::default(),
+            NewAuditEvent {
+                action: "movie.purge",
+                target_type: "movie",
+                target_id: Some(movie.id.to_string()),
+                before: Some(json!({
+                    "title": movie.title,
+                    "description": movie.description,
+                })),
+                after: None,
+            },
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    let blobs: Vec<String> = purged.iter().flat_map(|m| m.poster_blobs.clone()).collect();
+    poster_service::remove_blobs(store, &blobs).await;
+
+    Ok(purged.len() as u64)
+}
+
+fn validate(data: &NewMovie) -> Result<(), MovieramaError> {
+    let invalid = |message: &str| Err(MovieramaError::BadRequest(message.to_owned()));
+
+    if data.title.trim().is_empty() {
+        return invalid("title must not be empty");
+    }
+
+    if data.title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(MovieramaError::BadRequest(format!(
+            "title must be at most {} characters",
+            MAX_TITLE_LENGTH
+        )));
+    }
+
+    if data
+        .description
+        .as_ref()
+        .is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LENGTH)
+    {
+        return Err(MovieramaError::BadRequest(format!(
+            "description must be at most {} characters",
+            MAX_DESCRIPTION_LENGTH
+        )));
+    }
+
+    if data.runtime_minutes.is_some_and(|minutes| minutes <= 0) {
+        return invalid("runtimeMinutes must be positive");
+    }
+
+    if data
+        .directors
+        .iter()
+        .chain(&data.cast)
+        .any(|name| name.trim().is_empty())
+    {
+        return invalid("directors and cast must not contain empty names");
+    }
+
+    if let Some(language) = &data.original_language
+        && !(language.len() == 2 && language.chars().all(|c| c.is_ascii_lowercase()))
+    {
+        return invalid("originalLanguage must be a two letter ISO 639-1 code");
+    }
+
+    if let Some(imdb_id) = &data.imdb_id
+        && !imdb_id
+            .strip_prefix("tt")
+            .is_some_and(|digits| digits.len() >= 7 && digits.chars().all(|c| c.is_ascii_digit()))
+    {
+        return invalid("imdbId must look like tt0123456");
+    }
+
+    if data.tmdb_id.is_some_and(|id| id <= 0) {
+        return invalid("tmdbId must be positive");
+    }
+
+    if let Some(url) = &data.poster_url
+        && !(url.starts_with("https://") || url.starts_with("http://"))
+    {
+        return invalid("posterUrl must be an http(s) URL");
+    }
+
+    Ok(())
 }
 
-pub async fn update_movie(
-    pool: &PgPool,
-    movie_id: i32,
-    data: NewMovie,
-) -> Result<NewMovie, MovieramaError> {
-    let result = sqlx::query_as!(
-        NewMovie,
-        r#"
-        UPDATE movies
-        SET title = $1, description = $2
-        WHERE id = $3
-        RETURNING 
-            title,
-            description
-        "#,
-        data.title,
-        data.description,
-        movie_id,
-    )
-    .fetch_one(pool)
-    .await;
-
-    match result {
-        Ok(updated_movie) => Ok(updated_movie),
-        Err(sqlx::Error::RowNotFound) => Err(MovieramaError::NotFound),
-        Err(e) => Err(MovieramaError::DatabaseError(e)),
+/// Reports a clash on the unique external ids as a conflict
+fn duplicate_external_id(e: sqlx::Error) -> MovieramaError {
+    let constraint = e.as_database_error().and_then(|e| e.constraint());
+    match constraint {
+        Some("movies_imdb_id_key") => {
+            MovieramaError::Conflict("A movie with this IMDb id already exists".to_owned())
+        }
+        Some("movies_tmdb_id_key") => {
+            MovieramaError::Conflict("A movie with this TMDB id already exists".to_owned())
+        }
+        _ => MovieramaError::DatabaseError(e),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::ClientInfo;
     use crate::models::{NewMovie, RegisterUser};
     use crate::pagination::{Pageable, Sort};
     use crate::services::auth_service;
+    use crate::storage::LocalStore;
     use sqlx::PgPool;
+    use std::path::PathBuf;
 
     fn create_pagination(page: u32, size: u32, sort: &str) -> Pageable {
         Pageable::new(page, size, Sort::from_query(sort))
     }
 
+    /// None of these movies has an uploaded poster, so nothing is ever stored
+    fn no_blobs() -> LocalStore {
+        LocalStore {
+            root: std::env::temp_dir().join("movierama-no-blobs"),
+            public_url: "/media".into(),
+        }
+    }
+
     async fn create_user(pool: &PgPool, username: &str) -> i32 {
         unsafe {
             std::env::set_var("JWT_SECRET", "test-secret");
@@ -279,8 +1189,11 @@ mod tests {
             username: username.into(),
             email: format!("{}@mail.com", username),
             password: "password".into(),
+            invite_code: None,
         };
-        let auth = auth_service::register_user(pool, &reg).await.unwrap();
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
 
         let claims = jsonwebtoken::decode::<crate::auth::Claims>(
             &auth.token,
@@ -297,8 +1210,10 @@ mod tests {
         let new_movie = NewMovie {
             title: title.into(),
             description: Some(format!("Description for {}", title)),
+            ..Default::default()
         };
-        create_movie(pool, user_id, new_movie).await.unwrap()
+        // Tests number their movies, which would look like duplicates
+        create_movie(pool, user_id, new_movie, true).await.unwrap()
     }
 
     #[sqlx::test(migrations = "./migrations")]
@@ -308,9 +1223,12 @@ mod tests {
         let new_movie = NewMovie {
             title: "Test Movie".into(),
             description: Some("A great test movie".into()),
+            ..Default::default()
         };
 
-        let result = create_movie(&pool, user_id, new_movie).await.unwrap();
+        let result = create_movie(&pool, user_id, new_movie, false)
+            .await
+            .unwrap();
 
         assert_eq!(result.title, "Test Movie");
         assert_eq!(result.description, Some("A great test movie".into()));
@@ -350,7 +1268,9 @@ mod tests {
 
         let pageable = create_pagination(0, 10, "dateAdded,desc");
 
-        let (movies, total) = list_all_movies(&pool, &pageable).await.unwrap();
+        let (movies, total) = list_all_movies(&pool, &pageable, &MovieFilter::default())
+            .await
+            .unwrap();
 
         assert_eq!(total, 2);
         assert_eq!(movies.len(), 2);
@@ -371,14 +1291,18 @@ mod tests {
 
         // Test first page with 2 items
         let pageable = create_pagination(0, 2, "dateAdded,desc");
-        let (movies, total) = list_all_movies(&pool, &pageable).await.unwrap();
+        let (movies, total) = list_all_movies(&pool, &pageable, &MovieFilter::default())
+            .await
+            .unwrap();
 
         assert_eq!(total, 3);
         assert_eq!(movies.len(), 2);
 
         // Test second page with 2 items
         let pageable = create_pagination(1, 2, "dateAdded,desc");
-        let (movies, total) = list_all_movies(&pool, &pageable).await.unwrap();
+        let (movies, total) = list_all_movies(&pool, &pageable, &MovieFilter::default())
+            .await
+            .unwrap();
 
         assert_eq!(total, 3);
         assert_eq!(movies.len(), 1);
@@ -391,7 +1315,9 @@ mod tests {
 
         // Request page that doesn't exist
         let pageable = create_pagination(5, 10, "dateAdded,desc");
-        let (movies, total) = list_all_movies(&pool, &pageable).await.unwrap();
+        let (movies, total) = list_all_movies(&pool, &pageable, &MovieFilter::default())
+            .await
+            .unwrap();
 
         assert_eq!(total, 1);
         assert_eq!(movies.len(), 0); // Empty result for out-of-bounds page
@@ -408,9 +1334,10 @@ mod tests {
 
         let pageable = create_pagination(0, 10, "dateAdded,desc");
 
-        let (movies, total) = list_all_movies_by_username(&pool, &pageable, "specific_user")
-            .await
-            .unwrap();
+        let (movies, total) =
+            list_all_movies_by_username(&pool, &pageable, "specific_user", &MovieFilter::default())
+                .await
+                .unwrap();
 
         assert_eq!(total, 2);
         assert_eq!(movies.len(), 2);
@@ -425,9 +1352,14 @@ mod tests {
     async fn test_list_all_movies_by_username_not_found(pool: PgPool) {
         let pageable = create_pagination(0, 10, "dateAdded,desc");
 
-        let (movies, total) = list_all_movies_by_username(&pool, &pageable, "nonexistent_user")
-            .await
-            .unwrap();
+        let (movies, total) = list_all_movies_by_username(
+            &pool,
+            &pageable,
+            "nonexistent_user",
+            &MovieFilter::default(),
+        )
+        .await
+        .unwrap();
 
         assert_eq!(total, 0);
         assert_eq!(movies.len(), 0);
@@ -444,27 +1376,30 @@ mod tests {
 
         // First page - 2 movies
         let pageable = create_pagination(0, 2, "dateAdded,desc");
-        let (movies, total) = list_all_movies_by_username(&pool, &pageable, "paged_user")
-            .await
-            .unwrap();
+        let (movies, total) =
+            list_all_movies_by_username(&pool, &pageable, "paged_user", &MovieFilter::default())
+                .await
+                .unwrap();
 
         assert_eq!(total, 5);
         assert_eq!(movies.len(), 2);
 
         // Second page - 2 movies
         let pageable = create_pagination(1, 2, "dateAdded,desc");
-        let (movies, total) = list_all_movies_by_username(&pool, &pageable, "paged_user")
-            .await
-            .unwrap();
+        let (movies, total) =
+            list_all_movies_by_username(&pool, &pageable, "paged_user", &MovieFilter::default())
+                .await
+                .unwrap();
 
         assert_eq!(total, 5);
         assert_eq!(movies.len(), 2);
 
         // Third page - 1 movie
         let pageable = create_pagination(2, 2, "dateAdded,desc");
-        let (movies, total) = list_all_movies_by_username(&pool, &pageable, "paged_user")
-            .await
-            .unwrap();
+        let (movies, total) =
+            list_all_movies_by_username(&pool, &pageable, "paged_user", &MovieFilter::default())
+                .await
+                .unwrap();
 
         assert_eq!(total, 5);
         assert_eq!(movies.len(), 1);
@@ -475,7 +1410,9 @@ mod tests {
         let user_id = create_user(&pool, "deleter").await;
         let movie = create_test_movie(&pool, user_id, "To Delete").await;
 
-        let deleted = delete_movie(&pool, movie.id).await.unwrap();
+        let deleted = delete_movie(&pool, user_id, movie.id, None, &AuditContext::default())
+            .await
+            .unwrap();
         assert!(deleted);
 
         // Verify movie is gone
@@ -483,9 +1420,37 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_delete_movie_of_someone_else(pool: PgPool) {
+        let owner_id = create_user(&pool, "delete_owner").await;
+        let other_id = create_user(&pool, "delete_other").await;
+        let moderator_id = create_user(&pool, "delete_mod").await;
+        sqlx::query!(
+            "UPDATE users SET role = 'MODERATOR' WHERE id = $1",
+            moderator_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let movie = create_test_movie(&pool, owner_id, "Not Yours").await;
+        let ctx = AuditContext::default();
+
+        let result = delete_movie(&pool, other_id, movie.id, None, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+        assert!(get_movie_by_id(&pool, movie.id).await.unwrap().is_some());
+
+        assert!(
+            delete_movie(&pool, moderator_id, movie.id, None, &ctx)
+                .await
+                .unwrap()
+        );
+    }
+
     #[sqlx::test(migrations = "./migrations")]
     async fn test_delete_movie_not_found(pool: PgPool) {
-        let deleted = delete_movie(&pool, 99999).await.unwrap();
+        let deleted = delete_movie(&pool, 1, 99999, None, &AuditContext::default())
+            .await
+            .unwrap();
         assert!(!deleted);
     }
 
@@ -497,9 +1462,20 @@ mod tests {
         let update_data = NewMovie {
             title: "Updated Title".into(),
             description: Some("Updated description".into()),
+            ..Default::default()
         };
 
-        let result = update_movie(&pool, movie.id, update_data).await.unwrap();
+        let result = update_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            update_data,
+            None,
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
 
         assert_eq!(result.title, "Updated Title");
         assert_eq!(result.description, Some("Updated description".into()));
@@ -513,14 +1489,316 @@ mod tests {
         );
     }
 
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_stale_version_is_rejected(pool: PgPool) {
+        let user_id = create_user(&pool, "two_tabs").await;
+        let movie = create_test_movie(&pool, user_id, "Tabs").await;
+        let ctx = AuditContext::default();
+
+        let first_tab = NewMovie {
+            title: "First tab".into(),
+            description: None,
+            ..Default::default()
+        };
+        let updated = update_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            first_tab,
+            Some(movie.version),
+            &ctx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.version, movie.version + 1);
+
+        let second_tab = NewMovie {
+            title: "Second tab".into(),
+            description: None,
+            ..Default::default()
+        };
+        let result = update_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            second_tab,
+            Some(movie.version),
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::PreconditionFailed)));
+
+        let result = delete_movie(&pool, user_id, movie.id, Some(movie.version), &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::PreconditionFailed)));
+
+        let current = get_movie_by_id(&pool, movie.id).await.unwrap().unwrap();
+        assert_eq!(current.title, "First tab");
+        assert!(
+            delete_movie(&pool, user_id, movie.id, Some(current.version), &ctx)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_update_movie_of_someone_else(pool: PgPool) {
+        let owner_id = create_user(&pool, "edit_owner").await;
+        let other_id = create_user(&pool, "edit_other").await;
+        let movie = create_test_movie(&pool, owner_id, "Mine").await;
+        let ctx = AuditContext::default();
+
+        let data = NewMovie {
+            title: "Theirs".into(),
+            ..Default::default()
+        };
+        let result = update_movie(&pool, &no_blobs(), other_id, movie.id, data, None, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let result = patch_movie(
+            &pool,
+            &no_blobs(),
+            other_id,
+            movie.id,
+            &json!({"title": "Theirs"}),
+            None,
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let unchanged = get_movie_by_id(&pool, movie.id).await.unwrap().unwrap();
+        assert_eq!(unchanged.title, "Mine");
+        assert_eq!(unchanged.version, movie.version);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_writes_need_one_connection(pool: PgPool) {
+        let owner_id = create_user(&pool, "single_owner").await;
+        let moderator_id = create_user(&pool, "single_moderator").await;
+        sqlx::query!(
+            "UPDATE users SET role = 'MODERATOR' WHERE id = $1",
+            moderator_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let movie = create_test_movie(&pool, owner_id, "Narrow").await;
+
+        // Holding a second connection while the movie is locked would wait
+        // for the first one to be released, which never happens
+        let single = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(std::time::Duration::from_secs(2))
+            .connect_with(pool.connect_options().as_ref().clone())
+            .await
+            .unwrap();
+        let ctx = AuditContext::default();
+
+        let data = NewMovie {
+            title: "Narrower".into(),
+            ..Default::default()
+        };
+        update_movie(
+            &single,
+            &no_blobs(),
+            moderator_id,
+            movie.id,
+            data.clone(),
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+        update_movie(&single, &no_blobs(), owner_id, movie.id, data, None, &ctx)
+            .await
+            .unwrap();
+        assert!(
+            delete_movie(&single, moderator_id, movie.id, None, &ctx)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_patch_movie(pool: PgPool) {
+        let user_id = create_user(&pool, "patcher").await;
+        let movie = create_test_movie(&pool, user_id, "Patched").await;
+        let ctx = AuditContext::default();
+
+        // Absent fields are kept
+        let patched = patch_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            &json!({"title": "Renamed"}),
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(patched.title, "Renamed");
+        assert_eq!(patched.description, movie.description);
+
+        // null clears a field
+        let patched = patch_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            &json!({"description": null}),
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(patched.title, "Renamed");
+        assert_eq!(patched.description, None);
+
+        // The merged movie must still be valid
+        for patch in [
+            json!({"title": null}),
+            json!({"title": " "}),
+            json!({"title": 1}),
+            json!({"title": "x".repeat(MAX_TITLE_LENGTH + 1)}),
+            json!({"description": "\n".repeat(MAX_DESCRIPTION_LENGTH + 1)}),
+        ] {
+            let result =
+                patch_movie(&pool, &no_blobs(), user_id, movie.id, &patch, None, &ctx).await;
+            assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+        }
+
+        let result = patch_movie(
+            &pool,
+            &no_blobs(),
+            user_id,
+            movie.id,
+            &json!({"title": "Late"}),
+            Some(1),
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::PreconditionFailed)));
+    }
+
+    async fn create_released_movie(pool: &PgPool, user_id: i32, title: &str, year: i32) -> Movie {
+        let new_movie = NewMovie {
+            title: title.into(),
+            release_date: NaiveDate::from_ymd_opt(year, 6, 1),
+            ..Default::default()
+        };
+        create_movie(pool, user_id, new_movie, false).await.unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_filter_and_sort_by_release(pool: PgPool) {
+        let user_id = create_user(&pool, "archivist").await;
+        create_released_movie(&pool, user_id, "Metropolis", 1927).await;
+        create_released_movie(&pool, user_id, "Blade Runner", 1982).await;
+        create_released_movie(&pool, user_id, "Arrival", 2016).await;
+        create_test_movie(&pool, user_id, "Unreleased").await;
+
+        let eighties_on = MovieFilter {
+            year_from: Some(1982),
+            year_to: None,
+        };
+        let (movies, total) = list_all_movies(
+            &pool,
+            &create_pagination(0, 10, "releaseDate,asc"),
+            &eighties_on,
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(movies[0].title, "Blade Runner");
+        assert_eq!(movies[1].title, "Arrival");
+
+        // Movies without a release date come last
+        let (movies, _) = list_all_movies(
+            &pool,
+            &create_pagination(0, 10, "releaseDate,desc"),
+            &MovieFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(movies[0].title, "Arrival");
+        assert_eq!(movies[3].title, "Unreleased");
+
+        let twenties = MovieFilter {
+            year_from: Some(1920),
+            year_to: Some(1929),
+        };
+        let (movies, total) = list_all_movies_by_username(
+            &pool,
+            &create_pagination(0, 10, "dateAdded,desc"),
+            "archivist",
+            &twenties,
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(movies[0].title, "Metropolis");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_external_ids_are_unique(pool: PgPool) {
+        let user_id = create_user(&pool, "cataloguer").await;
+        let heat = NewMovie {
+            title: "Heat".into(),
+            directors: vec!["Michael Mann".into()],
+            original_language: Some("en".into()),
+            imdb_id: Some("tt0113277".into()),
+            ..Default::default()
+        };
+
+        let created = create_movie(&pool, user_id, heat.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(created.directors, vec!["Michael Mann".to_owned()]);
+        assert_eq!(created.imdb_id.as_deref(), Some("tt0113277"));
+
+        // Forced past the duplicate check, the external ids still clash
+        let result = create_movie(&pool, user_id, heat.clone(), true).await;
+        assert!(matches!(result, Err(MovieramaError::Conflict(_))));
+
+        let invalid = NewMovie {
+            imdb_id: Some("113277".into()),
+            ..heat.clone()
+        };
+        let result = create_movie(&pool, user_id, invalid, false).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        // A trashed movie does not hold on to its ids, but cannot be
+        // restored once they are taken again
+        let ctx = AuditContext::default();
+        delete_movie(&pool, user_id, created.id, None, &ctx)
+            .await
+            .unwrap();
+        create_movie(&pool, user_id, heat, true).await.unwrap();
+        let result = restore_movie(&pool, user_id, created.id, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Conflict(_))));
+    }
+
     #[sqlx::test(migrations = "./migrations")]
     async fn test_update_movie_not_found(pool: PgPool) {
         let update_data = NewMovie {
             title: "New Title".into(),
             description: Some("New description".into()),
+            ..Default::default()
         };
 
-        let result = update_movie(&pool, 99999, update_data).await;
+        let result = update_movie(
+            &pool,
+            &no_blobs(),
+            1,
+            99999,
+            update_data,
+            None,
+            &AuditContext::default(),
+        )
+        .await;
 
         assert!(matches!(result, Err(MovieramaError::NotFound)));
     }
@@ -535,10 +1813,10 @@ mod tests {
 
         // Add some votes
         use crate::services::vote_service;
-        vote_service::insert_vote(&pool, user2_id, movie.id, crate::models::VoteType::Like)
+        vote_service::insert_vote(&pool, user2_id, movie.id, crate::models::VoteType::like())
             .await
             .unwrap();
-        vote_service::insert_vote(&pool, user3_id, movie.id, crate::models::VoteType::Like)
+        vote_service::insert_vote(&pool, user3_id, movie.id, crate::models::VoteType::like())
             .await
             .unwrap();
 
@@ -548,6 +1826,124 @@ mod tests {
         assert_eq!(result.hate_count, 0);
     }
 
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_ranking_scores(pool: PgPool) {
+        use crate::{models::VoteType, services::vote_service};
+
+        let owner = create_user(&pool, "ranker").await;
+        let mut voters = Vec::new();
+        for i in 0..4 {
+            voters.push(create_user(&pool, &format!("ranker{}", i)).await);
+        }
+        let loved = create_test_movie(&pool, owner, "Loved").await.id;
+        let split = create_test_movie(&pool, owner, "Split").await.id;
+        let one_like = create_test_movie(&pool, owner, "One Like").await.id;
+
+        for (i, &uid) in voters.iter().enumerate() {
+            vote_service::insert_vote(&pool, uid, loved, VoteType::like())
+                .await
+                .unwrap();
+            let vote = if i % 2 == 0 {
+                VoteType::like()
+            } else {
+                VoteType::hate()
+            };
+            vote_service::insert_vote(&pool, uid, split, vote)
+                .await
+                .unwrap();
+        }
+        vote_service::insert_vote(&pool, voters[0], one_like, VoteType::like())
+            .await
+            .unwrap();
+
+        let ranking = |sort: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let (movies, _) = list_all_movies(
+                    &pool,
+                    &create_pagination(0, 10, sort),
+                    &MovieFilter::default(),
+                )
+                .await
+                .unwrap();
+                movies.into_iter().map(|m| m.id).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(ranking("best").await, vec![loved, one_like, split]);
+        assert_eq!(ranking("controversial").await[0], split);
+        assert_eq!(ranking("hot").await[0], loved);
+
+        // Two days of age outweigh four votes
+        sqlx::query!(
+            "UPDATE movies SET date_added = NOW() - INTERVAL '2 days' WHERE id = $1",
+            loved,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(ranking("hot").await[2], loved);
+
+        // Retracting votes lowers the scores straight away
+        for &uid in &voters {
+            vote_service::delete_vote(&pool, uid, loved).await.unwrap();
+        }
+        assert_eq!(ranking("best").await, vec![one_like, split, loved]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_list_trending_movies(pool: PgPool) {
+        use crate::{models::VoteType, services::vote_service};
+
+        let owner = create_user(&pool, "trender").await;
+        let fan = create_user(&pool, "trend_fan").await;
+        let critic = create_user(&pool, "trend_critic").await;
+        let old_hit = create_test_movie(&pool, owner, "Old Hit").await.id;
+        let new_hit = create_test_movie(&pool, owner, "New Hit").await.id;
+        let divisive = create_test_movie(&pool, owner, "Divisive").await.id;
+
+        vote_service::vote_movie(&pool, owner, old_hit, VoteType::like())
+            .await
+            .unwrap();
+        vote_service::vote_movie(&pool, fan, old_hit, VoteType::like())
+            .await
+            .unwrap();
+        vote_service::vote_movie(&pool, fan, new_hit, VoteType::like())
+            .await
+            .unwrap();
+        vote_service::vote_movie(&pool, fan, divisive, VoteType::like())
+            .await
+            .unwrap();
+        vote_service::vote_movie(&pool, critic, divisive, VoteType::hate())
+            .await
+            .unwrap();
+
+        sqlx::query!(
+            "UPDATE votes SET updated_at = NOW() - INTERVAL '10 days' WHERE movie_id = $1",
+            old_hit,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let pageable = create_pagination(0, 10, "");
+        let (week, total) = list_trending_movies(&pool, TrendingWindow::Week, &pageable)
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(week[0].id, new_hit);
+
+        let (month, total) = list_trending_movies(&pool, TrendingWindow::Month, &pageable)
+            .await
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(
+            month.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![old_hit, new_hit]
+        );
+        assert_eq!(month[0].like_count, 2);
+    }
+
     #[sqlx::test(migrations = "./migrations")]
     async fn test_list_movies_sorting(pool: PgPool) {
         let user_id = create_user(&pool, "sorter").await;
@@ -558,14 +1954,324 @@ mod tests {
 
         // Test descending order (newest first)
         let pageable_desc = create_pagination(0, 10, "dateAdded,desc");
-        let (movies_desc, _) = list_all_movies(&pool, &pageable_desc).await.unwrap();
+        let (movies_desc, _) = list_all_movies(&pool, &pageable_desc, &MovieFilter::default())
+            .await
+            .unwrap();
         assert_eq!(movies_desc[0].id, movie2.id); // Second movie should be first (newer)
         assert_eq!(movies_desc[1].id, movie1.id);
 
         // Test ascending order (oldest first)
         let pageable_asc = create_pagination(0, 10, "dateAdded,asc");
-        let (movies_asc, _) = list_all_movies(&pool, &pageable_asc).await.unwrap();
+        let (movies_asc, _) = list_all_movies(&pool, &pageable_asc, &MovieFilter::default())
+            .await
+            .unwrap();
         assert_eq!(movies_asc[0].id, movie1.id); // First movie should be first (older)
         assert_eq!(movies_asc[1].id, movie2.id);
     }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_trashed_movie_keeps_votes_until_restored(pool: PgPool) {
+        let owner_id = create_user(&pool, "trasher").await;
+        let voter_id = create_user(&pool, "fan").await;
+        let movie = create_test_movie(&pool, owner_id, "Oops").await;
+
+        use crate::services::vote_service;
+        vote_service::insert_vote(&pool, voter_id, movie.id, crate::models::VoteType::like())
+            .await
+            .unwrap();
+
+        let ctx = AuditContext::default();
+        assert!(
+            delete_movie(&pool, owner_id, movie.id, None, &ctx)
+                .await
+                .unwrap()
+        );
+        // Already in the trash
+        assert!(
+            !delete_movie(&pool, owner_id, movie.id, None, &ctx)
+                .await
+                .unwrap()
+        );
+
+        let (movies, total) = list_all_movies(
+            &pool,
+            &create_pagination(0, 10, "dateAdded,desc"),
+            &MovieFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((movies.len(), total), (0, 0));
+
+        let (trash, total) = list_trash(&pool, owner_id, &create_pagination(0, 10, "deletedAt"))
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(trash[0].id, movie.id);
+
+        // The trash belongs to the owner
+        let result = restore_movie(&pool, voter_id, movie.id, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::NotFound)));
+
+        let restored = restore_movie(&pool, owner_id, movie.id, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(restored.like_count, 1);
+    }
+
+    /// Gives the movie a poster file in a store of its own
+    async fn uploaded_poster(pool: &PgPool, name: &str, movie_id: i32) -> (LocalStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("movierama-{}-{}", name, std::process::id()));
+        let store = LocalStore {
+            root: root.clone(),
+            public_url: "/media".into(),
+        };
+        let key = format!("posters/{}.jpg", movie_id);
+        store.put(&key, "image/jpeg", vec![0xFF]).await.unwrap();
+        sqlx::query!(
+            "UPDATE movies SET poster_blobs = ARRAY[$2] WHERE id = $1",
+            movie_id,
+            key,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        (store, root.join(key))
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_purge_trashed_movies(pool: PgPool) {
+        let user_id = create_user(&pool, "purger").await;
+        let old = create_test_movie(&pool, user_id, "Long Gone").await;
+        let recent = create_test_movie(&pool, user_id, "Just Deleted").await;
+
+        let ctx = AuditContext::default();
+        delete_movie(&pool, user_id, old.id, None, &ctx)
+            .await
+            .unwrap();
+        delete_movie(&pool, user_id, recent.id, None, &ctx)
+            .await
+            .unwrap();
+
+        sqlx::query!(
+            "UPDATE movies SET deleted_at = NOW() - make_interval(days => $2) WHERE id = $1",
+            old.id,
+            trash_retention_days() + 1,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (store, poster) = uploaded_poster(&pool, "purge", old.id).await;
+        assert_eq!(purge_trashed_movies(&pool, &store).await.unwrap(), 1);
+        assert!(!poster.exists());
+        std::fs::remove_dir_all(&store.root).unwrap();
+
+        let (trash, _) = list_trash(&pool, user_id, &create_pagination(0, 10, "deletedAt"))
+            .await
+            .unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].id, recent.id);
+    }
+
+    struct Catalogue(Vec<crate::models::MovieMetadata>);
+
+    impl MetadataProvider for Catalogue {
+        fn search<'a>(&'a self, _title: &'a str) -> crate::metadata::ProviderFuture<'a> {
+            let found = self.0.clone();
+            Box::pin(async { Ok(found) })
+        }
+    }
+
+    fn metadata(title: &str, year: i32) -> crate::models::MovieMetadata {
+        crate::models::MovieMetadata {
+            title: title.into(),
+            overview: Some(format!("{} ({})", title, year)),
+            release_date: NaiveDate::from_ymd_opt(year, 1, 1),
+            poster_url: Some(format!("https://images.test/{}.jpg", year)),
+            original_language: None,
+            imdb_id: None,
+            tmdb_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enrich_movie() {
+        let provider = Catalogue(vec![
+            metadata("Dune: Part Two", 2024),
+            metadata("Dune", 1984),
+            metadata("Dune", 2021),
+        ]);
+
+        let enriched = enrich_movie(
+            &provider,
+            NewMovie {
+                title: "dune".into(),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(enriched.description.as_deref(), Some("Dune (1984)"));
+        assert_eq!(enriched.release_date, NaiveDate::from_ymd_opt(1984, 1, 1));
+
+        // The given year picks the remake, and what the user typed is kept
+        let enriched = enrich_movie(
+            &provider,
+            NewMovie {
+                title: "Dune".into(),
+                description: Some("Spice".into()),
+                release_date: NaiveDate::from_ymd_opt(2021, 10, 22),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(enriched.description.as_deref(), Some("Spice"));
+        assert_eq!(enriched.release_date, NaiveDate::from_ymd_opt(2021, 10, 22));
+        assert_eq!(
+            enriched.poster_url.as_deref(),
+            Some("https://images.test/2021.jpg")
+        );
+
+        let untouched = NewMovie {
+            title: "Dune Messiah".into(),
+            ..Default::default()
+        };
+        assert_eq!(enrich_movie(&provider, untouched.clone()).await, untouched);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_similar_titles_are_duplicates(pool: PgPool) {
+        let user_id = create_user(&pool, "submitter").await;
+        let original = create_released_movie(&pool, user_id, "The Matrix", 1999).await;
+
+        let submission = |title: &str, year: Option<i32>| NewMovie {
+            title: title.into(),
+            release_date: year.and_then(|year| NaiveDate::from_ymd_opt(year, 3, 31)),
+            ..Default::default()
+        };
+
+        let result = create_movie(&pool, user_id, submission("matrix!", None), false).await;
+        let Err(MovieramaError::DuplicateMovie(candidates)) = result else {
+            panic!("expected a duplicate, got {:?}", result);
+        };
+        assert_eq!(candidates[0].id, original.id);
+        assert_eq!(candidates[0].similarity, 1.0);
+
+        let reordered = find_duplicates(&pool, &submission("Matrix, The", Some(1999)))
+            .await
+            .unwrap();
+        assert_eq!(reordered.len(), 1);
+
+        // Same title, different year: a remake
+        let remake = find_duplicates(&pool, &submission("The Matrix", Some(2031)))
+            .await
+            .unwrap();
+        assert!(remake.is_empty());
+
+        let unrelated = find_duplicates(&pool, &submission("Heat", None))
+            .await
+            .unwrap();
+        assert!(unrelated.is_empty());
+
+        let forced = create_movie(&pool, user_id, submission("Matrix", None), true).await;
+        assert!(forced.is_ok());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_merge_movies(pool: PgPool) {
+        let moderator_id = create_user(&pool, "moderator").await;
+        let both_id = create_user(&pool, "voted_both").await;
+        let dup_only_id = create_user(&pool, "voted_duplicate").await;
+
+        let canonical = create_test_movie(&pool, moderator_id, "Inception").await;
+        let duplicate = create_movie(
+            &pool,
+            dup_only_id,
+            NewMovie {
+                title: "inception".into(),
+                ..Default::default()
+            },
+            true,
+        )
+        .await
+        .unwrap();
+
+        use crate::models::VoteType;
+        use crate::services::vote_service::vote_movie;
+        vote_movie(&pool, both_id, canonical.id, VoteType::like())
+            .await
+            .unwrap();
+        vote_movie(&pool, both_id, duplicate.id, VoteType::hate())
+            .await
+            .unwrap();
+        vote_movie(&pool, dup_only_id, duplicate.id, VoteType::like())
+            .await
+            .unwrap();
+
+        let ctx = AuditContext::default();
+        let result = merge_movies(
+            &pool,
+            &no_blobs(),
+            moderator_id,
+            duplicate.id,
+            canonical.id,
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        sqlx::query!(
+            "UPDATE users SET role = 'MODERATOR' WHERE id = $1",
+            moderator_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let result = merge_movies(
+            &pool,
+            &no_blobs(),
+            moderator_id,
+            canonical.id,
+            canonical.id,
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let (store, poster) = uploaded_poster(&pool, "merge", duplicate.id).await;
+        let merged = merge_movies(
+            &pool,
+            &store,
+            moderator_id,
+            duplicate.id,
+            canonical.id,
+            &ctx,
+        )
+        .await
+        .unwrap();
+        assert!(!poster.exists());
+        std::fs::remove_dir_all(&store.root).unwrap();
+        // The user who voted on both keeps their vote on the canonical movie
+        assert_eq!(merged.like_count, 2);
+        assert_eq!(merged.hate_count, 0);
+
+        assert!(
+            get_movie_by_id(&pool, duplicate.id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let result = merge_movies(
+            &pool,
+            &no_blobs(),
+            moderator_id,
+            duplicate.id,
+            canonical.id,
+            &ctx,
+        )
+        .await;
+        assert!(matches!(result, Err(MovieramaError::NotFound)));
+    }
 }
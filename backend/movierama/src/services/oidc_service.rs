@@ -0,0 +1,754 @@
+use crate::{
+    auth::ClientInfo,
+    exceptions::MovieramaError,
+    http,
+    models::{LoginResponse, RegistrationPolicy, User},
+    services::{account_service, auth_service, invite_service},
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+
+const OIDC_PROVIDERS: &str = "OIDC_PROVIDERS";
+const DEFAULT_SCOPES: &str = "openid email profile";
+const LOGIN_STATE_MINUTES: i32 = 10;
+const MAX_USERNAME_LENGTH: usize = 30;
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An OpenID Connect provider. `OIDC_PROVIDERS` holds a comma separated list
+/// of enabled provider names, and each of them is configured through
+/// `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID`, `OIDC_<NAME>_REDIRECT_URI`
+/// and the optional `OIDC_<NAME>_CLIENT_SECRET`, `OIDC_<NAME>_SCOPES` and
+/// `OIDC_<NAME>_ID_TOKEN_ALG`. The latter pins the algorithm ID tokens must be
+/// signed with, otherwise the provider's advertised ones are accepted.
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub id_token_alg: Option<Algorithm>,
+}
+
+impl OidcProvider {
+    pub fn from_env(name: &str) -> Result<Self, MovieramaError> {
+        let enabled = std::env::var(OIDC_PROVIDERS).unwrap_or_default();
+        if !enabled
+            .split(',')
+            .any(|p| p.trim().eq_ignore_ascii_case(name))
+        {
+            return Err(MovieramaError::BadRequest(format!(
+                "Unknown login provider '{}'",
+                name
+            )));
+        }
+
+        let prefix = format!("OIDC_{}_", name.to_uppercase().replace('-', "_"));
+        let optional = |key: &str| std::env::var(format!("{}{}", prefix, key)).ok();
+        let required = |key: &str| {
+            optional(key).ok_or_else(|| {
+                MovieramaError::UnexpectedError(format!("{}{} must be set", prefix, key))
+            })
+        };
+
+        Ok(OidcProvider {
+            name: name.to_lowercase(),
+            issuer: required("ISSUER")?,
+            client_id: required("CLIENT_ID")?,
+            client_secret: optional("CLIENT_SECRET"),
+            redirect_uri: required("REDIRECT_URI")?,
+            scopes: optional("SCOPES").unwrap_or_else(|| DEFAULT_SCOPES.to_owned()),
+            id_token_alg: optional("ID_TOKEN_ALG")
+                .map(|alg| {
+                    alg.parse().map_err(|_| {
+                        MovieramaError::UnexpectedError(format!(
+                            "{}ID_TOKEN_ALG is not a known algorithm",
+                            prefix
+                        ))
+                    })
+                })
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: Option<String>,
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Starts an authorization code flow with PKCE and returns the URL the user
+/// has to be sent to.
+pub async fn authorization_url(
+    pool: &PgPool,
+    provider: &OidcProvider,
+) -> Result<String, MovieramaError> {
+    let metadata = discover(&http::client(PROVIDER_TIMEOUT), provider).await?;
+
+    let state = random_token();
+    let nonce = random_token();
+    let code_verifier = random_token();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    // Drop attempts that were never completed
+    sqlx::query!(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE created_at < NOW() - make_interval(mins => $1)
+        "#,
+        LOGIN_STATE_MINUTES,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO oidc_login_states (state, provider, code_verifier, nonce)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        state,
+        provider.name,
+        code_verifier,
+        nonce,
+    )
+    .execute(pool)
+    .await?;
+
+    let url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("scope", provider.scopes.as_str()),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+
+    Ok(url.to_string())
+}
+
+/// Finishes the flow started by `authorization_url`: exchanges the code,
+/// validates the ID token and logs in the linked account, creating one on
+/// first login.
+pub async fn complete_login(
+    pool: &PgPool,
+    provider: &OidcProvider,
+    code: &str,
+    state: &str,
+    client_info: &ClientInfo,
+) -> Result<LoginResponse, MovieramaError> {
+    // Each state can only be used once
+    let login_state = sqlx::query!(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state = $1
+        AND provider = $2
+        AND created_at > NOW() - make_interval(mins => $3)
+        RETURNING code_verifier, nonce
+        "#,
+        state,
+        provider.name,
+        LOGIN_STATE_MINUTES,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::Unauthorized)?;
+
+    let client = http::client(PROVIDER_TIMEOUT);
+    let metadata = discover(&client, provider).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", provider.redirect_uri.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("code_verifier", login_state.code_verifier.as_str()),
+    ];
+    if let Some(secret) = &provider.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+
+    let tokens: TokenResponse = client
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)?;
+
+    let claims = validate_id_token(
+        &client,
+        provider,
+        &metadata,
+        &tokens.id_token,
+        &login_state.nonce,
+    )
+    .await?;
+
+    let user = find_or_create_user(pool, provider, &claims).await?;
+
+    auth_service::finish_login(pool, &user, client_info).await
+}
+
+async fn discover(
+    client: &reqwest::Client,
+    provider: &OidcProvider,
+) -> Result<ProviderMetadata, MovieramaError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)
+}
+
+async fn validate_id_token(
+    client: &reqwest::Client,
+    provider: &OidcProvider,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, MovieramaError> {
+    let header = decode_header(id_token).map_err(|_| MovieramaError::Unauthorized)?;
+
+    // The header is chosen by whoever made the token, so it must not pick the
+    // kind of key it is checked against
+    if !allowed_algorithms(provider, metadata).contains(&header.alg) {
+        return Err(MovieramaError::Unauthorized);
+    }
+
+    let key = match header.alg {
+        // Symmetric signatures are made with the client secret
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = provider
+                .client_secret
+                .as_ref()
+                .ok_or(MovieramaError::Unauthorized)?;
+            DecodingKey::from_secret(secret.as_bytes())
+        }
+        _ => {
+            let jwks_uri = metadata
+                .jwks_uri
+                .as_ref()
+                .ok_or(MovieramaError::Unauthorized)?;
+
+            let jwks: JwkSet = client
+                .get(jwks_uri)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(provider_error)?
+                .json()
+                .await
+                .map_err(provider_error)?;
+
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or(MovieramaError::Unauthorized)?;
+
+            DecodingKey::from_jwk(jwk).map_err(|_| MovieramaError::Unauthorized)?
+        }
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|_| MovieramaError::Unauthorized)?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(MovieramaError::Unauthorized);
+    }
+
+    Ok(claims)
+}
+
+/// The configured algorithm, or else the ones the provider advertises. RS256
+/// is the one every provider has to support, for those that advertise none.
+fn allowed_algorithms(provider: &OidcProvider, metadata: &ProviderMetadata) -> Vec<Algorithm> {
+    if let Some(alg) = provider.id_token_alg {
+        return vec![alg];
+    }
+
+    let advertised = &metadata.id_token_signing_alg_values_supported;
+    if advertised.is_empty() {
+        return vec![Algorithm::RS256];
+    }
+
+    // Unknown names, "none" among them, cannot be parsed and are left out
+    advertised
+        .iter()
+        .filter_map(|alg| alg.parse().ok())
+        .collect()
+}
+
+async fn find_or_create_user(
+    pool: &PgPool,
+    provider: &OidcProvider,
+    claims: &IdTokenClaims,
+) -> Result<User, MovieramaError> {
+    let linked = sqlx::query_as!(
+        User,
+        r#"
+        SELECT u.id, u.username, u.email, u.password
+        FROM user_identities i
+        JOIN users u ON u.id = i.user_id
+        WHERE i.provider = $1 AND i.subject = $2
+        "#,
+        provider.name,
+        claims.sub,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(user) = linked {
+        return Ok(user);
+    }
+
+    let Some(email) = &claims.email else {
+        return Err(MovieramaError::BadRequest(
+            "The login provider did not share an email address".to_owned(),
+        ));
+    };
+
+    let mut tx = pool.begin().await?;
+
+    let existing = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, username, email, password
+        FROM users
+        WHERE LOWER(email) = LOWER($1)
+        "#,
+        email,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user = match existing {
+        // Only link to an existing account when the provider vouches for the email
+        Some(user) if claims.email_verified == Some(true) => user,
+        Some(_) => {
+            return Err(MovieramaError::BadRequest(
+                "An account with this email already exists".to_owned(),
+            ));
+        }
+        None => {
+            // There is no way to pass an invite code through the provider
+            if invite_service::registration_policy()? != RegistrationPolicy::Open {
+                return Err(MovieramaError::Forbidden);
+            }
+
+            let username = unique_username(&mut tx, claims).await?;
+
+            sqlx::query_as!(
+                User,
+                r#"
+                INSERT INTO users (username, email, password)
+                VALUES ($1, $2, NULL)
+                RETURNING id, username, email, password
+                "#,
+                username,
+                email,
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_identities (user_id, provider, subject)
+        VALUES ($1, $2, $3)
+        "#,
+        user.id,
+        provider.name,
+        claims.sub,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+/// Derives a username from the provider's claims, appending a number when
+/// it is already taken.
+async fn unique_username(
+    conn: &mut PgConnection,
+    claims: &IdTokenClaims,
+) -> Result<String, MovieramaError> {
+    let base = [
+        claims.preferred_username.as_deref(),
+        claims.email.as_deref().and_then(|e| e.split('@').next()),
+        claims.name.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(sanitize_username)
+    .find(|u| !u.is_empty() && !account_service::is_reserved_username(u))
+    .unwrap_or_else(|| "user".to_owned());
+
+    let taken = sqlx::query_scalar!(
+        r#"
+        SELECT LOWER(username) AS "username!"
+        FROM users
+        WHERE LOWER(username) LIKE LOWER($1) || '%'
+        "#,
+        base,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let username = std::iter::once(base.clone())
+        .chain((1..).map(|n| format!("{}{}", base, n)))
+        .find(|candidate| !taken.contains(&candidate.to_lowercase()))
+        .unwrap();
+
+    Ok(username)
+}
+
+fn sanitize_username(raw: &str) -> String {
+    raw.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .take(MAX_USERNAME_LENGTH)
+        .collect()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn provider_error(e: reqwest::Error) -> MovieramaError {
+    MovieramaError::UnexpectedError(format!("Login provider request failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Claims, models::RegisterUser};
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::{Value, json};
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{body_string_contains, method, path},
+    };
+
+    const CLIENT_SECRET: &str = "client-secret";
+
+    async fn mock_provider() -> (MockServer, OidcProvider) {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let server = MockServer::start().await;
+
+        mount_discovery(&server).await;
+
+        let provider = OidcProvider {
+            name: "mock".into(),
+            issuer: server.uri(),
+            client_id: "movierama".into(),
+            client_secret: Some(CLIENT_SECRET.into()),
+            redirect_uri: "http://localhost:5173/oidc/callback".into(),
+            scopes: DEFAULT_SCOPES.into(),
+            id_token_alg: None,
+        };
+
+        (server, provider)
+    }
+
+    async fn mount_discovery(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": server.uri(),
+                "authorization_endpoint": format!("{}/authorize", server.uri()),
+                "token_endpoint": format!("{}/token", server.uri()),
+                "jwks_uri": format!("{}/jwks", server.uri()),
+                "id_token_signing_alg_values_supported": ["RS256", "HS256"],
+            })))
+            .mount(server)
+            .await;
+    }
+
+    /// Runs a full login against the mock provider, which answers the token
+    /// request with an ID token made of `extra_claims`.
+    async fn login(
+        pool: &PgPool,
+        server: &MockServer,
+        provider: &OidcProvider,
+        extra_claims: Value,
+    ) -> Result<LoginResponse, MovieramaError> {
+        let url = Url::parse(&authorization_url(pool, provider).await.unwrap()).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+                .unwrap()
+        };
+
+        assert_eq!(param("code_challenge_method"), "S256");
+
+        let mut claims = json!({
+            "iss": server.uri(),
+            "aud": provider.client_id,
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "nonce": param("nonce"),
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra_claims.as_object().unwrap().clone());
+
+        let id_token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+        )
+        .unwrap();
+
+        // Replace the token response of any previous login
+        server.reset().await;
+        mount_discovery(server).await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("code_verifier="))
+            .and(body_string_contains("code=auth-code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "provider-access-token",
+                "token_type": "Bearer",
+                "id_token": id_token,
+            })))
+            .mount(server)
+            .await;
+
+        complete_login(
+            pool,
+            provider,
+            "auth-code",
+            &param("state"),
+            &ClientInfo::default(),
+        )
+        .await
+    }
+
+    fn token_claims(resp: LoginResponse) -> Claims {
+        let token = match resp {
+            LoginResponse::Authenticated(auth) => auth.token,
+            LoginResponse::MfaRequired(_) => panic!("MFA is not enabled for this user"),
+        };
+
+        jsonwebtoken::decode::<Claims>(
+            &token,
+            &DecodingKey::from_secret("test-secret".as_bytes()),
+            &Validation::default(),
+        )
+        .unwrap()
+        .claims
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_first_login_creates_account(pool: PgPool) {
+        let (server, provider) = mock_provider().await;
+        let identity = json!({
+            "sub": "subject-1",
+            "email": "alice@example.com",
+            "preferred_username": "alice",
+        });
+
+        let first = token_claims(
+            login(&pool, &server, &provider, identity.clone())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(first.sub, "alice");
+
+        // The identity is linked, so the next login reuses the account
+        let second = token_claims(login(&pool, &server, &provider, identity).await.unwrap());
+        assert_eq!(second.user_id, first.user_id);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_username_collision(pool: PgPool) {
+        let (server, provider) = mock_provider().await;
+
+        auth_service::register_user(
+            &pool,
+            &RegisterUser {
+                username: "bob".into(),
+                email: "bob@mail.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let identity = json!({
+            "sub": "subject-2",
+            "email": "bob@example.com",
+            "preferred_username": "bob",
+        });
+
+        let claims = token_claims(login(&pool, &server, &provider, identity).await.unwrap());
+        assert_eq!(claims.sub, "bob1");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_existing_email_requires_verification(pool: PgPool) {
+        let (server, provider) = mock_provider().await;
+
+        auth_service::register_user(
+            &pool,
+            &RegisterUser {
+                username: "carol".into(),
+                email: "carol@example.com".into(),
+                password: "password".into(),
+                invite_code: None,
+            },
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let unverified = json!({ "sub": "subject-3", "email": "carol@example.com" });
+        let result = login(&pool, &server, &provider, unverified).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let verified = json!({
+            "sub": "subject-3",
+            "email": "carol@example.com",
+            "email_verified": true,
+        });
+        let claims = token_claims(login(&pool, &server, &provider, verified).await.unwrap());
+        assert_eq!(claims.sub, "carol");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_nonce_mismatch(pool: PgPool) {
+        let (server, provider) = mock_provider().await;
+        let identity = json!({
+            "sub": "subject-4",
+            "email": "dave@example.com",
+            "nonce": "not-the-nonce",
+        });
+
+        let result = login(&pool, &server, &provider, identity).await;
+
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_unknown_state(pool: PgPool) {
+        let (_server, provider) = mock_provider().await;
+
+        let result = complete_login(
+            &pool,
+            &provider,
+            "auth-code",
+            "forged-state",
+            &ClientInfo::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_unexpected_algorithm_is_rejected(pool: PgPool) {
+        let (server, mut provider) = mock_provider().await;
+        // The client secret is set, yet HS256 is not what this provider signs with
+        provider.id_token_alg = Some(Algorithm::RS256);
+        let identity = json!({ "sub": "subject-5", "email": "erin@example.com" });
+
+        let result = login(&pool, &server, &provider, identity).await;
+
+        assert!(matches!(result, Err(MovieramaError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_allowed_algorithms() {
+        let provider = |id_token_alg| OidcProvider {
+            name: "mock".into(),
+            issuer: "https://issuer".into(),
+            client_id: "movierama".into(),
+            client_secret: None,
+            redirect_uri: "http://localhost:5173/oidc/callback".into(),
+            scopes: DEFAULT_SCOPES.into(),
+            id_token_alg,
+        };
+        let metadata = |algs: &[&str]| ProviderMetadata {
+            issuer: "https://issuer".into(),
+            authorization_endpoint: "https://issuer/authorize".into(),
+            token_endpoint: "https://issuer/token".into(),
+            jwks_uri: None,
+            id_token_signing_alg_values_supported: algs.iter().map(|a| a.to_string()).collect(),
+        };
+
+        assert_eq!(
+            allowed_algorithms(&provider(None), &metadata(&["ES256", "none"])),
+            vec![Algorithm::ES256]
+        );
+        assert!(allowed_algorithms(&provider(None), &metadata(&["none"])).is_empty());
+        assert_eq!(
+            allowed_algorithms(&provider(None), &metadata(&[])),
+            vec![Algorithm::RS256]
+        );
+        assert_eq!(
+            allowed_algorithms(&provider(Some(Algorithm::PS256)), &metadata(&["RS256"])),
+            vec![Algorithm::PS256]
+        );
+    }
+}
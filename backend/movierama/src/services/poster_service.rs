@@ -0,0 +1,392 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::Movie,
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        movie_service,
+    },
+    storage::BlobStore,
+};
+use image::{ImageFormat, ImageReader, Limits, codecs::jpeg::JpegEncoder, imageops::FilterType};
+use serde_json::json;
+use sqlx::PgPool;
+use std::io::Cursor;
+use uuid::Uuid;
+
+const POSTER_MAX_BYTES: &str = "POSTER_MAX_BYTES";
+const DEFAULT_MAX_BYTES: usize = 5 * 1024 * 1024;
+
+/// Widths of the resized copies offered in the `srcset`
+pub const POSTER_WIDTHS: [u32; 4] = [160, 320, 640, 1280];
+
+const ACCEPTED_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+/// Larger images are refused before decoding, a small file can still
+/// decompress into a huge bitmap
+const MAX_DIMENSION: u32 = 8_000;
+const JPEG_QUALITY: u8 = 85;
+
+pub fn max_poster_bytes() -> usize {
+    std::env::var(POSTER_MAX_BYTES)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BYTES)
+}
+
+/// A resized copy of the poster, encoded as JPEG
+#[derive(Debug)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub data: Vec<u8>,
+}
+
+/// Replaces the poster of a movie with an uploaded image. Only the owner of
+/// the movie can change it. The image is stored in several widths, and the
+/// copies of the previous upload are removed.
+pub async fn upload_poster(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    user_id: i32,
+    movie_id: i32,
+    content_type: Option<&str>,
+    data: Vec<u8>,
+    ctx: &AuditContext,
+) -> Result<Movie, MovieramaError> {
+    let owner_id = sqlx::query_scalar!(
+        "SELECT user_id FROM movies WHERE id = $1 AND deleted_at IS NULL",
+        movie_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    if owner_id != user_id {
+        return Err(MovieramaError::Forbidden);
+    }
+
+    if data.len() > max_poster_bytes() {
+        return Err(MovieramaError::PayloadTooLarge);
+    }
+    if let Some(content_type) = content_type
+        && !ACCEPTED_TYPES.contains(&content_type)
+    {
+        return Err(MovieramaError::UnsupportedMediaType(format!(
+            "Posters must be one of {}",
+            ACCEPTED_TYPES.join(", ")
+        )));
+    }
+
+    // Decoding and resizing take a while, keep them off the async workers
+    let thumbnails = tokio::task::spawn_blocking(move || render_thumbnails(&data))
+        .await
+        .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))??;
+
+    let prefix = format!("posters/{}/{}", movie_id, Uuid::new_v4());
+    let mut keys = Vec::with_capacity(thumbnails.len());
+    let mut srcset = Vec::with_capacity(thumbnails.len());
+    for thumbnail in thumbnails {
+        let key = format!("{}/{}.jpg", prefix, thumbnail.width);
+        if let Err(e) = store.put(&key, "image/jpeg", thumbnail.data).await {
+            remove_blobs(store, &keys).await;
+            return Err(e);
+        }
+        srcset.push(format!("{} {}w", store.url(&key), thumbnail.width));
+        keys.push(key);
+    }
+
+    // The widest copy stands in for the poster itself
+    let poster_url = keys.last().map(|key| store.url(key));
+
+    let saved = save_poster(pool, movie_id, poster_url, srcset.join(", "), &keys, ctx).await;
+    match saved {
+        Ok(previous) => remove_blobs(store, &previous).await,
+        Err(e) => {
+            remove_blobs(store, &keys).await;
+            return Err(e);
+        }
+    }
+
+    movie_service::get_movie_by_id(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)
+}
+
+/// Points the movie at the new poster, returning the blobs of the old one
+async fn save_poster(
+    pool: &PgPool,
+    movie_id: i32,
+    poster_url: Option<String>,
+    srcset: String,
+    keys: &[String],
+    ctx: &AuditContext,
+) -> Result<Vec<String>, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let previous = sqlx::query!(
+        r#"
+        SELECT poster_url, poster_blobs
+        FROM movies
+        WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+        movie_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE movies
+        SET poster_url = $2, poster_srcset = $3, poster_blobs = $4, version = version + 1
+        WHERE id = $1
+        "#,
+        movie_id,
+        poster_url,
+        srcset,
+        keys,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "movie.poster",
+            target_type: "movie",
+            target_id: Some(movie_id.to_string()),
+            before: Some(json!({ "posterUrl": previous.poster_url })),
+            after: Some(json!({ "posterUrl": poster_url })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(previous.poster_blobs)
+}
+
+/// Removes stored files that nothing points at anymore. Failures are only
+/// logged, a leftover file does no harm.
+pub async fn remove_blobs(store: &dyn BlobStore, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = store.delete(key).await {
+            tracing::warn!("Failed to remove blob '{}': {}", key, e);
+        }
+    }
+}
+
+/// Decodes the image and resizes it to every width in [`POSTER_WIDTHS`] it
+/// is at least as wide as, plus its own width when that is smaller than the
+/// largest one. Narrowest first.
+pub fn render_thumbnails(data: &[u8]) -> Result<Vec<Thumbnail>, MovieramaError> {
+    let unsupported = || {
+        MovieramaError::UnsupportedMediaType(format!(
+            "Posters must be one of {}",
+            ACCEPTED_TYPES.join(", ")
+        ))
+    };
+
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| unsupported())?;
+    if !matches!(
+        reader.format(),
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+    ) {
+        return Err(unsupported());
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader
+        .decode()
+        .map_err(|e| MovieramaError::BadRequest(format!("Cannot read the image: {}", e)))?;
+
+    let largest = POSTER_WIDTHS[POSTER_WIDTHS.len() - 1];
+    let mut widths: Vec<u32> = POSTER_WIDTHS
+        .into_iter()
+        .filter(|&width| width <= image.width())
+        .collect();
+    if image.width() < largest {
+        widths.push(image.width());
+    }
+    widths.dedup();
+
+    widths
+        .into_iter()
+        .map(|width| {
+            let resized = image
+                .resize(width, u32::MAX, FilterType::Lanczos3)
+                .to_rgb8();
+
+            let mut data = Vec::new();
+            resized
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY))
+                .map_err(|e| MovieramaError::UnexpectedError(e.to_string()))?;
+
+            Ok(Thumbnail { width, data })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::NewMovie, storage::LocalStore};
+    use image::{ImageBuffer, Rgba};
+    use std::path::PathBuf;
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = ImageBuffer::from_pixel(width, height, Rgba([200u8, 40, 40, 255]));
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn local_store(name: &str) -> (LocalStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("movierama-{}-{}", name, std::process::id()));
+        let store = LocalStore {
+            root: root.clone(),
+            public_url: "/media".into(),
+        };
+        (store, root)
+    }
+
+    #[test]
+    fn test_render_thumbnails() {
+        let thumbnails = render_thumbnails(&png(400, 600)).unwrap();
+        let widths: Vec<u32> = thumbnails.iter().map(|t| t.width).collect();
+        assert_eq!(widths, vec![160, 320, 400]);
+
+        let decoded = image::load_from_memory(&thumbnails[0].data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (160, 240));
+
+        let widths: Vec<u32> = render_thumbnails(&png(2000, 100))
+            .unwrap()
+            .iter()
+            .map(|t| t.width)
+            .collect();
+        assert_eq!(widths, POSTER_WIDTHS.to_vec());
+
+        assert!(matches!(
+            render_thumbnails(b"GIF89a not a poster"),
+            Err(MovieramaError::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            render_thumbnails(&png(400, 600)[..100]),
+            Err(MovieramaError::BadRequest(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_upload_replaces_previous_poster(pool: PgPool) {
+        let owner_id = create_user(&pool, "owner").await;
+        let other_id = create_user(&pool, "other").await;
+        let movie = movie_service::create_movie(
+            &pool,
+            owner_id,
+            NewMovie {
+                title: "Vertigo".into(),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+
+        let (store, root) = local_store("posters");
+        let ctx = AuditContext::default();
+
+        let result =
+            upload_poster(&pool, &store, other_id, movie.id, None, png(200, 300), &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let result = upload_poster(
+            &pool,
+            &store,
+            owner_id,
+            movie.id,
+            Some("text/plain"),
+            png(200, 300),
+            &ctx,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(MovieramaError::UnsupportedMediaType(_))
+        ));
+
+        let first = upload_poster(
+            &pool,
+            &store,
+            owner_id,
+            movie.id,
+            Some("image/png"),
+            png(200, 300),
+            &ctx,
+        )
+        .await
+        .unwrap();
+        let first_url = first.poster_url.clone().unwrap();
+        assert!(first_url.starts_with("/media/posters/"));
+        assert!(first_url.ends_with("/200.jpg"));
+        assert_eq!(
+            first.poster_srcset.as_deref().unwrap().split(", ").count(),
+            2
+        );
+        assert_eq!(first.version, movie.version + 1);
+
+        let stored = root.join(first_url.trim_start_matches("/media/"));
+        assert!(stored.exists());
+
+        let second = upload_poster(&pool, &store, owner_id, movie.id, None, png(100, 150), &ctx)
+            .await
+            .unwrap();
+        let second_url = second.poster_url.clone().unwrap();
+        assert!(second_url.ends_with("/100.jpg"));
+        assert!(!stored.exists());
+
+        // Pointing the movie at another image drops the uploaded copies too
+        let stored = root.join(second_url.trim_start_matches("/media/"));
+        assert!(stored.exists());
+        let edited = movie_service::update_movie(
+            &pool,
+            &store,
+            owner_id,
+            movie.id,
+            NewMovie {
+                title: "Vertigo".into(),
+                poster_url: Some("https://images.test/vertigo.jpg".into()),
+                ..Default::default()
+            },
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+        assert!(edited.poster_srcset.is_none());
+        assert!(!stored.exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
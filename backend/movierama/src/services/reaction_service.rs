@@ -0,0 +1,305 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{NewReaction, Reaction, Role, UpdateReaction, VoteType},
+    services::{
+        audit_service::{self, AuditContext, NewAuditEvent},
+        user_service,
+    },
+};
+use serde_json::json;
+use sqlx::PgPool;
+
+const MAX_LABEL_LENGTH: usize = 50;
+
+/// Lists the reactions in display order, leaving out retired ones unless
+/// `include_retired` is set
+pub async fn list_reactions(
+    pool: &PgPool,
+    include_retired: bool,
+) -> Result<Vec<Reaction>, MovieramaError> {
+    let reactions = sqlx::query_as!(
+        Reaction,
+        r#"
+        SELECT code, label, emoji, position, active, created_at
+        FROM reactions
+        WHERE active OR $1
+        ORDER BY position, code
+        "#,
+        include_retired,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(reactions)
+}
+
+pub async fn create_reaction(
+    pool: &PgPool,
+    admin_id: i32,
+    data: &NewReaction,
+    ctx: &AuditContext,
+) -> Result<Reaction, MovieramaError> {
+    user_service::require_role(pool, admin_id, Role::Admin).await?;
+    let code: VoteType = data.code.parse()?;
+    let label = validate_label(&data.label)?;
+    let emoji = data
+        .emoji
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty());
+
+    let mut tx = pool.begin().await?;
+
+    let reaction = sqlx::query_as!(
+        Reaction,
+        r#"
+        INSERT INTO reactions (code, label, emoji, position)
+        VALUES ($1, $2, $3, $4)
+        RETURNING code, label, emoji, position, active, created_at
+        "#,
+        code.as_str(),
+        label,
+        emoji,
+        data.position,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(
+        |e| match e.as_database_error().and_then(|e| e.constraint()) {
+            Some("reactions_pkey") => MovieramaError::Conflict(format!(
+                "A reaction with code {} already exists",
+                code.as_str()
+            )),
+            _ => MovieramaError::DatabaseError(e),
+        },
+    )?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "reaction.create",
+            target_type: "reaction",
+            target_id: Some(reaction.code.clone()),
+            before: None,
+            after: Some(json!(reaction)),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(reaction)
+}
+
+/// Relabels, reorders, retires or brings back a reaction. Codes never change
+/// since votes refer to them.
+pub async fn update_reaction(
+    pool: &PgPool,
+    admin_id: i32,
+    code: &str,
+    data: &UpdateReaction,
+    ctx: &AuditContext,
+) -> Result<Reaction, MovieramaError> {
+    user_service::require_role(pool, admin_id, Role::Admin).await?;
+    let label = validate_label(&data.label)?;
+    let emoji = data
+        .emoji
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty());
+
+    let mut tx = pool.begin().await?;
+
+    let previous = sqlx::query_as!(
+        Reaction,
+        r#"
+        SELECT code, label, emoji, position, active, created_at
+        FROM reactions
+        WHERE code = $1
+        FOR UPDATE
+        "#,
+        code,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    let reaction = sqlx::query_as!(
+        Reaction,
+        r#"
+        UPDATE reactions
+        SET label = $2, emoji = $3, position = $4, active = $5
+        WHERE code = $1
+        RETURNING code, label, emoji, position, active, created_at
+        "#,
+        code,
+        label,
+        emoji,
+        data.position,
+        data.active,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "reaction.update",
+            target_type: "reaction",
+            target_id: Some(reaction.code.clone()),
+            before: Some(json!(previous)),
+            after: Some(json!(reaction)),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(reaction)
+}
+
+/// Fails unless the reaction exists and has not been retired
+pub async fn require_active(pool: &PgPool, vote_type: &VoteType) -> Result<(), MovieramaError> {
+    let active = sqlx::query_scalar!(
+        "SELECT active FROM reactions WHERE code = $1",
+        vote_type.as_str(),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if active != Some(true) {
+        return Err(MovieramaError::BadRequest(format!(
+            "Invalid vote type '{}'",
+            vote_type.as_str()
+        )));
+    }
+
+    Ok(())
+}
+
+fn validate_label(label: &str) -> Result<&str, MovieramaError> {
+    let label = label.trim();
+    if label.is_empty() || label.chars().count() > MAX_LABEL_LENGTH {
+        return Err(MovieramaError::BadRequest(format!(
+            "label must be between 1 and {} characters",
+            MAX_LABEL_LENGTH
+        )));
+    }
+
+    Ok(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::NewMovie,
+        services::{movie_service, vote_service},
+    };
+
+    async fn create_user(pool: &PgPool, username: &str, role: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password, role)
+            VALUES ($1, $1 || '@mail.com', NULL, $2)
+            RETURNING id
+            "#,
+            username,
+            role,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn rewatch() -> NewReaction {
+        NewReaction {
+            code: "re-watch".into(),
+            label: "Would re-watch".into(),
+            emoji: Some("🔁".into()),
+            position: 5,
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_custom_reaction_votes(pool: PgPool) {
+        let admin = create_user(&pool, "admin", "ADMIN").await;
+        let user = create_user(&pool, "user", "USER").await;
+        let ctx = AuditContext::default();
+
+        let result = create_reaction(&pool, user, &rewatch(), &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let reaction = create_reaction(&pool, admin, &rewatch(), &ctx)
+            .await
+            .unwrap();
+        assert_eq!(reaction.code, "RE-WATCH");
+        let result = create_reaction(&pool, admin, &rewatch(), &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Conflict(_))));
+
+        let movie_id = movie_service::create_movie(
+            &pool,
+            admin,
+            NewMovie {
+                title: "Heat".into(),
+                ..Default::default()
+            },
+            true,
+        )
+        .await
+        .unwrap()
+        .id;
+
+        vote_service::vote_movie(&pool, admin, movie_id, VoteType::like())
+            .await
+            .unwrap();
+        let movie = vote_service::vote_movie(&pool, user, movie_id, "re-watch".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(movie.like_count, 1);
+        assert_eq!(movie.hate_count, 0);
+        assert_eq!(movie.reactions.get("LIKE"), Some(&1));
+        assert_eq!(movie.reactions.get("RE-WATCH"), Some(&1));
+
+        // Retiring a reaction keeps its votes but stops new ones
+        let update = UpdateReaction {
+            label: "Would re-watch".into(),
+            emoji: None,
+            position: 5,
+            active: false,
+        };
+        update_reaction(&pool, admin, "RE-WATCH", &update, &ctx)
+            .await
+            .unwrap();
+
+        let result =
+            vote_service::vote_movie(&pool, admin, movie_id, "RE-WATCH".parse().unwrap()).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.reactions.get("RE-WATCH"), Some(&1));
+
+        let codes: Vec<String> = list_reactions(&pool, false)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.code)
+            .collect();
+        assert_eq!(codes, vec!["LIKE", "HATE"]);
+        assert_eq!(list_reactions(&pool, true).await.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_vote_type() {
+        assert_eq!("like".parse::<VoteType>().unwrap(), VoteType::like());
+        assert_eq!("RE_WATCH".parse::<VoteType>().unwrap().as_str(), "RE_WATCH");
+        assert!("".parse::<VoteType>().is_err());
+        assert!("1UP".parse::<VoteType>().is_err());
+        assert!("LOVE IT".parse::<VoteType>().is_err());
+    }
+}
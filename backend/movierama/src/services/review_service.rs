@@ -0,0 +1,323 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{NewReview, Review},
+    pagination::Pageable,
+};
+use sqlx::PgPool;
+
+const MOVIE_RATING_PRIOR_WEIGHT: &str = "MOVIE_RATING_PRIOR_WEIGHT";
+const DEFAULT_PRIOR_WEIGHT: f64 = 10.0;
+
+const MAX_REVIEW_LENGTH: usize = 10_000;
+
+/// The weight and mean rating every movie's average is pulled towards.
+/// The weight is how many reviews a movie needs before its own ratings
+/// count as much as everyone else's.
+pub async fn rating_prior(pool: &PgPool) -> Result<(f64, f64), MovieramaError> {
+    let weight = std::env::var(MOVIE_RATING_PRIOR_WEIGHT)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_PRIOR_WEIGHT);
+
+    // Only used for movies that have reviews, so the fallback never shows
+    let mean = sqlx::query_scalar!(
+        r#"SELECT COALESCE(AVG(rating_half_stars)::FLOAT8 / 2, 0) AS "mean!" FROM reviews"#
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((weight, mean))
+}
+
+/// Lists the reviews of a movie, newest first
+pub async fn list_reviews(
+    pool: &PgPool,
+    movie_id: i32,
+    pageable: &Pageable,
+) -> Result<(Vec<Review>, u64), MovieramaError> {
+    require_movie(pool, movie_id).await?;
+
+    let total_elements = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM reviews WHERE movie_id = $1"#,
+        movie_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let reviews = sqlx::query_as!(
+        Review,
+        r#"
+        SELECT
+            r.id,
+            r.movie_id,
+            u.username,
+            (r.rating_half_stars / 2.0)::REAL AS "rating!",
+            r.body,
+            r.spoiler,
+            r.created_at,
+            r.updated_at
+        FROM reviews r
+        JOIN users u ON u.id = r.user_id
+        WHERE r.movie_id = $1
+        ORDER BY r.updated_at DESC, r.id DESC
+        LIMIT $2 OFFSET $3
+        "#,
+        movie_id,
+        pageable.page_size as i64,
+        pageable.offset as i64,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok((reviews, total_elements as u64))
+}
+
+/// Saves the user's review of the movie, replacing the one they wrote before
+pub async fn save_review(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+    data: &NewReview,
+) -> Result<Review, MovieramaError> {
+    let half_stars = validate_rating(data.rating)?;
+    let body = validate_body(data.body.as_deref())?;
+    require_movie(pool, movie_id).await?;
+
+    let review = sqlx::query_as!(
+        Review,
+        r#"
+        WITH saved AS (
+            INSERT INTO reviews (movie_id, user_id, rating_half_stars, body, spoiler)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (movie_id, user_id) DO UPDATE
+            SET
+                rating_half_stars = EXCLUDED.rating_half_stars,
+                body = EXCLUDED.body,
+                spoiler = EXCLUDED.spoiler,
+                updated_at = NOW()
+            RETURNING *
+        )
+        SELECT
+            s.id,
+            s.movie_id,
+            u.username,
+            (s.rating_half_stars / 2.0)::REAL AS "rating!",
+            s.body,
+            s.spoiler,
+            s.created_at,
+            s.updated_at
+        FROM saved s
+        JOIN users u ON u.id = s.user_id
+        "#,
+        movie_id,
+        user_id,
+        half_stars,
+        body,
+        data.spoiler,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(review)
+}
+
+pub async fn delete_review(
+    pool: &PgPool,
+    user_id: i32,
+    movie_id: i32,
+) -> Result<(), MovieramaError> {
+    let rows_affected = sqlx::query!(
+        "DELETE FROM reviews WHERE movie_id = $1 AND user_id = $2",
+        movie_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Err(MovieramaError::NotFound);
+    }
+
+    Ok(())
+}
+
+async fn require_movie(pool: &PgPool, movie_id: i32) -> Result<(), MovieramaError> {
+    sqlx::query_scalar!(
+        "SELECT id FROM movies WHERE id = $1 AND deleted_at IS NULL",
+        movie_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)?;
+
+    Ok(())
+}
+
+/// Turns a rating in stars into half stars
+fn validate_rating(rating: f32) -> Result<i16, MovieramaError> {
+    let half_stars = rating * 2.0;
+    if !(1.0..=10.0).contains(&half_stars) || half_stars.fract() != 0.0 {
+        return Err(MovieramaError::BadRequest(
+            "rating must be between 0.5 and 5 stars, in steps of 0.5".to_owned(),
+        ));
+    }
+
+    Ok(half_stars as i16)
+}
+
+/// Trims the text and turns blank reviews into ratings without text
+fn validate_body(body: Option<&str>) -> Result<Option<&str>, MovieramaError> {
+    let body = body.map(str::trim).filter(|b| !b.is_empty());
+
+    if let Some(b) = body
+        && b.chars().count() > MAX_REVIEW_LENGTH
+    {
+        return Err(MovieramaError::BadRequest(format!(
+            "Reviews must be at most {} characters",
+            MAX_REVIEW_LENGTH
+        )));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::NewMovie,
+        pagination::Sort,
+        services::movie_service::{self, MovieFilter},
+    };
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn create_movie(pool: &PgPool, user_id: i32, title: &str) -> i32 {
+        let movie = NewMovie {
+            title: title.into(),
+            ..Default::default()
+        };
+        movie_service::create_movie(pool, user_id, movie, true)
+            .await
+            .unwrap()
+            .id
+    }
+
+    fn review(rating: f32) -> NewReview {
+        NewReview {
+            rating,
+            body: None,
+            spoiler: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_rating() {
+        assert_eq!(validate_rating(0.5).unwrap(), 1);
+        assert_eq!(validate_rating(3.5).unwrap(), 7);
+        assert_eq!(validate_rating(5.0).unwrap(), 10);
+        assert!(validate_rating(0.0).is_err());
+        assert!(validate_rating(3.3).is_err());
+        assert!(validate_rating(5.5).is_err());
+        assert!(validate_rating(f32::NAN).is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_one_review_per_user(pool: PgPool) {
+        let uid = create_user(&pool, "reviewer").await;
+        let movie_id = create_movie(&pool, uid, "Stalker").await;
+
+        let first = save_review(&pool, uid, movie_id, &review(3.0))
+            .await
+            .unwrap();
+        let updated = save_review(
+            &pool,
+            uid,
+            movie_id,
+            &NewReview {
+                rating: 4.5,
+                body: Some(" The zone changes everything ".into()),
+                spoiler: true,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(updated.id, first.id);
+        assert_eq!(updated.rating, 4.5);
+        assert_eq!(updated.body.as_deref(), Some("The zone changes everything"));
+        assert!(updated.spoiler);
+
+        let (reviews, total) =
+            list_reviews(&pool, movie_id, &Pageable::new(0, 10, Sort::from_query("")))
+                .await
+                .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(reviews[0].username, "reviewer");
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.rating_count, 1);
+        assert_eq!(movie.rating_histogram, vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+
+        delete_review(&pool, uid, movie_id).await.unwrap();
+        let result = delete_review(&pool, uid, movie_id).await;
+        assert!(matches!(result, Err(MovieramaError::NotFound)));
+
+        let movie = movie_service::get_movie_by_id(&pool, movie_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(movie.avg_rating, None);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_bayesian_average(pool: PgPool) {
+        let mut users = Vec::new();
+        for i in 0..10 {
+            users.push(create_user(&pool, &format!("user{}", i)).await);
+        }
+        let lucky = create_movie(&pool, users[0], "One Good Review").await;
+        let solid = create_movie(&pool, users[0], "Many Good Reviews").await;
+        let panned = create_movie(&pool, users[0], "Many Bad Reviews").await;
+
+        save_review(&pool, users[0], lucky, &review(5.0))
+            .await
+            .unwrap();
+        for &uid in &users {
+            save_review(&pool, uid, solid, &review(4.5)).await.unwrap();
+            save_review(&pool, uid, panned, &review(1.0)).await.unwrap();
+        }
+
+        // The mean of all 21 ratings is 60 / 21 stars
+        let mean = 60.0 / 21.0;
+        let lucky_movie = movie_service::get_movie_by_id(&pool, lucky)
+            .await
+            .unwrap()
+            .unwrap();
+        let expected = (10.0 * mean + 5.0) / 11.0;
+        assert!((lucky_movie.avg_rating.unwrap() - expected).abs() < 1e-9);
+
+        let pageable = Pageable::new(0, 10, Sort::from_query("avgRating,desc"));
+        let (movies, _) = movie_service::list_all_movies(&pool, &pageable, &MovieFilter::default())
+            .await
+            .unwrap();
+        let order: Vec<i32> = movies.iter().map(|m| m.id).collect();
+        assert_eq!(order, vec![solid, lucky, panned]);
+    }
+}
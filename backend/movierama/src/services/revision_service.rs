@@ -0,0 +1,340 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{DiffLine, DiffOp, Movie, MovieRevision, NewMovie, RevisionDiff},
+    services::{audit_service::AuditContext, movie_service},
+    storage::BlobStore,
+};
+use similar::{Algorithm, ChangeTag, TextDiff};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+
+const DIFF_DEADLINE: Duration = Duration::from_millis(500);
+
+/// Saves the movie's current title and description as its next revision.
+/// Callers hold the movie row locked, so revision numbers cannot clash.
+pub async fn record_revision(
+    conn: &mut PgConnection,
+    movie_id: i32,
+    data: &NewMovie,
+    editor_id: Option<i32>,
+) -> Result<i32, MovieramaError> {
+    let revision = sqlx::query_scalar!(
+        r#"
+        INSERT INTO movie_revisions (movie_id, revision, title, description, edited_by)
+        SELECT $1, COALESCE(MAX(revision), 0) + 1, $2, $3, $4
+        FROM movie_revisions
+        WHERE movie_id = $1
+        RETURNING revision
+        "#,
+        movie_id,
+        data.title,
+        data.description,
+        editor_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(revision)
+}
+
+/// Lists the revisions of a movie, newest first
+pub async fn list_revisions(
+    pool: &PgPool,
+    movie_id: i32,
+) -> Result<Vec<MovieRevision>, MovieramaError> {
+    require_movie(pool, movie_id).await?;
+
+    let revisions = sqlx::query_as!(
+        MovieRevision,
+        r#"
+        SELECT r.revision, r.title, r.description, u.username AS "edited_by?", r.edited_at
+        FROM movie_revisions r
+        LEFT JOIN users u ON u.id = r.edited_by
+        WHERE r.movie_id = $1
+        ORDER BY r.revision DESC
+        "#,
+        movie_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(revisions)
+}
+
+pub async fn get_revision(
+    pool: &PgPool,
+    movie_id: i32,
+    revision: i32,
+) -> Result<MovieRevision, MovieramaError> {
+    require_movie(pool, movie_id).await?;
+
+    sqlx::query_as!(
+        MovieRevision,
+        r#"
+        SELECT r.revision, r.title, r.description, u.username AS "edited_by?", r.edited_at
+        FROM movie_revisions r
+        LEFT JOIN users u ON u.id = r.edited_by
+        WHERE r.movie_id = $1 AND r.revision = $2
+        "#,
+        movie_id,
+        revision,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)
+}
+
+/// Compares revision `to` with revision `from`, or with the one before it
+/// when `from` is not given
+pub async fn diff_revisions(
+    pool: &PgPool,
+    movie_id: i32,
+    from: Option<i32>,
+    to: i32,
+) -> Result<RevisionDiff, MovieramaError> {
+    let new = get_revision(pool, movie_id, to).await?;
+
+    let from = from.or(Some(to - 1)).filter(|&revision| revision > 0);
+    let old = match from {
+        Some(revision) => Some(get_revision(pool, movie_id, revision).await?),
+        None => None,
+    };
+
+    let (old_title, old_description) = match &old {
+        Some(old) => (old.title.as_str(), old.description.as_deref()),
+        None => ("", None),
+    };
+
+    Ok(RevisionDiff {
+        from,
+        to,
+        title: diff_lines(old_title, &new.title),
+        description: diff_lines(
+            old_description.unwrap_or_default(),
+            new.description.as_deref().unwrap_or_default(),
+        ),
+    })
+}
+
+/// Restores the title and description of an earlier revision. This is an
+/// edit like any other and is saved as a new revision. Only the owner of
+/// the movie can revert it.
+pub async fn revert_to_revision(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    user_id: i32,
+    movie_id: i32,
+    revision: i32,
+    ctx: &AuditContext,
+) -> Result<Movie, MovieramaError> {
+    let owner_id = require_movie(pool, movie_id).await?;
+    if owner_id != user_id {
+        return Err(MovieramaError::Forbidden);
+    }
+
+    let target = get_revision(pool, movie_id, revision).await?;
+    let (current, current_version) = movie_service::get_editable(pool, movie_id)
+        .await?
+        .ok_or(MovieramaError::NotFound)?;
+
+    // Revisions only hold the text, the rest of the movie stays as it is
+    let data = NewMovie {
+        title: target.title,
+        description: target.description,
+        ..current
+    };
+    movie_service::update_movie(
+        pool,
+        store,
+        user_id,
+        movie_id,
+        data,
+        Some(current_version),
+        ctx,
+    )
+    .await
+}
+
+/// Returns the owner of the movie, failing for missing and deleted movies
+async fn require_movie(pool: &PgPool, movie_id: i32) -> Result<i32, MovieramaError> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM movies WHERE id = $1 AND deleted_at IS NULL",
+        movie_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(MovieramaError::NotFound)
+}
+
+/// Line based diff with Myers' algorithm, which needs memory in proportion
+/// to the texts only. Past [`DIFF_DEADLINE`] it settles for a diff that is
+/// correct but not the smallest one.
+fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    TextDiff::configure()
+        .algorithm(Algorithm::Myers)
+        .timeout(DIFF_DEADLINE)
+        .diff_slices(&old, &new)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            op: match change.tag() {
+                ChangeTag::Equal => DiffOp::Equal,
+                ChangeTag::Delete => DiffOp::Delete,
+                ChangeTag::Insert => DiffOp::Insert,
+            },
+            text: change.value().to_owned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStore;
+
+    /// None of these movies has an uploaded poster, so nothing is ever stored
+    fn no_blobs() -> LocalStore {
+        LocalStore {
+            root: std::env::temp_dir().join("movierama-no-blobs"),
+            public_url: "/media".into(),
+        }
+    }
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            VALUES ($1, $1 || '@mail.com', NULL)
+            RETURNING id
+            "#,
+            username,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn movie(title: &str, description: &str) -> NewMovie {
+        NewMovie {
+            title: title.into(),
+            description: Some(description.into()),
+            ..Default::default()
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_edits_are_kept_as_revisions(pool: PgPool) {
+        let owner_id = create_user(&pool, "author").await;
+        let editor_id = create_user(&pool, "editor").await;
+        sqlx::query!(
+            "UPDATE users SET role = 'MODERATOR' WHERE id = $1",
+            editor_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let created = movie_service::create_movie(&pool, owner_id, movie("Heat", "Cops"), false)
+            .await
+            .unwrap();
+
+        let ctx = AuditContext {
+            actor_id: Some(editor_id),
+            ..Default::default()
+        };
+        movie_service::update_movie(
+            &pool,
+            &no_blobs(),
+            editor_id,
+            created.id,
+            movie("Heat", "Cops\nRobbers"),
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+
+        let revisions = list_revisions(&pool, created.id).await.unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].revision, 2);
+        assert_eq!(revisions[0].edited_by.as_deref(), Some("editor"));
+        assert_eq!(revisions[1].description.as_deref(), Some("Cops"));
+
+        let diff = diff_revisions(&pool, created.id, None, 2).await.unwrap();
+        assert_eq!(diff.from, Some(1));
+        assert_eq!(diff.title[0].op, DiffOp::Equal);
+        assert_eq!(
+            diff.description,
+            vec![
+                DiffLine {
+                    op: DiffOp::Equal,
+                    text: "Cops".into()
+                },
+                DiffLine {
+                    op: DiffOp::Insert,
+                    text: "Robbers".into()
+                },
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_only_owner_can_revert(pool: PgPool) {
+        let owner_id = create_user(&pool, "owner").await;
+        let other_id = create_user(&pool, "other").await;
+        let created =
+            movie_service::create_movie(&pool, owner_id, movie("Ronin", "Original"), false)
+                .await
+                .unwrap();
+
+        let ctx = AuditContext::default();
+        movie_service::update_movie(
+            &pool,
+            &no_blobs(),
+            owner_id,
+            created.id,
+            movie("Ronin", "Rewritten"),
+            None,
+            &ctx,
+        )
+        .await
+        .unwrap();
+
+        let result = revert_to_revision(&pool, &no_blobs(), other_id, created.id, 1, &ctx).await;
+        assert!(matches!(result, Err(MovieramaError::Forbidden)));
+
+        let reverted = revert_to_revision(&pool, &no_blobs(), owner_id, created.id, 1, &ctx)
+            .await
+            .unwrap();
+        assert_eq!(reverted.description.as_deref(), Some("Original"));
+
+        // The revert is itself a revision
+        let latest = get_revision(&pool, created.id, 3).await.unwrap();
+        assert_eq!(latest.description.as_deref(), Some("Original"));
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let diff = diff_lines("a\nb\nc", "a\nc\nd");
+        let ops: Vec<_> = diff.iter().map(|l| (l.op, l.text.as_str())).collect();
+
+        assert_eq!(
+            ops,
+            vec![
+                (DiffOp::Equal, "a"),
+                (DiffOp::Delete, "b"),
+                (DiffOp::Equal, "c"),
+                (DiffOp::Insert, "d"),
+            ]
+        );
+        assert!(diff_lines("", "").is_empty());
+
+        // Long texts with nothing in common still diff line by line
+        let old = "a\n".repeat(10_000);
+        let new = "b\n".repeat(10_000);
+        let diff = diff_lines(&old, &new);
+        assert_eq!(diff.len(), 20_000);
+        assert!(diff.iter().all(|l| l.op != DiffOp::Equal));
+    }
+}
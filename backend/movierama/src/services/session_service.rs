@@ -0,0 +1,412 @@
+use crate::{
+    auth::ClientInfo,
+    exceptions::MovieramaError,
+    models::Session,
+    services::audit_service::{self, AuditContext, NewAuditEvent},
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::{FromRow, PgConnection, PgPool};
+
+/// How stale `last_seen_at` may get before a request refreshes it, so that
+/// not every authenticated request writes to the database
+const LAST_SEEN_RESOLUTION_SECS: f64 = 60.0;
+/// How long after signing in a session may still stand in for the password
+const RECENT_LOGIN_SECS: f64 = 5.0 * 60.0;
+
+#[derive(Debug, FromRow)]
+pub struct SessionRow {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+impl SessionRow {
+    fn into_session(self, current_session: Option<i32>) -> Session {
+        Session {
+            id: self.id,
+            device: self.user_agent.as_deref().and_then(describe_device),
+            user_agent: self.user_agent,
+            ip_address: self.ip_address,
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            current: current_session == Some(self.id),
+        }
+    }
+}
+
+pub async fn create_session(
+    conn: &mut PgConnection,
+    user_id: i32,
+    client: &ClientInfo,
+    expires_at: DateTime<Utc>,
+) -> Result<i32, MovieramaError> {
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO sessions (user_id, user_agent, ip_address, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+        user_id,
+        client.user_agent,
+        client.ip_address,
+        expires_at,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(id)
+}
+
+/// Checks that the session has been neither revoked nor expired, refreshing
+/// its last-seen time when it is out of date.
+pub async fn is_active(
+    pool: &PgPool,
+    session_id: i32,
+    user_id: i32,
+) -> Result<bool, MovieramaError> {
+    let session = sqlx::query!(
+        r#"
+        SELECT last_seen_at < NOW() - make_interval(secs => $3) AS "stale!"
+        FROM sessions
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > NOW()
+        "#,
+        session_id,
+        user_id,
+        LAST_SEEN_RESOLUTION_SECS,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(session) = session else {
+        return Ok(false);
+    };
+
+    if session.stale {
+        sqlx::query!(
+            "UPDATE sessions SET last_seen_at = NOW() WHERE id = $1",
+            session_id,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(true)
+}
+
+/// Tells whether the session was started within the last few minutes, for
+/// confirming sensitive changes on accounts without a password.
+pub async fn is_recent(
+    pool: &PgPool,
+    session_id: i32,
+    user_id: i32,
+) -> Result<bool, MovieramaError> {
+    let recent = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM sessions
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            AND created_at > NOW() - make_interval(secs => $3)
+        ) AS "recent!"
+        "#,
+        session_id,
+        user_id,
+        RECENT_LOGIN_SECS,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(recent)
+}
+
+pub async fn list_sessions(
+    pool: &PgPool,
+    user_id: i32,
+    current_session: Option<i32>,
+) -> Result<Vec<Session>, MovieramaError> {
+    let sessions = sqlx::query_as!(
+        SessionRow,
+        r#"
+        SELECT id, user_agent, ip_address, created_at, last_seen_at
+        FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ORDER BY last_seen_at DESC
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.into_session(current_session))
+    .collect();
+
+    Ok(sessions)
+}
+
+pub async fn revoke_session(
+    pool: &PgPool,
+    user_id: i32,
+    session_id: i32,
+    ctx: &AuditContext,
+) -> Result<bool, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+        "#,
+        session_id,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    audit_service::record(
+        &mut tx,
+        ctx,
+        NewAuditEvent {
+            action: "session.revoke",
+            target_type: "session",
+            target_id: Some(session_id.to_string()),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Signs the user out everywhere except the given session, returning how
+/// many sessions were revoked.
+pub async fn revoke_other_sessions(
+    pool: &PgPool,
+    user_id: i32,
+    current_session: i32,
+    ctx: &AuditContext,
+) -> Result<u64, MovieramaError> {
+    let mut tx = pool.begin().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = NOW()
+        WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL
+        "#,
+        user_id,
+        current_session,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected > 0 {
+        audit_service::record(
+            &mut tx,
+            ctx,
+            NewAuditEvent {
+                action: "session.revoke_others",
+                target_type: "user",
+                target_id: Some(user_id.to_string()),
+                before: None,
+                after: Some(json!({ "kept": current_session, "revoked": rows_affected })),
+            },
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(rows_affected)
+}
+
+/// Gives a short description such as "Firefox on Linux" for the common
+/// browsers and platforms
+pub fn describe_device(user_agent: &str) -> Option<String> {
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+
+    match (browser, os) {
+        (Some(browser), Some(os)) => Some(format!("{} on {}", browser, os)),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_owned()),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Claims,
+        models::{LoginResponse, LoginUser, RegisterUser},
+        services::auth_service,
+    };
+
+    fn client(user_agent: &str) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.into()),
+            ip_address: Some("203.0.113.7".into()),
+            request_id: None,
+        }
+    }
+
+    fn decode(token: &str) -> Claims {
+        jsonwebtoken::decode::<Claims>(
+            token,
+            &jsonwebtoken::DecodingKey::from_secret("test-secret".as_bytes()),
+            &jsonwebtoken::Validation::default(),
+        )
+        .unwrap()
+        .claims
+    }
+
+    async fn login(pool: &PgPool, user_agent: &str) -> Claims {
+        let resp = auth_service::login_user(
+            pool,
+            &LoginUser {
+                username: "traveller".into(),
+                password: "password".into(),
+            },
+            &client(user_agent),
+        )
+        .await
+        .unwrap();
+
+        match resp {
+            LoginResponse::Authenticated(auth) => decode(&auth.token),
+            LoginResponse::MfaRequired(_) => panic!("MFA is not enabled for this user"),
+        }
+    }
+
+    async fn register(pool: &PgPool) -> Claims {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let reg = RegisterUser {
+            username: "traveller".into(),
+            email: "traveller@mail.com".into(),
+            password: "password".into(),
+            invite_code: None,
+        };
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        decode(&auth.token)
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_list_sessions(pool: PgPool) {
+        register(&pool).await;
+        let laptop = login(
+            &pool,
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
+        )
+        .await;
+
+        let sessions = list_sessions(&pool, laptop.user_id, laptop.sid)
+            .await
+            .unwrap();
+
+        assert_eq!(sessions.len(), 2);
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(Some(current.id), laptop.sid);
+        assert_eq!(current.device.as_deref(), Some("Firefox on Linux"));
+        assert_eq!(current.ip_address.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_revoked_session_is_inactive(pool: PgPool) {
+        let registered = register(&pool).await;
+        let sid = registered.sid.unwrap();
+
+        assert!(is_active(&pool, sid, registered.user_id).await.unwrap());
+        // Sessions cannot be checked or revoked on behalf of another user
+        assert!(!is_active(&pool, sid, registered.user_id + 1).await.unwrap());
+        assert!(
+            !revoke_session(&pool, registered.user_id + 1, sid, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+
+        assert!(
+            revoke_session(&pool, registered.user_id, sid, &AuditContext::default())
+                .await
+                .unwrap()
+        );
+        assert!(!is_active(&pool, sid, registered.user_id).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_revoke_other_sessions(pool: PgPool) {
+        let first = register(&pool).await;
+        login(&pool, "curl/8.5.0").await;
+        let current = login(&pool, "curl/8.5.0").await;
+
+        let revoked = revoke_other_sessions(
+            &pool,
+            current.user_id,
+            current.sid.unwrap(),
+            &AuditContext::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(revoked, 2);
+        assert!(
+            !is_active(&pool, first.sid.unwrap(), first.user_id)
+                .await
+                .unwrap()
+        );
+        assert!(
+            is_active(&pool, current.sid.unwrap(), current.user_id)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_describe_device() {
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1";
+        let edge = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36 Edg/129.0.0.0";
+
+        assert_eq!(describe_device(iphone).as_deref(), Some("Safari on iOS"));
+        assert_eq!(describe_device(edge).as_deref(), Some("Edge on Windows"));
+        assert_eq!(describe_device("curl/8.5.0").as_deref(), Some("curl"));
+        assert_eq!(describe_device("Mystery/1.0"), None);
+    }
+}
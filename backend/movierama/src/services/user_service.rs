@@ -0,0 +1,293 @@
+use crate::{
+    exceptions::MovieramaError,
+    models::{Role, UpdateProfile, UserProfile},
+};
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgConnection, PgPool};
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+
+#[derive(Debug, FromRow)]
+pub struct ProfileRow {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub joined_at: DateTime<Utc>,
+    pub movie_count: i64,
+    pub likes_received: i64,
+    pub hates_received: i64,
+}
+
+impl From<ProfileRow> for UserProfile {
+    fn from(r: ProfileRow) -> Self {
+        UserProfile {
+            username: r.username,
+            display_name: r.display_name,
+            bio: r.bio,
+            avatar_url: r.avatar_url,
+            joined_at: r.joined_at,
+            movie_count: r.movie_count as u64,
+            likes_received: r.likes_received as u64,
+            hates_received: r.hates_received as u64,
+        }
+    }
+}
+
+pub async fn get_profile_by_username(
+    pool: &PgPool,
+    username: &str,
+) -> Result<Option<UserProfile>, MovieramaError> {
+    let profile = sqlx::query_as!(
+        ProfileRow,
+        r#"
+        SELECT
+            u.username,
+            u.display_name,
+            u.bio,
+            u.avatar_url,
+            u.created_at AS joined_at,
+            (SELECT COUNT(*) FROM movies m WHERE m.user_id = u.id AND m.deleted_at IS NULL) AS "movie_count!: i64",
+            (
+                SELECT COALESCE(SUM(m.likes), 0)
+                FROM movies m
+                WHERE m.user_id = u.id AND m.deleted_at IS NULL
+            ) AS "likes_received!: i64",
+            (
+                SELECT COALESCE(SUM(m.hates), 0)
+                FROM movies m
+                WHERE m.user_id = u.id AND m.deleted_at IS NULL
+            ) AS "hates_received!: i64"
+        FROM users u
+        WHERE LOWER(u.username) = LOWER($1)
+        "#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(UserProfile::from);
+
+    Ok(profile)
+}
+
+pub async fn get_profile_by_id(pool: &PgPool, user_id: i32) -> Result<UserProfile, MovieramaError> {
+    let username = sqlx::query_scalar!("SELECT username FROM users WHERE id = $1", user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(MovieramaError::NotFound)?;
+
+    get_profile_by_username(pool, &username)
+        .await?
+        .ok_or(MovieramaError::NotFound)
+}
+
+pub async fn update_profile(
+    pool: &PgPool,
+    user_id: i32,
+    data: UpdateProfile,
+) -> Result<UserProfile, MovieramaError> {
+    let display_name = normalize(data.display_name, "displayName", MAX_DISPLAY_NAME_LENGTH)?;
+    let bio = normalize(data.bio, "bio", MAX_BIO_LENGTH)?;
+    let avatar_url = normalize(data.avatar_url, "avatarUrl", MAX_AVATAR_URL_LENGTH)?;
+
+    if let Some(Some(url)) = &avatar_url
+        && !(url.starts_with("https://") || url.starts_with("http://"))
+    {
+        return Err(MovieramaError::BadRequest(
+            "avatarUrl must be an http(s) URL".to_owned(),
+        ));
+    }
+
+    // The flags tell apart fields to keep from fields to set (possibly to NULL)
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET
+            display_name = CASE WHEN $2 THEN $3 ELSE display_name END,
+            bio = CASE WHEN $4 THEN $5 ELSE bio END,
+            avatar_url = CASE WHEN $6 THEN $7 ELSE avatar_url END
+        WHERE id = $1
+        "#,
+        user_id,
+        display_name.is_some(),
+        display_name.flatten(),
+        bio.is_some(),
+        bio.flatten(),
+        avatar_url.is_some(),
+        avatar_url.flatten(),
+    )
+    .execute(pool)
+    .await?;
+
+    get_profile_by_id(pool, user_id).await
+}
+
+pub async fn get_role(pool: &PgPool, user_id: i32) -> Result<Role, MovieramaError> {
+    let mut conn = pool.acquire().await?;
+    role_of(&mut conn, user_id).await
+}
+
+/// Like [`get_role`], on a connection the caller already holds
+pub async fn role_of(conn: &mut PgConnection, user_id: i32) -> Result<Role, MovieramaError> {
+    sqlx::query_scalar!(
+        r#"SELECT role AS "role: Role" FROM users WHERE id = $1"#,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(MovieramaError::NotFound)
+}
+
+/// Rejects users below the given role
+pub async fn require_role(pool: &PgPool, user_id: i32, role: Role) -> Result<(), MovieramaError> {
+    if get_role(pool, user_id).await? < role {
+        return Err(MovieramaError::Forbidden);
+    }
+
+    Ok(())
+}
+
+/// Trims the value and turns blank strings into `null`
+fn normalize(
+    field: Option<Option<String>>,
+    name: &str,
+    max_length: usize,
+) -> Result<Option<Option<String>>, MovieramaError> {
+    let Some(value) = field else {
+        return Ok(None);
+    };
+
+    let value = value.map(|v| v.trim().to_owned()).filter(|v| !v.is_empty());
+
+    if let Some(v) = &value
+        && v.chars().count() > max_length
+    {
+        return Err(MovieramaError::BadRequest(format!(
+            "{} must be at most {} characters",
+            name, max_length
+        )));
+    }
+
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ClientInfo;
+    use crate::models::{NewMovie, RegisterUser, VoteType};
+    use crate::services::{auth_service, movie_service, vote_service};
+
+    async fn create_user(pool: &PgPool, username: &str) -> i32 {
+        unsafe {
+            std::env::set_var("JWT_SECRET", "test-secret");
+        }
+
+        let reg = RegisterUser {
+            username: username.into(),
+            email: format!("{}@mail.com", username),
+            password: "password".into(),
+            invite_code: None,
+        };
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        jsonwebtoken::decode::<crate::auth::Claims>(
+            &auth.token,
+            &jsonwebtoken::DecodingKey::from_secret("test-secret".as_bytes()),
+            &jsonwebtoken::Validation::default(),
+        )
+        .unwrap()
+        .claims
+        .user_id
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_get_profile_counts(pool: PgPool) {
+        let owner = create_user(&pool, "director").await;
+        let fan = create_user(&pool, "fan").await;
+        let critic = create_user(&pool, "critic").await;
+
+        let movie = movie_service::create_movie(
+            &pool,
+            owner,
+            NewMovie {
+                title: "Profile Movie".into(),
+                description: None,
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+
+        vote_service::insert_vote(&pool, fan, movie.id, VoteType::like())
+            .await
+            .unwrap();
+        vote_service::insert_vote(&pool, critic, movie.id, VoteType::hate())
+            .await
+            .unwrap();
+
+        let profile = get_profile_by_username(&pool, "director")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(profile.movie_count, 1);
+        assert_eq!(profile.likes_received, 1);
+        assert_eq!(profile.hates_received, 1);
+
+        let json = serde_json::to_value(&profile).unwrap();
+        assert!(json.get("email").is_none());
+        assert!(json.get("password").is_none());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_get_profile_not_found(pool: PgPool) {
+        let profile = get_profile_by_username(&pool, "nobody").await.unwrap();
+
+        assert!(profile.is_none());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_update_profile_partial(pool: PgPool) {
+        let uid = create_user(&pool, "editor").await;
+
+        let update: UpdateProfile = serde_json::from_str(
+            r#"{"displayName": " Ed ", "bio": "Film buff", "avatarUrl": "https://img/a.png"}"#,
+        )
+        .unwrap();
+        let profile = update_profile(&pool, uid, update).await.unwrap();
+        assert_eq!(profile.display_name, Some("Ed".into()));
+        assert_eq!(profile.bio, Some("Film buff".into()));
+
+        // Absent fields are kept, null clears
+        let update: UpdateProfile = serde_json::from_str(r#"{"bio": null}"#).unwrap();
+        let profile = update_profile(&pool, uid, update).await.unwrap();
+        assert_eq!(profile.display_name, Some("Ed".into()));
+        assert_eq!(profile.bio, None);
+        assert_eq!(profile.avatar_url, Some("https://img/a.png".into()));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_update_profile_validation(pool: PgPool) {
+        let uid = create_user(&pool, "invalid").await;
+
+        let update = UpdateProfile {
+            avatar_url: Some(Some("javascript:alert(1)".into())),
+            ..Default::default()
+        };
+        let result = update_profile(&pool, uid, update).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+
+        let update = UpdateProfile {
+            display_name: Some(Some("x".repeat(MAX_DISPLAY_NAME_LENGTH + 1))),
+            ..Default::default()
+        };
+        let result = update_profile(&pool, uid, update).await;
+        assert!(matches!(result, Err(MovieramaError::BadRequest(_))));
+    }
+}
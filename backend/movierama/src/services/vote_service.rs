@@ -5,7 +5,7 @@ use sqlx::{FromRow, PgPool};
 use crate::{
     exceptions::MovieramaError,
     models::{Movie, VoteType},
-    services::movie_service,
+    services::{movie_service, reaction_service},
 };
 
 #[derive(Debug, FromRow)]
@@ -33,15 +33,17 @@ pub async fn vote_movie(
     match get_vote(pool, user_id, movie_id).await? {
         Some(vtype) => {
             if vtype == vote_type {
-                // Retract vote
+                // Retract vote, even one cast with a since retired reaction
                 delete_vote(pool, user_id, movie_id).await?;
             } else {
                 // Reverse vote
+                reaction_service::require_active(pool, &vote_type).await?;
                 update_vote(pool, user_id, movie_id, vote_type).await?;
             }
         }
         // Simple vote
         None => {
+            reaction_service::require_active(pool, &vote_type).await?;
             insert_vote(pool, user_id, movie_id, vote_type).await?;
         }
     }
@@ -100,12 +102,12 @@ pub async fn insert_vote(
 ) -> Result<(), MovieramaError> {
     sqlx::query!(
         r#"
-        INSERT INTO votes (movie_id, user_id, type) 
-        VALUES ($1, $2, $3)
+        INSERT INTO votes (movie_id, user_id, type, created_at, updated_at)
+        VALUES ($1, $2, $3, NOW(), NOW())
         "#,
         movie_id,
         user_id,
-        vote_type as VoteType,
+        vote_type.as_str(),
     )
     .execute(pool)
     .await?;
@@ -121,12 +123,12 @@ pub async fn update_vote(
 ) -> Result<(), MovieramaError> {
     sqlx::query!(
         r#"
-        UPDATE votes SET type = $3
+        UPDATE votes SET type = $3, updated_at = NOW()
         WHERE user_id = $1 AND movie_id = $2
         "#,
         user_id,
         movie_id,
-        vote_type as VoteType,
+        vote_type.as_str(),
     )
     .execute(pool)
     .await?;
@@ -183,6 +185,7 @@ pub async fn get_user_votes_for_movies(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::ClientInfo;
     use crate::models::{NewMovie, RegisterUser};
     use crate::services::{auth_service, movie_service};
     use sqlx::PgPool;
@@ -196,8 +199,11 @@ mod tests {
             username: username.into(),
             email: format!("{}@mail.com", username),
             password: "password".into(),
+            invite_code: None,
         };
-        let auth = auth_service::register_user(pool, &reg).await.unwrap();
+        let auth = auth_service::register_user(pool, &reg, &ClientInfo::default())
+            .await
+            .unwrap();
 
         let claims = jsonwebtoken::decode::<crate::auth::Claims>(
             &auth.token,
@@ -217,7 +223,9 @@ mod tests {
             NewMovie {
                 title: title.into(),
                 description: Some("desc".into()),
+                ..Default::default()
             },
+            false,
         )
         .await
         .unwrap();
@@ -231,7 +239,7 @@ mod tests {
         let mid = create_movie(&pool, uid, "movie1").await;
 
         // Add LIKE vote
-        let result = vote_movie(&pool, uid, mid, VoteType::Like).await.unwrap();
+        let result = vote_movie(&pool, uid, mid, VoteType::like()).await.unwrap();
 
         assert_eq!(result.like_count, 1);
         assert_eq!(result.hate_count, 0);
@@ -243,10 +251,10 @@ mod tests {
         let mid = create_movie(&pool, uid, "movie2").await;
 
         // First LIKE
-        vote_movie(&pool, uid, mid, VoteType::Like).await.unwrap();
+        vote_movie(&pool, uid, mid, VoteType::like()).await.unwrap();
 
         // Then switch to HATE
-        let updated = vote_movie(&pool, uid, mid, VoteType::Hate).await.unwrap();
+        let updated = vote_movie(&pool, uid, mid, VoteType::hate()).await.unwrap();
 
         assert_eq!(updated.like_count, 0);
         assert_eq!(updated.hate_count, 1);
@@ -258,10 +266,10 @@ mod tests {
         let mid = create_movie(&pool, uid, "movie3").await;
 
         // First LIKE
-        vote_movie(&pool, uid, mid, VoteType::Like).await.unwrap();
+        vote_movie(&pool, uid, mid, VoteType::like()).await.unwrap();
 
         // Like again → retract (remove vote)
-        let updated = vote_movie(&pool, uid, mid, VoteType::Like).await.unwrap();
+        let updated = vote_movie(&pool, uid, mid, VoteType::like()).await.unwrap();
 
         assert_eq!(updated.like_count, 0);
         assert_eq!(updated.hate_count, 0);
@@ -271,16 +279,66 @@ mod tests {
         assert!(v.is_none());
     }
 
+    #[sqlx::test(migrations = "./migrations")]
+    async fn test_concurrent_votes_are_all_counted(pool: PgPool) {
+        let owner = create_user(&pool, "owner").await;
+        let mid = create_movie(&pool, owner, "movie4").await;
+
+        let voters: Vec<i32> = sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (username, email, password)
+            SELECT 'voter' || n, 'voter' || n || '@mail.com', NULL
+            FROM generate_series(1, 20) n
+            RETURNING id
+            "#,
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        let votes = voters.iter().enumerate().map(|(i, &uid)| {
+            let pool = pool.clone();
+            let vote_type = if i % 4 == 0 {
+                VoteType::hate()
+            } else {
+                VoteType::like()
+            };
+            tokio::spawn(async move { insert_vote(&pool, uid, mid, vote_type).await })
+        });
+        for vote in votes.collect::<Vec<_>>() {
+            vote.await.unwrap().unwrap();
+        }
+
+        let counts = sqlx::query!("SELECT likes, hates FROM movies WHERE id = $1", mid)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((counts.likes, counts.hates), (15, 5));
+
+        // Switching and retracting adjust the counts just the same
+        update_vote(&pool, voters[1], mid, VoteType::hate())
+            .await
+            .unwrap();
+        delete_vote(&pool, voters[0], mid).await.unwrap();
+        let counts = sqlx::query!("SELECT likes, hates FROM movies WHERE id = $1", mid)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((counts.likes, counts.hates), (14, 5));
+    }
+
     #[sqlx::test(migrations = "./migrations")]
     async fn test_get_vote(pool: PgPool) {
         let uid = create_user(&pool, "getv").await;
         let mid = create_movie(&pool, uid, "movie4").await;
 
-        insert_vote(&pool, uid, mid, VoteType::Hate).await.unwrap();
+        insert_vote(&pool, uid, mid, VoteType::hate())
+            .await
+            .unwrap();
 
         let vote = get_vote(&pool, uid, mid).await.unwrap();
 
-        assert_eq!(vote, Some(VoteType::Hate));
+        assert_eq!(vote, Some(VoteType::hate()));
     }
 
     #[sqlx::test(migrations = "./migrations")]
@@ -292,23 +350,23 @@ mod tests {
         let m3 = create_movie(&pool, uid, "m3").await;
 
         // Votes:
-        insert_vote(&pool, uid, m1, VoteType::Like).await.unwrap();
-        insert_vote(&pool, uid, m3, VoteType::Hate).await.unwrap();
+        insert_vote(&pool, uid, m1, VoteType::like()).await.unwrap();
+        insert_vote(&pool, uid, m3, VoteType::hate()).await.unwrap();
 
         let results = get_user_votes_for_movies(&pool, uid, &[m1, m2, m3])
             .await
             .unwrap();
 
-        assert_eq!(results.get(&m1), Some(&VoteType::Like));
+        assert_eq!(results.get(&m1), Some(&VoteType::like()));
         assert_eq!(results.get(&m2), None);
-        assert_eq!(results.get(&m3), Some(&VoteType::Hate));
+        assert_eq!(results.get(&m3), Some(&VoteType::hate()));
     }
 
     #[sqlx::test(migrations = "./migrations")]
     async fn test_vote_movie_not_found(pool: PgPool) {
         let uid = create_user(&pool, "nofound").await;
 
-        let result = vote_movie(&pool, uid, 99999, VoteType::Like).await;
+        let result = vote_movie(&pool, uid, 99999, VoteType::like()).await;
 
         assert!(matches!(result, Err(MovieramaError::NotFound)));
     }
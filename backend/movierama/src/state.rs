@@ -0,0 +1,30 @@
+use crate::{metadata::MetadataProvider, storage::BlobStore};
+use axum::extract::FromRef;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+/// Shared by all handlers, which extract just the parts they need
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub metadata: Arc<dyn MetadataProvider>,
+    pub blobs: Arc<dyn BlobStore>,
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> PgPool {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn MetadataProvider> {
+    fn from_ref(state: &AppState) -> Arc<dyn MetadataProvider> {
+        state.metadata.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn BlobStore> {
+    fn from_ref(state: &AppState) -> Arc<dyn BlobStore> {
+        state.blobs.clone()
+    }
+}
@@ -0,0 +1,401 @@
+use crate::{exceptions::MovieramaError, http};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{
+    future::Future,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+/// `local` (default) or `s3`
+const STORAGE_BACKEND: &str = "STORAGE_BACKEND";
+/// Where the `local` backend keeps its files
+const STORAGE_LOCAL_DIR: &str = "STORAGE_LOCAL_DIR";
+/// Base of the URLs handed out to clients, e.g. a CDN in front of the bucket
+const STORAGE_PUBLIC_URL: &str = "STORAGE_PUBLIC_URL";
+/// Any S3-compatible service, MinIO included
+const S3_ENDPOINT: &str = "S3_ENDPOINT";
+const S3_BUCKET: &str = "S3_BUCKET";
+const S3_REGION: &str = "S3_REGION";
+const S3_ACCESS_KEY_ID: &str = "S3_ACCESS_KEY_ID";
+const S3_SECRET_ACCESS_KEY: &str = "S3_SECRET_ACCESS_KEY";
+
+const DEFAULT_LOCAL_DIR: &str = "uploads";
+const DEFAULT_REGION: &str = "us-east-1";
+/// Long enough to upload a poster over a slow link
+const S3_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the API serves the files of the `local` backend
+pub const LOCAL_MEDIA_PATH: &str = "/media";
+
+pub type BlobFuture<'a> = Pin<Box<dyn Future<Output = Result<(), MovieramaError>> + Send + 'a>>;
+
+/// Stores uploaded files under keys like `posters/12/abc/320.jpg`
+pub trait BlobStore: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, content_type: &'a str, data: Vec<u8>) -> BlobFuture<'a>;
+
+    /// Deleting a missing blob is not an error
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a>;
+
+    /// The address clients download the blob from
+    fn url(&self, key: &str) -> String;
+}
+
+/// Picks the backend configured through `STORAGE_BACKEND`
+pub fn store_from_env() -> Result<Arc<dyn BlobStore>, MovieramaError> {
+    let setting = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let required = |name: &str| {
+        setting(name)
+            .ok_or_else(|| MovieramaError::UnexpectedError(format!("{} must be set", name)))
+    };
+
+    match setting(STORAGE_BACKEND).as_deref() {
+        None | Some("local") => Ok(Arc::new(LocalStore {
+            root: local_dir(),
+            public_url: setting(STORAGE_PUBLIC_URL).unwrap_or_else(|| LOCAL_MEDIA_PATH.to_owned()),
+        })),
+        Some("s3") => {
+            let endpoint = required(S3_ENDPOINT)?;
+            let bucket = required(S3_BUCKET)?;
+            let public_url = setting(STORAGE_PUBLIC_URL)
+                .unwrap_or_else(|| format!("{}/{}", endpoint.trim_end_matches('/'), bucket));
+
+            Ok(Arc::new(S3Store {
+                client: http::client(S3_TIMEOUT),
+                endpoint,
+                bucket,
+                region: setting(S3_REGION).unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+                access_key_id: required(S3_ACCESS_KEY_ID)?,
+                secret_access_key: required(S3_SECRET_ACCESS_KEY)?,
+                public_url,
+            }))
+        }
+        Some(other) => Err(MovieramaError::UnexpectedError(format!(
+            "Unknown storage backend '{}', expected 'local' or 's3'",
+            other
+        ))),
+    }
+}
+
+/// The directory the API has to serve under [`LOCAL_MEDIA_PATH`], when
+/// files are kept on the local filesystem
+pub fn served_dir_from_env() -> Option<PathBuf> {
+    match std::env::var(STORAGE_BACKEND).ok().as_deref() {
+        None | Some("") | Some("local") => Some(local_dir()),
+        Some(_) => None,
+    }
+}
+
+fn local_dir() -> PathBuf {
+    std::env::var(STORAGE_LOCAL_DIR)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_LOCAL_DIR.to_owned())
+        .into()
+}
+
+/// Keeps the files in a directory on the local filesystem
+pub struct LocalStore {
+    pub root: PathBuf,
+    pub public_url: String,
+}
+
+impl LocalStore {
+    fn path_for(&self, key: &str) -> Result<PathBuf, MovieramaError> {
+        // Keys are generated by us, this only guards against a bug letting
+        // one point outside the root
+        if !Path::new(key)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(MovieramaError::UnexpectedError(format!(
+                "Invalid blob key '{}'",
+                key
+            )));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put<'a>(&'a self, key: &'a str, _content_type: &'a str, data: Vec<u8>) -> BlobFuture<'a> {
+        Box::pin(async move {
+            let path = self.path_for(key)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(io_error)?;
+            }
+            tokio::fs::write(path, data).await.map_err(io_error)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path_for(key)?).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_error(e)),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url.trim_end_matches('/'), key)
+    }
+}
+
+/// Talks to an S3-compatible API with path-style addressing and requests
+/// signed with AWS Signature Version 4
+pub struct S3Store {
+    pub client: reqwest::Client,
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub public_url: String,
+}
+
+impl S3Store {
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        content_type: Option<&str>,
+        data: Vec<u8>,
+    ) -> Result<reqwest::Response, MovieramaError> {
+        let url = reqwest::Url::parse(&format!(
+            "{}/{}/{}",
+            self.endpoint.trim_end_matches('/'),
+            self.bucket,
+            key
+        ))
+        .map_err(|e| MovieramaError::UnexpectedError(format!("Invalid S3 endpoint: {}", e)))?;
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => {
+                return Err(MovieramaError::UnexpectedError(
+                    "Invalid S3 endpoint: no host".to_owned(),
+                ));
+            }
+        };
+
+        let payload_hash = format!("{:x}", Sha256::digest(&data));
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(content_type) = content_type {
+            headers.push(("content-type", content_type.to_owned()));
+        }
+        headers.sort();
+
+        let authorization =
+            self.authorization(method.as_str(), url.path(), &headers, &payload_hash, now);
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        request
+            .body(data)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| MovieramaError::UnexpectedError(format!("S3 request failed: {}", e)))
+    }
+
+    /// `headers` must be sorted by name and include `host`
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let date = now.format("%Y%m%d").to_string();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        // No query string, and the keys we generate need no further encoding
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let key = signing_key(&self.secret_access_key, &date, &self.region, "s3");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        )
+    }
+}
+
+impl BlobStore for S3Store {
+    fn put<'a>(&'a self, key: &'a str, content_type: &'a str, data: Vec<u8>) -> BlobFuture<'a> {
+        Box::pin(async move {
+            self.send(reqwest::Method::PUT, key, Some(content_type), data)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a> {
+        // S3 answers 204 whether or not the object existed
+        Box::pin(async move {
+            self.send(reqwest::Method::DELETE, key, None, Vec::new())
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url.trim_end_matches('/'), key)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn io_error(e: std::io::Error) -> MovieramaError {
+    MovieramaError::UnexpectedError(format!("Blob storage failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{header, header_exists, method, path},
+    };
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20150830",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_put_and_delete() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/posters/1/a.jpg"))
+            .and(header("content-type", "image/jpeg"))
+            .and(header(
+                "x-amz-content-sha256",
+                format!("{:x}", Sha256::digest(b"jpeg")),
+            ))
+            .and(header_exists("authorization"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/posters/1/a.jpg"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let store = S3Store {
+            client: http::client(S3_TIMEOUT),
+            endpoint: server.uri(),
+            bucket: "posters".into(),
+            region: DEFAULT_REGION.into(),
+            access_key_id: "minio".into(),
+            secret_access_key: "minio-secret".into(),
+            public_url: "https://cdn.test".into(),
+        };
+
+        store
+            .put("1/a.jpg", "image/jpeg", b"jpeg".to_vec())
+            .await
+            .unwrap();
+        store.delete("1/a.jpg").await.unwrap();
+        assert_eq!(store.url("1/a.jpg"), "https://cdn.test/1/a.jpg");
+    }
+
+    #[tokio::test]
+    async fn test_local_store() {
+        let root = std::env::temp_dir().join(format!("movierama-blobs-{}", std::process::id()));
+        let store = LocalStore {
+            root: root.clone(),
+            public_url: "/media/".into(),
+        };
+
+        store
+            .put("posters/1/a.jpg", "image/jpeg", b"jpeg".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(root.join("posters/1/a.jpg")).unwrap(),
+            b"jpeg"
+        );
+        assert_eq!(store.url("posters/1/a.jpg"), "/media/posters/1/a.jpg");
+
+        store.delete("posters/1/a.jpg").await.unwrap();
+        store.delete("posters/1/a.jpg").await.unwrap();
+        assert!(!root.join("posters/1/a.jpg").exists());
+
+        assert!(
+            store
+                .put("../escape", "text/plain", Vec::new())
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
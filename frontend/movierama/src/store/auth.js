@@ -6,6 +6,8 @@ export const useAuthStore = defineStore('auth', {
   state: () => ({
     user: null,
     token: localStorage.getItem('token'),
+    // Set between the password and the code step of a two-factor login
+    mfaToken: null,
   }),
 
   getters: {
@@ -25,18 +27,37 @@ export const useAuthStore = defineStore('auth', {
   },
 
   actions: {
+    // Resolves to true when the account wants a second factor, to be passed
+    // to verifyMfa
     async login(username, password) {
       const { data } = await api.post('auth/login', { username, password })
-      this.token = data.token
-      localStorage.setItem('token', data.token)
-      this.user = { username }
+      if (data.mfaRequired) {
+        this.mfaToken = data.mfaToken
+        return true
+      }
+
+      this.signIn(data.token)
+      return false
+    },
+
+    async verifyMfa(code) {
+      const { data } = await api.post('auth/2fa/verify', { mfaToken: this.mfaToken, code })
+      this.mfaToken = null
+      this.signIn(data.token)
+    },
+
+    signIn(token) {
+      this.token = token
+      localStorage.setItem('token', token)
+      // The login may be an email or differ in case, so use the token's username
+      this.user = null
 
       const moviesStore = useMoviesStore()
       moviesStore.clearUserVotes()
     },
 
-    async register(username, email, password) {
-      const { data } = await api.post('auth/register', { username, email, password })
+    async register(username, email, password, inviteCode = null) {
+      const { data } = await api.post('auth/register', { username, email, password, inviteCode })
       this.token = data.token
       localStorage.setItem('token', data.token)
       this.user = { username }
@@ -46,6 +67,20 @@ export const useAuthStore = defineStore('auth', {
     },
 
     logout() {
+      // Sign the session out server-side too, without waiting for it
+      try {
+        const payload = JSON.parse(atob(this.token.split('.')[1]))
+        if (payload.sid) {
+          api
+            .delete(`auth/sessions/${payload.sid}`, {
+              headers: { Authorization: `Bearer ${this.token}` },
+            })
+            .catch(() => {})
+        }
+      } catch {
+        // No valid token to revoke
+      }
+
       this.user = null
       this.token = null
       localStorage.removeItem('token')